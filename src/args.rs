@@ -1,20 +1,57 @@
+use crate::media::DEFAULT_MEDIA_DIRNAME;
+use crate::twitter::retry::RetryPolicy;
+use crate::twitter::oauth::DEFAULT_AUTHORIZE_URL;
+use crate::twitter::twitter::DEFAULT_API_BASE_URL;
 use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[command(subcommand)]
     pub command: Option<Commands>,
+
+    /// Directory of the cache (exported pages, user lookups, checkpoints).
+    /// Defaults to `$XDG_CACHE_HOME/twitter-likes-exporter`
+    #[arg(long, global = true, env = "TLE_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+
+    /// How the cache is stored
+    #[arg(long, global = true, env = "TLE_CACHE_BACKEND", value_enum, default_value_t = CacheBackend::Json)]
+    pub cache_backend: CacheBackend,
+
+    /// How new cache files are compressed (existing files are read whatever
+    /// their compression)
+    #[arg(long, global = true, env = "TLE_CACHE_COMPRESSION", value_enum, default_value_t = CacheCompression::None)]
+    pub cache_compression: CacheCompression,
+
+    /// Encrypt new cache files and compiled outputs with age. The passphrase
+    /// is read from `TLE_PASSPHRASE`, or prompted for
+    #[arg(long, global = true, env = "TLE_ENCRYPT")]
+    pub encrypt: bool,
+
+    /// Base URL of the Twitter API (e.g. to use a local mock server)
+    #[arg(long, global = true, env = "TLE_API_BASE_URL", default_value = DEFAULT_API_BASE_URL)]
+    pub api_base_url: String,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
-    /// Export Twitter likes
+    /// Export Twitter likes (or bookmarks, or the user's own tweets)
     Export {
         /// Twitter username to export
         #[arg(short, long)]
         username: String,
 
+        /// Which tweets to export
+        #[arg(long, value_enum, default_value_t = ExportSource::Likes)]
+        source: ExportSource,
+
+        #[command(flatten)]
+        timeline: TimelineArgs,
+
         /// format %Y-%m-%d 2022-01-01
         #[arg(short, long)]
         not_before_date: Option<String>,
@@ -22,6 +59,30 @@ pub enum Commands {
         /// Pagination token for next page of results
         #[arg(long)]
         next_token: Option<String>,
+
+        /// Continue an interrupted export from its checkpoint
+        #[arg(long, conflicts_with_all = ["next_token", "not_before_date"])]
+        resume: bool,
+
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+
+    /// Fetch only the likes added since the last export or sync
+    Sync {
+        /// Twitter username to sync
+        #[arg(short, long)]
+        username: String,
+
+        /// Which tweets to sync
+        #[arg(long, value_enum, default_value_t = ExportSource::Likes)]
+        source: ExportSource,
+
+        #[command(flatten)]
+        timeline: TimelineArgs,
+
+        #[command(flatten)]
+        retry: RetryArgs,
     },
 
     Compile {
@@ -29,21 +90,342 @@ pub enum Commands {
         #[arg(short, long)]
         username: String,
 
+        /// Which cached tweets to compile. Give several (comma separated) to
+        /// merge them into one output, e.g. `--source likes,tweets`
+        #[arg(long = "source", value_enum, value_delimiter = ',', default_value = "likes")]
+        sources: Vec<ExportSource>,
+
         /// Output format (defaults to JSON)
         #[arg(long, value_enum, default_value_t = OutputFormat::JSON)]
         format: OutputFormat,
 
         #[arg(long)]
         filename: Option<String>,
+
+        /// Order of the tweets
+        #[arg(long, value_enum, default_value_t = SortOrder::Like)]
+        sort: SortOrder,
+
+        /// Directory of media downloaded with `media`. Compiled tweets refer
+        /// to the local copies of any media found there
+        #[arg(long, default_value = DEFAULT_MEDIA_DIRNAME)]
+        media_dir: PathBuf,
+
+        #[command(flatten)]
+        table: TableArgs,
+    },
+
+    /// Download the photos, GIFs and videos of cached tweets, so that the
+    /// archive doesn't depend on Twitter's CDN
+    Media {
+        /// Twitter username whose cached tweets to download media for
+        #[arg(short, long)]
+        username: String,
+
+        /// Which cached tweets to download media for (comma separated)
+        #[arg(long = "source", value_enum, value_delimiter = ',', default_value = "likes")]
+        sources: Vec<ExportSource>,
+
+        /// Directory receiving the files, named by the SHA-256 of their content
+        #[arg(long, default_value = DEFAULT_MEDIA_DIRNAME)]
+        media_dir: PathBuf,
+
+        /// Maximum number of downloads in flight
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+
+    /// Maintain the cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    /// Manage user authentication (OAuth 2.0), needed to export bookmarks
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AuthCommands {
+    /// Log in with OAuth 2.0 (Authorization Code with PKCE) and store the
+    /// user token in the cache
+    Login {
+        /// Local port for the redirect listener. The redirect URI
+        /// http://127.0.0.1:{port}/callback must be registered for the app.
+        #[arg(long, default_value_t = 8765)]
+        port: u16,
+
+        /// OAuth 2.0 authorization page
+        #[arg(long, env = "TLE_AUTHORIZE_URL", default_value = DEFAULT_AUTHORIZE_URL)]
+        authorize_url: String,
+    },
+
+    /// Remove the stored user token
+    Logout,
+}
+
+/// Options for retrying failed requests to the Twitter API.
+#[derive(clap::Args, Debug)]
+pub struct RetryArgs {
+    /// Number of times to retry a request after a transient failure
+    /// (network error, 5xx). Rate limits are always waited out
+    #[arg(long, default_value_t = 5)]
+    pub max_retries: u32,
+
+    /// Base delay in seconds for exponential backoff between retries
+    #[arg(long, default_value_t = 2)]
+    pub retry_delay: u64,
+}
+
+impl RetryArgs {
+    pub fn policy(&self) -> RetryPolicy {
+        RetryPolicy::new(self.max_retries, Duration::from_secs(self.retry_delay))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommands {
+    /// Rewrite cached files written by older versions in the current format
+    Migrate {
+        /// Only migrate the cache of this account (default: every account)
+        #[arg(short, long)]
+        username: Option<String>,
+    },
+
+    /// Check an account's cache for unreadable files, missing pages and
+    /// missing users. Prints a JSON report; exits non-zero if the cache is
+    /// damaged
+    Verify {
+        /// Twitter username whose cache to check
+        #[arg(short, long)]
+        username: String,
+
+        /// Write the JSON report to this file instead of stdout
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+
+    /// Quarantine unreadable files, fetch missing pages again and look up
+    /// missing users. Prints a JSON report; exits non-zero if the cache is
+    /// still damaged
+    Repair {
+        /// Twitter username whose cache to repair
+        #[arg(short, long)]
+        username: String,
+
+        /// Which tweets to fetch again (each source may need its own token)
+        #[arg(long, value_enum, default_value_t = ExportSource::Likes)]
+        source: ExportSource,
+
+        /// Write the JSON report to this file instead of stdout
+        #[arg(long)]
+        report: Option<PathBuf>,
+
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+
+    /// Rewrite an account's cache into one page per source, keeping a single
+    /// copy of each tweet
+    Compact {
+        /// Twitter username whose cache to compact
+        #[arg(short, long)]
+        username: String,
+    },
+
+    /// Generate a new encryption key, protected by a new passphrase (read
+    /// from `TLE_NEW_PASSPHRASE`, or prompted for), and re-encrypt the whole
+    /// cache with it
+    Rekey,
+
+    /// Convert the cached pages and user lookups to another compression, in
+    /// place
+    Compress {
+        /// Only convert the cache of this account (default: every account)
+        #[arg(short, long)]
+        username: Option<String>,
+
+        /// Compression to convert to
+        #[arg(long, value_enum, default_value_t = CacheCompression::Zstd)]
+        to: CacheCompression,
+    },
+
+    /// Copy an account's JSON cache into its SQLite database
+    #[cfg(feature = "sqlite")]
+    ImportJson {
+        /// Twitter username whose cache to import
+        #[arg(short, long)]
+        username: String,
+    },
+}
+
+/// Where the cache is stored.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheBackend {
+    /// One JSON file per page of tweets
+    #[default]
+    Json,
+    /// One SQLite database per account (requires the `sqlite` feature)
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+/// How cached pages and user lookups are compressed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheCompression {
+    /// Plain JSON (`.json`)
+    #[default]
+    None,
+    /// gzip (`.json.gz`)
+    Gzip,
+    /// Zstandard (`.json.zst`)
+    Zstd,
+}
+
+impl CacheCompression {
+    /// Extension added after `.json` to the files compressed this way.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CacheCompression::None => "",
+            CacheCompression::Gzip => ".gz",
+            CacheCompression::Zstd => ".zst",
+        }
+    }
+}
+
+/// The kind of tweets to export. Each source is cached under its own file
+/// prefix, e.g. `likes-{username}-...json`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportSource {
+    /// Tweets liked by the user (app-only bearer token)
+    #[default]
+    Likes,
+    /// Tweets bookmarked by the user (requires `auth login`)
+    Bookmarks,
+    /// Tweets (and retweets, replies) written by the user
+    Tweets,
+}
+
+impl ExportSource {
+    /// Prefix of the cache files holding pages from this source.
+    pub fn file_prefix(&self) -> &'static str {
+        match self {
+            ExportSource::Likes => "likes",
+            ExportSource::Bookmarks => "bookmarks",
+            ExportSource::Tweets => "tweets",
+        }
+    }
+
+    /// Default name of the compiled output for this source.
+    pub fn compiled_name(&self) -> &'static str {
+        match self {
+            ExportSource::Likes => "liked_tweets",
+            ExportSource::Bookmarks => "bookmarked_tweets",
+            ExportSource::Tweets => "authored_tweets",
+        }
+    }
+}
+
+/// Options for exporting a user's own tweets (`--source tweets`).
+#[derive(clap::Args, Debug)]
+pub struct TimelineArgs {
+    /// Leave out replies when exporting the user's own tweets
+    #[arg(long)]
+    pub exclude_replies: bool,
+
+    /// Leave out retweets when exporting the user's own tweets
+    #[arg(long)]
+    pub exclude_retweets: bool,
+}
+
+/// Options for the tabular output formats (`--format csv`, `--format tsv`).
+#[derive(clap::Args, Debug, Clone)]
+pub struct TableArgs {
+    /// Columns of the table, in order (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = TableColumn::value_variants().to_vec())]
+    pub columns: Vec<TableColumn>,
+
+    /// Write one row per link in a tweet (with the link in the `urls`
+    /// column), leaving out the tweets without links
+    #[arg(long)]
+    pub row_per_url: bool,
+}
+
+impl Default for TableArgs {
+    fn default() -> TableArgs {
+        TableArgs {
+            columns: TableColumn::value_variants().to_vec(),
+            row_per_url: false,
+        }
     }
 }
 
+/// A column of the tabular output formats.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "snake_case")]
+pub enum TableColumn {
+    Id,
+    CreatedAt,
+    AuthorUsername,
+    AuthorName,
+    Text,
+    /// The expanded links of the tweet, separated by spaces
+    Urls,
+    Lang,
+    LikePosition,
+    Permalink,
+}
+
+impl TableColumn {
+    /// Name of the column in the header row.
+    pub fn name(&self) -> String {
+        self.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+    }
+}
+
+/// Order of the tweets in compiled output.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    /// Most recently liked (bookmarked, tweeted) first
+    #[default]
+    Like,
+    /// Most recently written first
+    TweetTime,
+    /// By author username, unknown authors last
+    Author,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum OutputFormat {
     /// Outputs to JSON format
     JSON,
+    /// Outputs JSON Lines, one tweet per line, streamed from the cache
+    /// (`--filename -` writes to stdout)
+    Jsonl,
     /// Outputs to Markdown format
     Markdown,
+    /// Outputs a static site (a directory) to browse and search the tweets
+    Html,
+    /// Outputs a table of comma separated values (RFC 4180)
+    Csv,
+    /// Outputs a table of tab separated values
+    Tsv,
+    /// Outputs a Netscape Bookmark File, importable by every browser
+    Netscape,
+    /// Outputs bookmarks in Pinboard's JSON format
+    Pinboard,
+    /// Outputs bookmarks as CSV in the columns Raindrop imports
+    Raindrop,
+    /// Outputs an Obsidian or Logseq vault (a directory) with a note per
+    /// tweet, updated in place when compiled again
+    Vault,
 }
 
 pub fn parse() -> Args {
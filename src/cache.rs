@@ -1,10 +1,43 @@
+use crate::args::{CacheBackend, CacheCompression, ExportSource, SortOrder};
 use crate::serialization::{FsCacheable, FsLoadable};
-use crate::twitter::json_types::{LikedTweets, TwitLikeResponse, UserIdLookup};
+use crate::twitter::json_types::{
+    dedupe_tweets, earliest_first_seen, LikedTweets, TwitLikeDatum, TwitLikeResponse, TwitUserDatum, UserIdLookup,
+};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{File, TryLockError};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::{env, fs, io};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::{env, fs, io, process};
 use std::{error::Error, fmt};
 
+/// Cache directory used in earlier versions, relative to the working directory.
 const CACHE_DIRNAME: &str = ".cache";
+/// Name of our directory under `$XDG_CACHE_HOME`.
+const APP_DIRNAME: &str = "twitter-likes-exporter";
+
+/// Cache directory chosen with `--cache-dir` / `TLE_CACHE_DIR`.
+static CACHE_DIRECTORY: OnceLock<PathBuf> = OnceLock::new();
+/// Storage chosen with `--cache-backend` / `TLE_CACHE_BACKEND`.
+static CACHE_BACKEND: OnceLock<CacheBackend> = OnceLock::new();
+/// Compression chosen with `--cache-compression` / `TLE_CACHE_COMPRESSION`.
+static CACHE_COMPRESSION: OnceLock<CacheCompression> = OnceLock::new();
+/// Lock file taken in an account's cache directory while it is written to.
+const LOCK_FILENAME: &str = ".lock";
+/// Distinguishes the temporary files of concurrent writes within the process.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub mod compact;
+pub mod compression;
+pub mod migrations;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod verify;
 
 #[derive(Debug)]
 pub enum CacheLoadError {
@@ -18,72 +51,239 @@ impl fmt::Display for CacheLoadError {
     }
 }
 
-/// Attempts to load a UserIdLookup from cache or else returns an error.
-pub fn load_user_lookup() -> Result<UserIdLookup, Box<dyn Error>> {
-    // If it exists, load the users lookup from cache. Caching this data means
-    // that we don't have to go back to the API repeatedly for user info between runs.
-    let cache_directory = get_cache_directory_path()?;
-    fs::create_dir_all(&cache_directory)?;
-    let full_path = UserIdLookup::fs_full_path()?;
-    let user_id_lkup = UserIdLookup::load(&full_path)?;
-    Ok(user_id_lkup)
+#[derive(Debug)]
+pub enum CacheLockError {
+    /// Another process holds the lock on the account's cache directory.
+    Held { username: String, path: PathBuf },
+}
+
+impl Error for CacheLockError {}
+impl fmt::Display for CacheLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheLockError::Held { username, path } => write!(
+                f,
+                "The cache of '{username}' is in use by another process (lock file {})",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// An advisory lock on the cache directory of an account, held until it is
+/// dropped. See `lock_account`.
+#[derive(Debug)]
+pub struct AccountLock {
+    // Closing the file releases the lock.
+    _file: File,
+}
+
+/// Takes an advisory lock on the cache directory of `username`, so that two
+/// processes (say, two `export` runs) cannot interleave their writes to it.
+/// Fails straight away, rather than waiting, if the lock is already held.
+pub fn lock_account(username: &str) -> Result<AccountLock, Box<dyn Error>> {
+    let account_directory = get_account_directory_path(username)?;
+    fs::create_dir_all(&account_directory)?;
+    let path = account_directory.join(LOCK_FILENAME);
+    let file = File::options().create(true).truncate(false).write(true).open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(AccountLock { _file: file }),
+        Err(TryLockError::WouldBlock) => Err(Box::new(CacheLockError::Held {
+            username: username.to_string(),
+            path,
+        })),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+/// Attempts to load the UserIdLookup of `username` from cache or else returns
+/// an error.
+pub fn load_user_lookup(username: &str) -> Result<UserIdLookup, Box<dyn Error>> {
+    match cache_backend() {
+        CacheBackend::Json => {
+            // If it exists, load the users lookup from cache. Caching this data means
+            // that we don't have to go back to the API repeatedly for user info between runs.
+            let account_directory = get_account_directory_path(username)?;
+            fs::create_dir_all(&account_directory)?;
+            UserIdLookup::load_default(username)
+        }
+        #[cfg(feature = "sqlite")]
+        CacheBackend::Sqlite => sqlite::SqliteCache::open_for_username(username)?.load_user_lookup(),
+    }
+}
+
+/// Writes the UserIdLookup of `username` to cache.
+pub fn write_user_lookup(username: &str, user_id_lkup: &UserIdLookup) -> Result<(), Box<dyn Error>> {
+    match cache_backend() {
+        CacheBackend::Json => {
+            let path = UserIdLookup::fs_full_path(username)?;
+            write_cache(user_id_lkup, &path)?;
+            // Drop the copy written with an earlier compression, if any.
+            compression::remove_other_variants(&path)?;
+            Ok(())
+        }
+        #[cfg(feature = "sqlite")]
+        CacheBackend::Sqlite => {
+            sqlite::SqliteCache::open_for_username(username)?.store_user_lookup(user_id_lkup)
+        }
+    }
+}
+
+/// Writes a page of tweets from `source` to cache. With the JSON backend, a
+/// page already cached by the same export (as when it is resumed) is left as
+/// it is, while a page of another export with the same name is kept alongside
+/// (see `TwitLikeResponse::fs_export_path`).
+pub fn write_page(source: ExportSource, page: &TwitLikeResponse) -> Result<(), Box<dyn Error>> {
+    match cache_backend() {
+        CacheBackend::Json => {
+            let mut fs_path = match page.fs_full_path(source) {
+                Some(fs_path) => fs_path,
+                None => return Ok(()),
+            };
+            if let Some(cached) = compression::find_variant(&fs_path) {
+                let same_export = TwitLikeResponse::load(&cached)
+                    .is_ok_and(|cached| cached.export_started_at == page.export_started_at);
+                if !same_export {
+                    fs_path = page.fs_export_path(source).unwrap_or(fs_path);
+                }
+            }
+            println!("{:?}", fs_path);
+            if compression::find_variant(&fs_path).is_some() {
+                println!("Cache exists for this batch of tweets, skipping...");
+            } else {
+                write_cache(page, &fs_path)?;
+            }
+            Ok(())
+        }
+        #[cfg(feature = "sqlite")]
+        CacheBackend::Sqlite => {
+            let username = match &page.user {
+                Some(user) => &user.username,
+                None => return Err("Cannot cache a page without its user".into()),
+            };
+            sqlite::SqliteCache::open_for_username(username)?.store_page(source, page)
+        }
+    }
 }
 
-/// Attempts to load a UserIdLookup from cache, and failing that returns a new
-/// empty object.
-pub fn try_load_user_lookup() -> UserIdLookup {
-    match load_user_lookup() {
+/// Attempts to load the UserIdLookup of `username` from cache, and failing
+/// that returns a new empty object.
+pub fn try_load_user_lookup(username: &str) -> UserIdLookup {
+    match load_user_lookup(username) {
         Ok(uil) => uil,
         Err(err) => {
-            println!("try_load_user_lookup: error {:?}", err);
+            eprintln!("try_load_user_lookup: error {:?}", err);
             UserIdLookup::new()
         }
     }
 }
 
-/// Loads tweets previously cached in the .cache directory into a single object.
-/// Returns an error if the attempt to load fails.
+/// Lists the cached pages of tweets from `source` for `username`. Returns an
+/// error if the cache directory can't be read.
 ///
 /// # Arguments
 ///
+/// * `source` - Which kind of tweets (likes, bookmarks).
 /// * `username` - A string slice representing the twitter username (not user id).
-pub fn load_all_liked_tweets_from_cache(username: &str) -> Result<LikedTweets, Box<dyn Error>> {
-    // From the cache directory, find all cached JSON files with liked tweets.
-    // TODO: allow the cache directory to be configurable.
-    let cache_directory = env::current_dir()?.join(CACHE_DIRNAME);
-    let paths = fs::read_dir(cache_directory)?;
-    let mut liked_tweets = LikedTweets::new();
-    let user_id_lkup = UserIdLookup::load_default()?;
-
-    for path in paths {
-        let path = path.unwrap().path();
-        if let Some(filen) = path.file_name() {
-            if filen
-                .to_str()
-                .unwrap()
-                .starts_with(&format!("likes-{username}-"))
-            {
-                println!("Loaded: {}", path.display());
-                let twit_like_resp = TwitLikeResponse::load(&path)?;
-
-                if let None = liked_tweets.user {
-                    liked_tweets.user = twit_like_resp.user;
-                }
+pub fn cached_page_paths(source: ExportSource, username: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    // From the account's directory, find all cached JSON files with pages of tweets.
+    let account_directory = get_account_directory_path(username)?;
+    let prefix = format!("{}-{username}-", source.file_prefix());
+    let mut paths = Vec::new();
+    if !account_directory.exists() {
+        return Ok(paths);
+    }
 
-                if let Some(data) = twit_like_resp.data {
-                    for mut datum in data {
-                        let user = match user_id_lkup.users_by_id.get(&datum.author_id) {
-                            Some(user_opt) => user_opt.as_ref().unwrap(),
-                            None => panic!("Expected user data for {}", &datum.author_id),
-                        };
-                        datum.user = Some(user.clone());
-                        liked_tweets.tweets.push(datum);
-                    }
-                }
+    for entry in fs::read_dir(account_directory)? {
+        let path = entry?.path();
+        if let Some(filen) = path.file_name().and_then(|f| f.to_str()) {
+            let is_page = compression::strip_compression_extension(filen).ends_with(".json");
+            if filen.starts_with(&prefix) && is_page {
+                paths.push(path);
             }
         }
     }
 
+    Ok(paths)
+}
+
+/// The account directories in the cache: that of `username`, if it exists,
+/// or else every one.
+pub(crate) fn account_directories(username: Option<&str>) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let root = get_cache_directory_path()?;
+    if !root.exists() {
+        return Ok(Vec::new());
+    }
+    let dirs: Vec<PathBuf> = match username {
+        Some(username) => vec![root.join(username)],
+        None => fs::read_dir(&root)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.is_dir())
+            .collect(),
+    };
+    Ok(dirs.into_iter().filter(|dir| dir.exists()).collect())
+}
+
+/// Collects the ids of all the tweets from `source` already cached for
+/// `username`. Returns an empty set if nothing has been cached yet.
+pub fn load_cached_tweet_ids(source: ExportSource, username: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    #[cfg(feature = "sqlite")]
+    if cache_backend() == CacheBackend::Sqlite {
+        return sqlite::SqliteCache::open_for_username(username)?.load_tweet_ids(source);
+    }
+
+    let mut ids = HashSet::new();
+    for path in cached_page_paths(source, username)? {
+        let twit_like_resp = TwitLikeResponse::load(&path)?;
+        if let Some(data) = twit_like_resp.data {
+            ids.extend(data.into_iter().map(|datum| datum.id));
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Loads tweets previously cached for `username` into a single object.
+/// The tweets are in like order (`SortOrder::Like`). Returns an error if the
+/// attempt to load fails.
+///
+/// # Arguments
+///
+/// * `sources` - Which kinds of tweets (likes, bookmarks, tweets) to load.
+///   With more than one, the tweets are merged into a single list.
+/// * `username` - A string slice representing the twitter username (not user id).
+pub fn load_all_liked_tweets_from_cache(
+    sources: &[ExportSource],
+    username: &str,
+) -> Result<LikedTweets, Box<dyn Error>> {
+    let mut liked_tweets = LikedTweets::new();
+    let user_id_lkup = try_load_user_lookup(username);
+
+    for source in sources {
+        let (user, tweets) = load_tweets(*source, username)?;
+        // Overlapping exports, resumes and syncs can cache a tweet more than
+        // once. (A tweet from several sources is kept once per source.)
+        let loaded = tweets.len();
+        let tweets = dedupe_tweets(tweets);
+        liked_tweets.duplicates += loaded - tweets.len();
+        if liked_tweets.user.is_none() {
+            liked_tweets.user = user;
+        }
+
+        for (like_position, mut datum) in tweets.into_iter().enumerate() {
+            datum.like_position = Some(like_position as u64);
+            // Deleted and suspended authors stay None: an "unknown author".
+            datum.user = user_id_lkup
+                .users_by_id
+                .get(&datum.author_id)
+                .cloned()
+                .flatten();
+            datum.author_error = user_id_lkup.errors_by_id.get(&datum.author_id).cloned();
+            datum.source = Some(*source);
+            liked_tweets.tweets.push(datum);
+        }
+    }
+
     if liked_tweets.tweets.is_empty() {
         return Err(Box::new(CacheLoadError::NoTweets(format!(
             "No tweets were found for user '{}'. Did you mean to run `export` first?",
@@ -91,10 +291,208 @@ pub fn load_all_liked_tweets_from_cache(username: &str) -> Result<LikedTweets, B
         ))));
     }
 
-    liked_tweets.sort_by_date();
+    liked_tweets.sort(SortOrder::Like);
     Ok(liked_tweets)
 }
 
+/// Loads the tweets from `source` cached for `username`, with their includes,
+/// in the order they were liked (most recent first, duplicates included), and
+/// the user whose tweets they are.
+fn load_tweets(
+    source: ExportSource,
+    username: &str,
+) -> Result<(Option<TwitUserDatum>, Vec<TwitLikeDatum>), Box<dyn Error>> {
+    #[cfg(feature = "sqlite")]
+    if cache_backend() == CacheBackend::Sqlite {
+        return sqlite::SqliteCache::open_for_username(username)?.load_tweets(source);
+    }
+
+    let mut user = None;
+    let mut pages = Vec::new();
+    for path in cached_page_paths(source, username)? {
+        eprintln!("Loaded: {}", path.display());
+        let twit_like_resp = TwitLikeResponse::load(&path)?;
+        if user.is_none() {
+            user = twit_like_resp.user.clone();
+        }
+        pages.push((PageOrder::of(&twit_like_resp, &path), twit_like_resp));
+    }
+    pages.sort_by(|(order1, _), (order2, _)| order1.cmp(order2));
+
+    let mut tweets = Vec::new();
+    for (order, page) in pages {
+        tweets.extend(page_tweets(page, &order));
+    }
+    Ok((user, tweets))
+}
+
+/// Where a cached page goes in like order. The API returns the most recently
+/// liked tweets first, so the pages go from the latest export to the
+/// earliest, and each export from its first page to its last.
+#[derive(PartialEq, Eq)]
+struct PageOrder {
+    export_started_at: Option<DateTime<Utc>>,
+    index: Option<u64>,
+    fetched_at: Option<DateTime<Utc>>,
+}
+
+impl PageOrder {
+    fn of(page: &TwitLikeResponse, path: &Path) -> PageOrder {
+        PageOrder {
+            export_started_at: page.export_started_at,
+            index: page.index,
+            // Pages cached by earlier versions were fetched when they were written.
+            fetched_at: page
+                .fetched_at
+                .or_else(|| fs::metadata(path).and_then(|m| m.modified()).ok().map(DateTime::<Utc>::from)),
+        }
+    }
+}
+
+impl Ord for PageOrder {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other
+            .export_started_at
+            .cmp(&self.export_started_at)
+            .then(self.index.cmp(&other.index))
+            .then(other.fetched_at.cmp(&self.fetched_at))
+    }
+}
+
+impl PartialOrd for PageOrder {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The tweets of `page`, with their includes and fetch times.
+fn page_tweets(page: TwitLikeResponse, order: &PageOrder) -> Vec<TwitLikeDatum> {
+    let includes = page.includes.unwrap_or_default();
+    let mut tweets = page.data.unwrap_or_default();
+    for datum in tweets.iter_mut() {
+        // Compacted pages keep the includes and fetch times of each tweet.
+        if datum.includes.is_none() {
+            datum.includes = includes.for_tweet(datum);
+        }
+        datum.fetched_at = datum.fetched_at.or(order.fetched_at);
+        datum.first_seen = datum.first_seen.or(page.export_started_at).or(datum.fetched_at);
+    }
+    tweets
+}
+
+/// The copy of a cached tweet which is kept, and where the tweet goes in like
+/// order.
+struct KeptCopy {
+    /// Index of the source in the sources compiled.
+    source: usize,
+    /// Position of the first copy among the tweets of the source.
+    like_position: u64,
+    first_seen: Option<DateTime<Utc>>,
+    fetched_at: Option<DateTime<Utc>>,
+    /// Index of the page of the copy, and of the copy in the page.
+    page: usize,
+    offset: usize,
+}
+
+/// Like `load_all_liked_tweets_from_cache`, but hands the tweets to `f` one at
+/// a time, holding a single page of tweets in memory rather than every tweet.
+/// Returns the number of tweets and of duplicates left out.
+///
+/// The tweets are the same, in the same order: a first pass over the pages
+/// picks the copy of each tweet which `dedupe_tweets` keeps and its place in
+/// `SortOrder::Like`, and the second reads those copies in that order. Pages
+/// whose tweets are spread out by an overlapping export are read again.
+pub fn for_each_cached_tweet(
+    sources: &[ExportSource],
+    username: &str,
+    mut f: impl FnMut(TwitLikeDatum) -> Result<(), Box<dyn Error>>,
+) -> Result<(usize, usize), Box<dyn Error>> {
+    if cache_backend() != CacheBackend::Json {
+        // The database returns every tweet at once anyway.
+        let liked_tweets = load_all_liked_tweets_from_cache(sources, username)?;
+        let counts = (liked_tweets.tweets.len(), liked_tweets.duplicates);
+        for datum in liked_tweets.tweets {
+            f(datum)?;
+        }
+        return Ok(counts);
+    }
+
+    let mut pages: Vec<(PageOrder, PathBuf, ExportSource)> = Vec::new();
+    let mut kept: Vec<KeptCopy> = Vec::new();
+    let mut copies = 0;
+    for (source_index, source) in sources.iter().enumerate() {
+        // Only the order of the pages and the ids and times of their tweets
+        // are kept from this first pass.
+        let mut source_pages = Vec::new();
+        for path in cached_page_paths(*source, username)? {
+            let page = TwitLikeResponse::load(&path)?;
+            let order = PageOrder::of(&page, &path);
+            let tweets: Vec<_> = page_tweets(page, &order)
+                .into_iter()
+                .map(|datum| (datum.id, datum.first_seen, datum.fetched_at))
+                .collect();
+            source_pages.push((order, path, tweets));
+        }
+        source_pages.sort_by(|(order1, ..), (order2, ..)| order1.cmp(order2));
+
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (order, path, tweets) in source_pages {
+            let page = pages.len();
+            pages.push((order, path, *source));
+            for (offset, (id, first_seen, fetched_at)) in tweets.into_iter().enumerate() {
+                copies += 1;
+                match positions.get(&id) {
+                    Some(&position) => {
+                        let copy = &mut kept[position];
+                        copy.first_seen = earliest_first_seen(copy.first_seen, first_seen);
+                        if fetched_at >= copy.fetched_at {
+                            (copy.fetched_at, copy.page, copy.offset) = (fetched_at, page, offset);
+                        }
+                    }
+                    None => {
+                        let like_position = positions.len() as u64;
+                        positions.insert(id, kept.len());
+                        kept.push(KeptCopy { source: source_index, like_position, first_seen, fetched_at, page, offset });
+                    }
+                }
+            }
+        }
+    }
+    if kept.is_empty() {
+        return Err(Box::new(CacheLoadError::NoTweets(format!(
+            "No tweets were found for user '{}'. Did you mean to run `export` first?",
+            username
+        ))));
+    }
+    // As `SortOrder::Like` sorts the tweets of every source, one after the other.
+    kept.sort_by_key(|copy| (Reverse(copy.first_seen), copy.like_position, copy.source));
+
+    let user_id_lkup = try_load_user_lookup(username);
+    let mut loaded: Option<(usize, Vec<Option<TwitLikeDatum>>)> = None;
+    for copy in &kept {
+        let (order, path, source) = &pages[copy.page];
+        let tweets = match &mut loaded {
+            Some((page, tweets)) if *page == copy.page => tweets,
+            _ => {
+                eprintln!("Loaded: {}", path.display());
+                let tweets = page_tweets(TwitLikeResponse::load(path)?, order).into_iter().map(Some).collect();
+                &mut loaded.insert((copy.page, tweets)).1
+            }
+        };
+        let mut datum = tweets
+            .get_mut(copy.offset)
+            .and_then(Option::take)
+            .ok_or_else(|| format!("{} changed while it was read", path.display()))?;
+        datum.like_position = Some(copy.like_position);
+        datum.first_seen = copy.first_seen;
+        datum.user = user_id_lkup.users_by_id.get(&datum.author_id).cloned().flatten();
+        datum.author_error = user_id_lkup.errors_by_id.get(&datum.author_id).cloned();
+        datum.source = Some(*source);
+        f(datum)?;
+    }
+    Ok((kept.len(), copies - kept.len()))
+}
+
 /// Gets the filesystem path for this cacheable type.
 /// Return the cache directory path, followed by the cache file path.
 ///
@@ -105,12 +503,136 @@ pub fn get_cache_file_path(filename: &str) -> std::io::Result<PathBuf> {
     Ok(get_cache_directory_path()?.join(filename))
 }
 
-/// Gets the filesystem path for the cache directory (currently, this is set to
-/// `CACHE_DIRNAME` (.cache) in the current working directory).
+/// Gets the filesystem path for a file belonging to `username`, in the
+/// account's own subdirectory of the cache directory.
+pub fn get_account_file_path(username: &str, filename: &str) -> std::io::Result<PathBuf> {
+    Ok(get_account_directory_path(username)?.join(filename))
+}
+
+/// Gets the filesystem path for the subdirectory of the cache directory that
+/// holds the pages, user lookup and checkpoint of `username`.
+pub fn get_account_directory_path(username: &str) -> io::Result<PathBuf> {
+    Ok(get_cache_directory_path()?.join(username))
+}
+
+/// Uses `backend` to store the cache for the rest of the process. Only the
+/// first call has an effect; returns `false` if the backend was already set.
+pub fn set_cache_backend(backend: CacheBackend) -> bool {
+    CACHE_BACKEND.set(backend).is_ok()
+}
+
+/// The storage used for the cache (JSON files unless set otherwise).
+pub fn cache_backend() -> CacheBackend {
+    CACHE_BACKEND.get().copied().unwrap_or_default()
+}
+
+/// Uses `compression` for the cache files written for the rest of the
+/// process. Only the first call has an effect; returns `false` if the
+/// compression was already set.
+pub fn set_cache_compression(compression: CacheCompression) -> bool {
+    CACHE_COMPRESSION.set(compression).is_ok()
+}
+
+/// The compression of new cache files (none unless set otherwise).
+pub fn cache_compression() -> CacheCompression {
+    CACHE_COMPRESSION.get().copied().unwrap_or_default()
+}
+
+/// Uses `path` as the cache directory for the rest of the process. Only the
+/// first call has an effect; returns `false` if the directory was already set.
+pub fn set_cache_directory(path: PathBuf) -> bool {
+    CACHE_DIRECTORY.set(path).is_ok()
+}
+
+/// Gets the filesystem path for the cache directory: the one set with
+/// `set_cache_directory` (`--cache-dir`), or else `default_cache_directory`.
 pub fn get_cache_directory_path() -> io::Result<PathBuf> {
+    match CACHE_DIRECTORY.get() {
+        Some(path) => Ok(path.clone()),
+        None => default_cache_directory(),
+    }
+}
+
+/// `$XDG_CACHE_HOME/twitter-likes-exporter`, falling back on
+/// `$HOME/.cache/twitter-likes-exporter` and, without a home directory, on
+/// `CACHE_DIRNAME` (.cache) in the current working directory.
+pub fn default_cache_directory() -> io::Result<PathBuf> {
+    // Relative paths in XDG_CACHE_HOME are invalid per the spec; ignore them.
+    let absolute = |var: &str| env::var_os(var).map(PathBuf::from).filter(|p| p.is_absolute());
+    if let Some(xdg_cache_home) = absolute("XDG_CACHE_HOME") {
+        return Ok(xdg_cache_home.join(APP_DIRNAME));
+    }
+    if let Some(home) = absolute("HOME") {
+        return Ok(home.join(".cache").join(APP_DIRNAME));
+    }
     Ok(env::current_dir()?.join(CACHE_DIRNAME))
 }
 
+/// Returns the `.cache` directory written by earlier versions to the current
+/// working directory, if there is one and it isn't the cache directory in use.
+pub fn legacy_cache_directory() -> Option<PathBuf> {
+    let legacy = env::current_dir().ok()?.join(CACHE_DIRNAME);
+    let current = get_cache_directory_path().ok()?;
+    if legacy.is_dir() && legacy != current {
+        Some(legacy)
+    } else {
+        None
+    }
+}
+
+/// Copies the cache in `legacy` (see `legacy_cache_directory`), where earlier
+/// versions kept the files of every account side by side, into `cache_dir`:
+/// each account's pages and checkpoint into its own subdirectory, together
+/// with a copy of the user lookup they shared. Only done while `cache_dir`
+/// holds no account, so that nothing is overwritten; `legacy` is left as it
+/// is. Returns the accounts copied.
+pub fn adopt_legacy_cache(legacy: &Path, cache_dir: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    if cache_dir.exists() && fs::read_dir(cache_dir)?.flatten().any(|entry| entry.path().is_dir()) {
+        return Ok(Vec::new());
+    }
+    let mut account_files: Vec<(String, PathBuf)> = Vec::new();
+    let mut shared_files = Vec::new();
+    for entry in fs::read_dir(legacy)?.flatten() {
+        let path = entry.path();
+        let filename = entry.file_name().to_string_lossy().to_string();
+        let stem = match filename.strip_suffix(".json") {
+            Some(stem) if path.is_file() => stem,
+            _ => continue,
+        };
+        // Usernames have no `-`: `{prefix}-{username}-{index}-{token}`.
+        let page_owner = ExportSource::value_variants().iter().find_map(|source| {
+            let rest = stem.strip_prefix(source.file_prefix())?.strip_prefix('-')?;
+            rest.split_once('-').map(|(username, _)| username)
+        });
+        match (page_owner, stem.strip_prefix("checkpoint-")) {
+            (Some(username), _) | (None, Some(username)) => account_files.push((username.to_string(), path)),
+            _ if filename == "user_id_lookup.json" || filename == "oauth_token.json" => shared_files.push(path),
+            _ => {}
+        }
+    }
+
+    let mut accounts: Vec<String> = account_files.iter().map(|(username, _)| username.clone()).collect();
+    accounts.sort();
+    accounts.dedup();
+    for (username, path) in &account_files {
+        let account_directory = cache_dir.join(username);
+        fs::create_dir_all(&account_directory)?;
+        fs::copy(path, account_directory.join(path.file_name().unwrap_or_default()))?;
+    }
+    for path in shared_files {
+        let filename = path.file_name().unwrap_or_default();
+        if filename == "oauth_token.json" {
+            fs::create_dir_all(cache_dir)?;
+            fs::copy(&path, cache_dir.join(filename))?;
+        } else {
+            for username in &accounts {
+                fs::copy(&path, cache_dir.join(username).join(filename))?;
+            }
+        }
+    }
+    Ok(accounts)
+}
+
 /// Writes a filesystem-cacheable, serializable object to the cache directory.
 /// If the cache directory does not exist, it will be created. Returns an error
 /// if any occurs.
@@ -118,7 +640,136 @@ pub fn write_cache<T>(cacheable: &T, file_path: &Path) -> Result<(), Box<dyn Err
 where
     T: FsCacheable<T>,
 {
-    fs::create_dir_all(file_path.parent().unwrap())?;
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
     cacheable.cache(file_path)?;
     Ok(())
 }
+
+/// A unique temporary path in the directory of `path`, to write to before
+/// renaming over `path`.
+pub fn temp_path_for(path: &Path) -> io::Result<PathBuf> {
+    let filename = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Not a file path: {}", path.display()))
+    })?;
+    // Hidden, so that it is never mistaken for a cached page.
+    let mut temp_filename = OsString::from(".");
+    temp_filename.push(filename);
+    temp_filename.push(format!(
+        ".{}-{}.tmp",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    Ok(path.with_file_name(temp_filename))
+}
+
+/// Writes `contents` to `path` atomically: to a temporary file in the same
+/// directory first, which is then renamed over `path`. An interrupted write
+/// leaves whatever was at `path` untouched, rather than a truncated file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, contents, |temp_path| File::create(temp_path))
+}
+
+/// Like `write_atomic`, but on unix the file can only be read and written by
+/// its owner (mode 0600), for files holding secrets such as tokens.
+pub fn write_atomic_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, contents, |temp_path| {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(temp_path)
+    })
+}
+
+fn write_atomic_with(path: &Path, contents: &[u8], create: impl FnOnce(&Path) -> io::Result<File>) -> io::Result<()> {
+    let temp_path = temp_path_for(path)?;
+    let result = create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::use_scratch_cache_dir;
+
+    #[test]
+    fn test_write_atomic_replaces_file_without_leftovers() {
+        let dir = use_scratch_cache_dir().join("write_atomic");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("user_id_lookup.json");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1, "temporary files were left behind");
+
+        // Failures are returned, not panics, and leave nothing behind.
+        assert!(write_atomic(&dir.join("missing").join("page.json"), b"x").is_err());
+        assert!(write_atomic(&dir, b"x").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_lock_account_is_exclusive() {
+        use_scratch_cache_dir();
+        let lock = lock_account("lock_account").unwrap();
+
+        let err = lock_account("lock_account").unwrap_err();
+        assert!(err.downcast_ref::<CacheLockError>().is_some(), "{err}");
+        // Other accounts are not affected.
+        lock_account("lock_account_other").unwrap();
+
+        drop(lock);
+        lock_account("lock_account").unwrap();
+    }
+
+    #[test]
+    fn test_adopt_legacy_cache() {
+        let dir = use_scratch_cache_dir().join("legacy-adopt");
+        let legacy = dir.join(CACHE_DIRNAME);
+        fs::create_dir_all(&legacy).unwrap();
+        for name in [
+            "likes-alice-0-1669800000000.json",
+            "bookmarks-alice-1-tok100.json",
+            "likes-bob-0-1669800000000.json",
+            "checkpoint-bob.json",
+            "user_id_lookup.json",
+            "oauth_token.json",
+            "notes.txt",
+        ] {
+            fs::write(legacy.join(name), name).unwrap();
+        }
+        let cache_dir = dir.join("cache");
+
+        assert_eq!(adopt_legacy_cache(&legacy, &cache_dir).unwrap(), ["alice", "bob"]);
+        for (account, name) in [
+            ("alice", "likes-alice-0-1669800000000.json"),
+            ("alice", "bookmarks-alice-1-tok100.json"),
+            ("alice", "user_id_lookup.json"),
+            ("bob", "likes-bob-0-1669800000000.json"),
+            ("bob", "checkpoint-bob.json"),
+            ("bob", "user_id_lookup.json"),
+        ] {
+            assert_eq!(fs::read_to_string(cache_dir.join(account).join(name)).unwrap(), name);
+        }
+        assert!(cache_dir.join("oauth_token.json").exists());
+        assert!(!cache_dir.join("notes.txt").exists());
+        assert!(legacy.join("likes-alice-0-1669800000000.json").exists(), "the legacy cache is kept");
+
+        // Once the cache has accounts, it is left alone.
+        fs::write(legacy.join("likes-carol-0-1669800000000.json"), "").unwrap();
+        assert!(adopt_legacy_cache(&legacy, &cache_dir).unwrap().is_empty());
+        assert!(!cache_dir.join("carol").exists());
+    }
+}
@@ -0,0 +1,162 @@
+//! `cache compact`: rewrites the pages of an account into a single page per
+//! source, with one copy of each tweet.
+//!
+//! Repeated exports, resumes and syncs leave overlapping pages behind, since
+//! the first page of each run is cached under a new name. Compiling already
+//! skips the duplicates; compacting removes them from the cache.
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use std::error::Error;
+use std::fs;
+
+use crate::args::{CacheBackend, ExportSource};
+use crate::cache::{cache_backend, cached_page_paths, load_tweets, lock_account, write_cache};
+use crate::twitter::json_types::{dedupe_tweets, ExportCheckpoint, TwitLikeMeta, TwitLikeResponse};
+
+/// Outcome of `compact_account`.
+#[derive(Debug, Default, PartialEq)]
+pub struct CompactReport {
+    /// Pages before compacting.
+    pub pages_before: usize,
+    /// Pages after compacting (one per source with tweets).
+    pub pages_after: usize,
+    /// Tweets kept.
+    pub tweets: usize,
+    /// Copies of tweets cached more than once which were removed.
+    pub duplicates: usize,
+}
+
+/// Rewrites the cached pages of `username` into one page per source, keeping
+/// the most recently fetched copy of each tweet along with its includes and
+/// fetch times, in like order. Refuses to run while an export can still be
+/// resumed, since the pages it would add could no longer be chained to the
+/// ones before.
+pub fn compact_account(username: &str) -> Result<CompactReport, Box<dyn Error>> {
+    let _lock = lock_account(username)?;
+    #[cfg(feature = "sqlite")]
+    if cache_backend() == CacheBackend::Sqlite {
+        // Tweets are stored once already; just reclaim the space.
+        crate::cache::sqlite::SqliteCache::open_for_username(username)?.vacuum()?;
+        return Ok(CompactReport::default());
+    }
+    if cache_backend() != CacheBackend::Json {
+        return Err("Only the JSON cache can be compacted".into());
+    }
+    if ExportCheckpoint::load_for_username(username)?.is_some() {
+        return Err(format!(
+            "An export of '{username}' was interrupted. Finish it with `export --resume` before compacting."
+        )
+        .into());
+    }
+
+    let mut report = CompactReport::default();
+    for source in ExportSource::value_variants() {
+        let paths = cached_page_paths(*source, username)?;
+        if paths.is_empty() {
+            continue;
+        }
+        let (user, tweets) = load_tweets(*source, username)?;
+        if user.is_none() {
+            return Err(format!("The cached {} of '{username}' have no user", source.file_prefix()).into());
+        }
+        let loaded = tweets.len();
+        let mut tweets = dedupe_tweets(tweets);
+        // Authors are looked up again at compile time.
+        for tweet in tweets.iter_mut() {
+            tweet.user = None;
+            tweet.author_error = None;
+            tweet.source = None;
+            tweet.like_position = None;
+        }
+        report.pages_before += paths.len();
+        report.pages_after += 1;
+        report.tweets += tweets.len();
+        report.duplicates += loaded - tweets.len();
+
+        let fetched_at: Option<DateTime<Utc>> = tweets.iter().filter_map(|t| t.fetched_at).max();
+        // The tweets are kept in like order, as one export older than any
+        // export still to come.
+        let export_started_at: Option<DateTime<Utc>> = tweets.iter().filter_map(|t| t.first_seen).max();
+        let page = TwitLikeResponse {
+            id: None,
+            index: Some(0),
+            user,
+            meta: Some(TwitLikeMeta {
+                result_count: tweets.len() as u32,
+                next_token: None,
+                previous_token: None,
+            }),
+            data: Some(tweets),
+            includes: None,
+            fetched_at,
+            export_started_at,
+        };
+        let compacted = match page.fs_full_path(*source) {
+            Some(path) => path,
+            None => return Err("Could not locate the cache directory".into()),
+        };
+        if paths.contains(&compacted) {
+            return Err(format!("{} already exists, try again", compacted.display()).into());
+        }
+        // Write the new page before removing the old ones, so that an
+        // interruption leaves duplicates behind rather than losing tweets.
+        write_cache(&page, &compacted)?;
+        for path in paths {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::load_all_liked_tweets_from_cache;
+    use crate::cache::verify::verify_account;
+    use crate::twitter::mock_server::{export_started_on, mock_tweet, use_scratch_cache_dir, MockTwitter, EXPORT_LOCK};
+    use crate::twitter::retry::RetryPolicy;
+    use crate::twitter::twitter::{export_twitter_likes_for_username, TwitClient};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_overlapping_exports_are_deduped_and_compacted() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let likes: Vec<_> = (0..150)
+            .map(|i| mock_tweet(3_000_000 - i, "900", "2022-11-30T12:00:00.000Z"))
+            .collect();
+        let server = MockTwitter::new()
+            .user("56", "mock_compact", "Compacting User")
+            .user("900", "author0", "Author Zero")
+            .likes("56", likes)
+            .start()
+            .await;
+        let mut client = TwitClient::new("test-token", &server.base_url, RetryPolicy::new(0, Duration::ZERO));
+
+        export_twitter_likes_for_username(&mut client, "mock_compact", &export_started_on(1)).await.unwrap();
+        server.state.lock().unwrap().likes.get_mut("56").unwrap()[0]["text"] = json!("Edited");
+        // The first page of each export is named after the time it was fetched.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        export_twitter_likes_for_username(&mut client, "mock_compact", &export_started_on(2)).await.unwrap();
+
+        let compiled = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_compact").unwrap();
+        assert_eq!(compiled.tweets.len(), 150);
+        assert_eq!(compiled.duplicates, 150);
+        let edited = compiled.tweets.iter().find(|t| t.id == "3000000").unwrap();
+        assert_eq!(edited.text, "Edited", "the most recently fetched copy is kept");
+
+        let report = compact_account("mock_compact").unwrap();
+        assert_eq!(
+            report,
+            CompactReport { pages_before: 4, pages_after: 1, tweets: 150, duplicates: 150 }
+        );
+        assert!(verify_account("mock_compact").unwrap().is_healthy());
+        let compacted = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_compact").unwrap();
+        assert_eq!(compacted.duplicates, 0);
+        assert_eq!(compacted.tweets.len(), 150);
+        assert!(compacted.tweets.iter().all(|t| t.user.is_some() && t.fetched_at.is_some()));
+        let edited = compacted.tweets.iter().find(|t| t.id == "3000000").unwrap();
+        assert_eq!(edited.text, "Edited");
+    }
+}
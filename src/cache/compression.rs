@@ -0,0 +1,219 @@
+//! Compression of cached pages and user lookups. The compression of a file is
+//! given by its extension (`.json`, `.json.gz` or `.json.zst`), so files
+//! written with any `--cache-compression` can be read back whatever the
+//! current setting; `cache compress` converts the files already cached.
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::args::CacheCompression;
+use crate::cache::migrations::kind_of_path;
+use crate::cache::{account_directories, cache_compression, lock_account};
+use crate::twitter::serialization::{read_bytes, write_bytes};
+
+/// Compression level used for zstd; favours speed, as pages are small.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The compression of the file at `path`, judging by its extension.
+pub fn compression_of(path: &Path) -> CacheCompression {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => CacheCompression::Gzip,
+        Some("zst") => CacheCompression::Zstd,
+        _ => CacheCompression::None,
+    }
+}
+
+/// `filename` without the extension of its compression, if any.
+pub fn strip_compression_extension(filename: &str) -> &str {
+    CacheCompression::value_variants()
+        .iter()
+        .filter(|compression| **compression != CacheCompression::None)
+        .find_map(|compression| filename.strip_suffix(compression.extension()))
+        .unwrap_or(filename)
+}
+
+/// `path` with its extension changed to that of `compression`.
+pub fn with_compression(path: &Path, compression: CacheCompression) -> PathBuf {
+    let filename = path.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+    let filename = format!("{}{}", strip_compression_extension(filename), compression.extension());
+    path.with_file_name(filename)
+}
+
+/// The file cached as `path` under any compression, preferring the one
+/// currently configured, or None if there is none.
+pub fn find_variant(path: &Path) -> Option<PathBuf> {
+    let configured = cache_compression();
+    std::iter::once(configured)
+        .chain(CacheCompression::value_variants().iter().copied().filter(|c| *c != configured))
+        .map(|compression| with_compression(path, compression))
+        .find(|candidate| candidate.exists())
+}
+
+/// Removes the files cached as `path` under a compression other than its own,
+/// so that a stale copy can't be read in its place.
+pub fn remove_other_variants(path: &Path) -> io::Result<()> {
+    let own = compression_of(path);
+    for compression in CacheCompression::value_variants().iter().filter(|c| **c != own) {
+        match fs::remove_file(with_compression(path, *compression)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses `raw`, the content of the file at `path`, according to its
+/// extension.
+pub fn decompress(path: &Path, raw: Vec<u8>) -> io::Result<Vec<u8>> {
+    match compression_of(path) {
+        CacheCompression::None => Ok(raw),
+        CacheCompression::Gzip => {
+            let mut bytes = Vec::new();
+            GzDecoder::new(raw.as_slice()).read_to_end(&mut bytes)?;
+            Ok(bytes)
+        }
+        CacheCompression::Zstd => zstd::decode_all(raw.as_slice()),
+    }
+}
+
+/// Compresses `bytes`, to be written to `path`, according to its extension.
+pub fn compress(path: &Path, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    match compression_of(path) {
+        CacheCompression::None => Ok(bytes),
+        CacheCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&bytes)?;
+            encoder.finish()
+        }
+        CacheCompression::Zstd => zstd::encode_all(bytes.as_slice(), ZSTD_LEVEL),
+    }
+}
+
+/// Outcome of `compress_cache`.
+#[derive(Debug, Default, PartialEq)]
+pub struct CompressReport {
+    /// Files rewritten with the requested compression.
+    pub converted: usize,
+    /// Files which already had it.
+    pub unchanged: usize,
+    /// Size on disk of the converted files, before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Files that could not be converted, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Rewrites the cached pages and user lookups of `username` (or of every
+/// account) with the compression `to`, replacing the original files.
+pub fn compress_cache(username: Option<&str>, to: CacheCompression) -> Result<CompressReport, Box<dyn Error>> {
+    let mut report = CompressReport::default();
+    for dir in account_directories(username)? {
+        // Don't convert files under an export that is running.
+        let _lock = match dir.file_name().and_then(|name| name.to_str()) {
+            Some(account) => Some(lock_account(account)?),
+            None => None,
+        };
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| kind_of_path(path).is_some())
+            .collect();
+        paths.sort();
+        for path in paths {
+            if compression_of(&path) == to {
+                report.unchanged += 1;
+                continue;
+            }
+            match convert_file(&path, to) {
+                Ok((before, after)) => {
+                    report.converted += 1;
+                    report.bytes_before += before;
+                    report.bytes_after += after;
+                }
+                Err(err) => report.failed.push((path, err.to_string())),
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Rewrites `path` with the compression `to` and removes it. Returns the
+/// sizes of the old and new files.
+fn convert_file(path: &Path, to: CacheCompression) -> Result<(u64, u64), Box<dyn Error>> {
+    let before = fs::metadata(path)?.len();
+    let bytes = read_bytes(path)?;
+    let target = with_compression(path, to);
+    // Written before the original is removed, so an interruption leaves both.
+    write_bytes(&target, bytes)?;
+    fs::remove_file(path)?;
+    Ok((before, fs::metadata(&target)?.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ExportSource;
+    use crate::cache::{get_cache_directory_path, load_all_liked_tweets_from_cache};
+    use crate::twitter::mock_server::{use_scratch_cache_dir, EXPORT_LOCK};
+
+    #[test]
+    fn test_round_trip_by_extension() {
+        let _scratch = use_scratch_cache_dir();
+        let dir = get_cache_directory_path().unwrap().join("compression-round-trip");
+        fs::create_dir_all(&dir).unwrap();
+        let content = br#"{"version": 2, "kind": "page", "data": {}}"#.repeat(50);
+        for compression in CacheCompression::value_variants() {
+            let path = with_compression(&dir.join("likes-x-0-0.json"), *compression);
+            write_bytes(&path, content.clone()).unwrap();
+            assert_eq!(read_bytes(&path).unwrap(), content, "{compression:?}");
+            if *compression != CacheCompression::None {
+                assert!(fs::metadata(&path).unwrap().len() < content.len() as u64);
+            }
+        }
+        assert_eq!(strip_compression_extension("likes-x-0-0.json.zst"), "likes-x-0-0.json");
+        assert_eq!(
+            with_compression(&dir.join("user_id_lookup.json.gz"), CacheCompression::None),
+            dir.join("user_id_lookup.json")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compress_cache_converts_in_place() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let dir = get_cache_directory_path().unwrap().join("fixture-compress");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("likes-fixture-compress-0-0.json"),
+            include_str!("../../fixtures/cache/v2/likes-fixture-0-0.json"),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("user_id_lookup.json"),
+            include_str!("../../fixtures/cache/v2/user_id_lookup.json"),
+        )
+        .unwrap();
+        let before = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "fixture-compress").unwrap();
+
+        let report = compress_cache(Some("fixture-compress"), CacheCompression::Zstd).unwrap();
+        assert_eq!((report.converted, report.unchanged, report.failed.len()), (2, 0, 0));
+        assert!(dir.join("likes-fixture-compress-0-0.json.zst").exists());
+        assert!(!dir.join("user_id_lookup.json").exists());
+        let after = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "fixture-compress").unwrap();
+        assert_eq!(after.tweets.len(), before.tweets.len());
+        let authors = |liked: &crate::twitter::json_types::LikedTweets| -> Vec<Option<String>> {
+            liked.tweets.iter().map(|t| t.user.as_ref().map(|u| u.username.clone())).collect()
+        };
+        assert!(authors(&after).iter().all(Option::is_some), "the compressed user lookup is read");
+        assert_eq!(authors(&after), authors(&before));
+
+        let report = compress_cache(Some("fixture-compress"), CacheCompression::Gzip).unwrap();
+        assert_eq!(report.converted, 2);
+        let report = compress_cache(Some("fixture-compress"), CacheCompression::Gzip).unwrap();
+        assert_eq!((report.converted, report.unchanged), (0, 2));
+        assert!(dir.join("user_id_lookup.json.gz").exists());
+    }
+}
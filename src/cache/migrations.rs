@@ -0,0 +1,270 @@
+//! Versioning of cached files. Pages of tweets and user lookups are written in
+//! an envelope carrying a format version:
+//!
+//! ```json
+//! { "version": 2, "kind": "page", "data": { ... } }
+//! ```
+//!
+//! Files written before the envelope existed are version 1. When a file with
+//! an older version is loaded, the migrations registered in `MIGRATIONS` are
+//! applied in turn, in memory; `cache migrate` rewrites the files on disk.
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::compression::strip_compression_extension;
+use crate::twitter::serialization::{read_bytes, write_bytes};
+use crate::cache::{account_directories, lock_account};
+
+/// Format version written by this build.
+pub const CURRENT_VERSION: u32 = 2;
+
+/// The kinds of cached files that are versioned.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheKind {
+    /// A page of tweets (`TwitLikeResponse`).
+    Page,
+    /// An account's `UserIdLookup`.
+    UserLookup,
+}
+
+/// Wrapper written around the content of each versioned cache file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CacheEnvelope<T> {
+    pub version: u32,
+    pub kind: CacheKind,
+    pub data: T,
+}
+
+/// Upgrades the content of a `kind` file from version `from` to `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    pub migrate: fn(CacheKind, Value) -> Result<Value, MigrationError>,
+}
+
+/// Every migration, in order of the version it upgrades from.
+pub const MIGRATIONS: &[Migration] = &[
+    // 1 -> 2: wrap the content in a versioned envelope.
+    Migration { from: 1, migrate: wrap_in_envelope },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The file was written by a newer version of the exporter.
+    UnsupportedVersion(u32),
+    /// The file holds something other than what was expected.
+    WrongKind { expected: CacheKind, found: CacheKind },
+    /// No migration is registered from this version.
+    MissingMigration(u32),
+    /// The file is not valid JSON or not a valid envelope.
+    Invalid(String),
+}
+
+impl Error for MigrationError {}
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::UnsupportedVersion(version) => write!(
+                f,
+                "cache format version {version} is newer than this build supports ({CURRENT_VERSION}); please upgrade"
+            ),
+            MigrationError::WrongKind { expected, found } => {
+                write!(f, "expected a cached {expected:?}, found a {found:?}")
+            }
+            MigrationError::MissingMigration(version) => {
+                write!(f, "no migration from cache format version {version}")
+            }
+            MigrationError::Invalid(msg) => write!(f, "invalid cache file: {msg}"),
+        }
+    }
+}
+
+/// Format version of a cached file's content: the envelope's `version`, or 1
+/// for files written before envelopes.
+pub fn version_of(value: &Value) -> u32 {
+    match value.get("version").and_then(Value::as_u64) {
+        Some(version) if value.get("data").is_some() => version as u32,
+        _ => 1,
+    }
+}
+
+/// Applies every migration needed to bring `value` (the content of a `kind`
+/// file) up to `CURRENT_VERSION`, and returns the upgraded envelope.
+pub fn upgrade(kind: CacheKind, mut value: Value) -> Result<Value, MigrationError> {
+    let mut version = version_of(&value);
+    if version > CURRENT_VERSION {
+        return Err(MigrationError::UnsupportedVersion(version));
+    }
+    while version < CURRENT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or(MigrationError::MissingMigration(version))?;
+        value = (migration.migrate)(kind, value)?;
+        version += 1;
+        value["version"] = json!(version);
+    }
+
+    let found: CacheKind = serde_json::from_value(value["kind"].clone())
+        .map_err(|err| MigrationError::Invalid(err.to_string()))?;
+    if found != kind {
+        return Err(MigrationError::WrongKind { expected: kind, found });
+    }
+    Ok(value)
+}
+
+/// Version 1 -> 2: the content becomes the `data` of an envelope.
+fn wrap_in_envelope(kind: CacheKind, value: Value) -> Result<Value, MigrationError> {
+    Ok(json!({ "version": 2, "kind": kind, "data": value }))
+}
+
+/// Which kind of versioned file `path` is, judging by its name (whatever its
+/// compression), or None if it isn't versioned.
+pub fn kind_of_path(path: &Path) -> Option<CacheKind> {
+    let filename = strip_compression_extension(path.file_name()?.to_str()?);
+    if !filename.ends_with(".json") {
+        return None;
+    }
+    if filename == "user_id_lookup.json" {
+        return Some(CacheKind::UserLookup);
+    }
+    let is_page = ["likes-", "bookmarks-", "tweets-"]
+        .iter()
+        .any(|prefix| filename.starts_with(prefix));
+    is_page.then_some(CacheKind::Page)
+}
+
+/// Outcome of `migrate_cache`.
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Files rewritten in the current format.
+    pub migrated: Vec<PathBuf>,
+    /// Files already in the current format.
+    pub current: usize,
+    /// Files that could not be migrated, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Rewrites every versioned file in the cache of `username` (or of every
+/// account) that is in an older format.
+pub fn migrate_cache(username: Option<&str>) -> Result<MigrationReport, Box<dyn Error>> {
+    let mut report = MigrationReport::default();
+    for dir in account_directories(username)? {
+        // Don't rewrite files under an export that is running.
+        let _lock = match dir.file_name().and_then(|name| name.to_str()) {
+            Some(account) => Some(lock_account(account)?),
+            None => None,
+        };
+        let mut paths: Vec<PathBuf> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .collect();
+        paths.sort();
+        for path in paths {
+            let kind = match kind_of_path(&path) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            match migrate_file(&path, kind) {
+                Ok(true) => report.migrated.push(path),
+                Ok(false) => report.current += 1,
+                Err(err) => report.failed.push((path, err.to_string())),
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Rewrites `path` in the current format. Returns `false` if it already was.
+fn migrate_file(path: &Path, kind: CacheKind) -> Result<bool, Box<dyn Error>> {
+    let value: Value = serde_json::from_slice(&read_bytes(path)?)?;
+    if version_of(&value) == CURRENT_VERSION {
+        return Ok(false);
+    }
+    let upgraded = upgrade(kind, value)?;
+    write_bytes(path, serde_json::to_string_pretty(&upgraded)?.into_bytes())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::get_cache_directory_path;
+    use crate::serialization::FsLoadable;
+    use crate::twitter::json_types::{TwitLikeResponse, UserIdLookup};
+    use crate::twitter::mock_server::{use_scratch_cache_dir, EXPORT_LOCK};
+
+    const FIXTURES: &[(u32, &str, &str)] = &[
+        (1, "likes-fixture-0-0.json", include_str!("../../fixtures/cache/v1/likes-fixture-0-0.json")),
+        (1, "user_id_lookup.json", include_str!("../../fixtures/cache/v1/user_id_lookup.json")),
+        (2, "likes-fixture-0-0.json", include_str!("../../fixtures/cache/v2/likes-fixture-0-0.json")),
+        (2, "user_id_lookup.json", include_str!("../../fixtures/cache/v2/user_id_lookup.json")),
+    ];
+
+    /// Writes the fixtures of `version` into the account directory `username`.
+    fn write_fixtures(username: &str, version: u32) -> PathBuf {
+        let dir = get_cache_directory_path().unwrap().join(username);
+        fs::create_dir_all(&dir).unwrap();
+        for (_, name, content) in FIXTURES.iter().filter(|(v, _, _)| *v == version) {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_fixtures_of_every_version_load() {
+        let _scratch = use_scratch_cache_dir();
+        for version in 1..=CURRENT_VERSION {
+            let dir = write_fixtures(&format!("fixture-load-v{version}"), version);
+
+            let page = TwitLikeResponse::load(&dir.join("likes-fixture-0-0.json")).unwrap();
+            let data = page.data.unwrap();
+            assert_eq!(data.len(), 2, "version {version}");
+            assert_eq!(data[0].id, "1597000000000000001");
+            assert_eq!(page.user.unwrap().username, "fixture");
+
+            let user_id_lkup = UserIdLookup::load(&dir.join("user_id_lookup.json")).unwrap();
+            assert!(user_id_lkup.users_by_id["900"].is_some(), "version {version}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_cache_rewrites_old_files() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let dir = write_fixtures("fixture-migrate", 1);
+        fs::write(dir.join("likes-fixture-0-1.json"), "not json").unwrap();
+
+        let report = migrate_cache(Some("fixture-migrate")).unwrap();
+        assert_eq!(report.migrated.len(), 2);
+        assert_eq!(report.failed.len(), 1);
+        for path in &report.migrated {
+            let value: Value = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+            assert_eq!(version_of(&value), CURRENT_VERSION);
+        }
+        let page = TwitLikeResponse::load(&dir.join("likes-fixture-0-0.json")).unwrap();
+        assert_eq!(page.data.unwrap().len(), 2);
+
+        fs::remove_file(dir.join("likes-fixture-0-1.json")).unwrap();
+        let report = migrate_cache(Some("fixture-migrate")).unwrap();
+        assert_eq!((report.migrated.len(), report.current), (0, 2));
+    }
+
+    #[test]
+    fn test_upgrade_rejects_newer_and_mismatched_files() {
+        let newer = json!({"version": CURRENT_VERSION + 1, "kind": "page", "data": {}});
+        assert!(matches!(
+            upgrade(CacheKind::Page, newer),
+            Err(MigrationError::UnsupportedVersion(_))
+        ));
+
+        let lookup = json!({"version": CURRENT_VERSION, "kind": "user_lookup", "data": {}});
+        assert!(matches!(
+            upgrade(CacheKind::Page, lookup),
+            Err(MigrationError::WrongKind { .. })
+        ));
+    }
+}
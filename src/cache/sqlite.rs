@@ -0,0 +1,478 @@
+//! SQLite cache backend (`--cache-backend sqlite`), one database per account.
+//! Tweets are upserted by id, so a tweet fetched again (or liked and
+//! bookmarked) is stored once, and the ids in the cache can be read without
+//! parsing every page.
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::args::ExportSource;
+use crate::cache::{cached_page_paths, get_account_file_path, lock_account};
+use crate::serialization::FsLoadable;
+use crate::twitter::json_types::{TwitLikeDatum, TwitLikeResponse, TwitUserDatum, UserIdLookup};
+
+/// Name of the database file in each account's cache directory.
+pub const DATABASE_FILENAME: &str = "cache.sqlite3";
+
+/// Version of the schema created by `SqliteCache::open`, kept in
+/// `PRAGMA user_version`.
+const SCHEMA_VERSION: i32 = 2;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS pages (
+    source TEXT NOT NULL,
+    page_index INTEGER NOT NULL,
+    token TEXT NOT NULL,
+    user_json TEXT,
+    result_count INTEGER NOT NULL,
+    next_token TEXT,
+    fetched_at TEXT NOT NULL,
+    PRIMARY KEY (source, page_index, token)
+);
+CREATE TABLE IF NOT EXISTS tweets (
+    id TEXT PRIMARY KEY,
+    author_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    lang TEXT,
+    text TEXT NOT NULL,
+    json TEXT NOT NULL,
+    fetched_at TEXT NOT NULL,
+    first_seen_at TEXT
+);
+CREATE TABLE IF NOT EXISTS tweet_sources (
+    source TEXT NOT NULL,
+    tweet_id TEXT NOT NULL REFERENCES tweets (id),
+    page_index INTEGER NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0,
+    export_started_at TEXT,
+    PRIMARY KEY (source, tweet_id)
+);
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    username TEXT,
+    json TEXT,
+    error_json TEXT
+);
+CREATE TABLE IF NOT EXISTS media (
+    media_key TEXT PRIMARY KEY,
+    media_type TEXT NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tweet_media (
+    tweet_id TEXT NOT NULL REFERENCES tweets (id),
+    media_key TEXT NOT NULL REFERENCES media (media_key),
+    PRIMARY KEY (tweet_id, media_key)
+);
+CREATE TABLE IF NOT EXISTS sync_state (
+    source TEXT PRIMARY KEY,
+    page_count INTEGER NOT NULL,
+    last_fetched_at TEXT NOT NULL
+);
+";
+
+/// Upgrades a database created with schema version 1.
+const SCHEMA_V1_TO_V2: &str = "
+ALTER TABLE tweets ADD COLUMN first_seen_at TEXT;
+ALTER TABLE tweet_sources ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tweet_sources ADD COLUMN export_started_at TEXT;
+";
+
+/// Counts of what `SqliteCache::import_json_cache` copied.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub pages: usize,
+    pub tweets: usize,
+    pub users: usize,
+}
+
+/// Cache of a single account, stored in a SQLite database.
+pub struct SqliteCache {
+    conn: Connection,
+}
+
+impl SqliteCache {
+    /// Opens (creating if needed) the database in the cache directory of
+    /// `username`.
+    pub fn open_for_username(username: &str) -> Result<SqliteCache, Box<dyn Error>> {
+        let path = get_account_file_path(username, DATABASE_FILENAME)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::open(&path)
+    }
+
+    /// Opens (creating if needed) the database at `path`.
+    pub fn open(path: &Path) -> Result<SqliteCache, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(format!(
+                "{} has schema version {version}, newer than this build supports ({SCHEMA_VERSION})",
+                path.display()
+            )
+            .into());
+        }
+        conn.execute_batch(SCHEMA)?;
+        if version == 1 {
+            // Like order and first-seen times.
+            conn.execute_batch(SCHEMA_V1_TO_V2)?;
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(SqliteCache { conn })
+    }
+
+    /// Stores a page of tweets from `source`: the page itself, its tweets
+    /// (replacing any earlier copy with the same id), their media and the
+    /// users embedded in the response.
+    pub fn store_page(&mut self, source: ExportSource, page: &TwitLikeResponse) -> Result<(), Box<dyn Error>> {
+        let source = source.file_prefix();
+        let fetched_at = page.fetched_at.unwrap_or_else(Utc::now).to_rfc3339();
+        let page_index = page.index.unwrap_or(0) as i64;
+        let includes = page.includes.clone().unwrap_or_default();
+        let data = page.data.as_deref().unwrap_or_default();
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO pages (source, page_index, token, user_json, result_count, next_token, fetched_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (source, page_index, token) DO UPDATE SET
+                user_json = excluded.user_json, result_count = excluded.result_count,
+                next_token = excluded.next_token, fetched_at = excluded.fetched_at",
+            params![
+                source,
+                page_index,
+                page.id.as_deref().unwrap_or(""),
+                page.user.as_ref().map(serde_json::to_string).transpose()?,
+                data.len() as i64,
+                page.next_token(),
+                fetched_at,
+            ],
+        )?;
+
+        let export_started_at = page.export_started_at.map(|t| t.to_rfc3339());
+        for (position, datum) in data.iter().enumerate() {
+            let mut tweet = datum.clone();
+            tweet.includes = includes.for_tweet(datum);
+            let first_seen_at = tweet
+                .first_seen
+                .map(|t| t.to_rfc3339())
+                .or_else(|| export_started_at.clone())
+                .unwrap_or_else(|| fetched_at.clone());
+            // The earliest first-seen time of all the copies is kept.
+            tx.execute(
+                "INSERT INTO tweets (id, author_id, created_at, lang, text, json, fetched_at, first_seen_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (id) DO UPDATE SET
+                    author_id = excluded.author_id, created_at = excluded.created_at,
+                    lang = excluded.lang, text = excluded.text, json = excluded.json,
+                    fetched_at = excluded.fetched_at,
+                    first_seen_at = CASE
+                        WHEN tweets.first_seen_at IS NULL OR excluded.first_seen_at < tweets.first_seen_at
+                        THEN excluded.first_seen_at ELSE tweets.first_seen_at END",
+                params![
+                    tweet.id,
+                    tweet.author_id,
+                    tweet.created_at,
+                    tweet.lang,
+                    tweet.text,
+                    serde_json::to_string(&tweet)?,
+                    fetched_at,
+                    first_seen_at,
+                ],
+            )?;
+            tx.execute(
+                "INSERT INTO tweet_sources (source, tweet_id, page_index, position, export_started_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (source, tweet_id) DO UPDATE SET
+                    page_index = excluded.page_index, position = excluded.position,
+                    export_started_at = excluded.export_started_at",
+                params![source, tweet.id, page_index, position as i64, export_started_at],
+            )?;
+            let media = tweet.includes.iter().flat_map(|i| i.media.iter().flatten());
+            for media in media {
+                tx.execute(
+                    "INSERT INTO media (media_key, media_type, json) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (media_key) DO UPDATE SET
+                        media_type = excluded.media_type, json = excluded.json",
+                    params![media.media_key, media.media_type, serde_json::to_string(media)?],
+                )?;
+                tx.execute(
+                    "INSERT OR IGNORE INTO tweet_media (tweet_id, media_key) VALUES (?1, ?2)",
+                    params![tweet.id, media.media_key],
+                )?;
+            }
+        }
+
+        for user in includes.users.iter().flatten() {
+            upsert_user(&tx, &user.id, Some(user))?;
+        }
+
+        tx.execute(
+            "INSERT INTO sync_state (source, page_count, last_fetched_at) VALUES (?1, 1, ?2)
+             ON CONFLICT (source) DO UPDATE SET
+                page_count = page_count + 1, last_fetched_at = excluded.last_fetched_at",
+            params![source, fetched_at],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file, reclaiming the space of deleted rows.
+    pub fn vacuum(&self) -> Result<(), Box<dyn Error>> {
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Ids of all the tweets from `source` in the cache.
+    pub fn load_tweet_ids(&self, source: ExportSource) -> Result<HashSet<String>, Box<dyn Error>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT tweet_id FROM tweet_sources WHERE source = ?1")?;
+        let ids = stmt
+            .query_map(params![source.file_prefix()], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        Ok(ids)
+    }
+
+    /// All the tweets from `source` in the cache (with their includes, but
+    /// without authors) in like order, and the user whose tweets they are.
+    pub fn load_tweets(
+        &self,
+        source: ExportSource,
+    ) -> Result<(Option<TwitUserDatum>, Vec<TwitLikeDatum>), Box<dyn Error>> {
+        let source = source.file_prefix();
+        let user_json: Option<Option<String>> = self
+            .conn
+            .query_row(
+                "SELECT user_json FROM pages WHERE source = ?1 ORDER BY fetched_at DESC LIMIT 1",
+                params![source],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let user = match user_json.flatten() {
+            Some(json) => Some(serde_json::from_str(&json)?),
+            None => None,
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT tweets.json, tweets.fetched_at, tweets.first_seen_at FROM tweets
+             JOIN tweet_sources ON tweet_sources.tweet_id = tweets.id
+             WHERE tweet_sources.source = ?1
+             ORDER BY tweet_sources.export_started_at DESC, tweet_sources.page_index,
+                tweets.fetched_at DESC, tweet_sources.position",
+        )?;
+        let rows = stmt.query_map(params![source], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        let parse = |time: &str| DateTime::parse_from_rfc3339(time).ok().map(|t| t.with_timezone(&Utc));
+        let mut tweets = Vec::new();
+        for row in rows {
+            let (json, fetched_at, first_seen_at) = row?;
+            let mut tweet: TwitLikeDatum = serde_json::from_str(&json)?;
+            tweet.fetched_at = parse(&fetched_at);
+            tweet.first_seen = first_seen_at.as_deref().and_then(parse).or(tweet.fetched_at);
+            tweets.push(tweet);
+        }
+        Ok((user, tweets))
+    }
+
+    /// Loads the authors resolved so far (and the errors for those that could
+    /// not be).
+    pub fn load_user_lookup(&self) -> Result<UserIdLookup, Box<dyn Error>> {
+        let mut user_id_lkup = UserIdLookup::new();
+        let mut stmt = self.conn.prepare("SELECT id, json, error_json FROM users")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+            ))
+        })?;
+        for row in rows {
+            let (id, json, error_json) = row?;
+            let user = match json {
+                Some(json) => Some(serde_json::from_str(&json)?),
+                None => None,
+            };
+            user_id_lkup.insert(id.clone(), user);
+            if let Some(error_json) = error_json {
+                user_id_lkup.insert_error(id, serde_json::from_str(&error_json)?);
+            }
+        }
+        Ok(user_id_lkup)
+    }
+
+    /// Stores every entry of `user_id_lkup`.
+    pub fn store_user_lookup(&mut self, user_id_lkup: &UserIdLookup) -> Result<(), Box<dyn Error>> {
+        let tx = self.conn.transaction()?;
+        for (id, user) in &user_id_lkup.users_by_id {
+            upsert_user(&tx, id, user.as_ref())?;
+        }
+        for (id, error) in &user_id_lkup.errors_by_id {
+            tx.execute(
+                "INSERT INTO users (id, error_json) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET error_json = excluded.error_json",
+                params![id, serde_json::to_string(error)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Copies the JSON cache of `username` (pages of every source, and the
+    /// user lookup) into this database. Safe to run more than once.
+    pub fn import_json_cache(&mut self, username: &str) -> Result<ImportReport, Box<dyn Error>> {
+        let _lock = lock_account(username)?;
+        let mut report = ImportReport::default();
+        for source in ExportSource::value_variants() {
+            for path in cached_page_paths(*source, username)? {
+                let page = TwitLikeResponse::load(&path)?;
+                report.tweets += page.data.as_ref().map_or(0, |data| data.len());
+                self.store_page(*source, &page)?;
+                report.pages += 1;
+            }
+        }
+
+        if UserIdLookup::find_cached(username)?.is_some() {
+            let user_id_lkup = UserIdLookup::load_default(username)?;
+            report.users = user_id_lkup.users_by_id.len();
+            self.store_user_lookup(&user_id_lkup)?;
+        }
+        Ok(report)
+    }
+}
+
+/// Inserts or replaces the user `id`, keeping any error recorded for it
+/// unless the user has now been resolved.
+fn upsert_user(conn: &Connection, id: &str, user: Option<&TwitUserDatum>) -> Result<(), Box<dyn Error>> {
+    match user {
+        Some(user) => conn.execute(
+            "INSERT INTO users (id, username, json, error_json) VALUES (?1, ?2, ?3, NULL)
+             ON CONFLICT (id) DO UPDATE SET
+                username = excluded.username, json = excluded.json, error_json = NULL",
+            params![id, user.username, serde_json::to_string(user)?],
+        )?,
+        None => conn.execute("INSERT OR IGNORE INTO users (id) VALUES (?1)", params![id])?,
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{export_started_on, mock_tweet, mock_user, use_scratch_cache_dir, MockTwitter, EXPORT_LOCK};
+    use crate::twitter::retry::RetryPolicy;
+    use crate::twitter::twitter::{export_twitter_likes_for_username, TwitClient};
+    use chrono::TimeZone;
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn page(index: u64, tweets: Vec<serde_json::Value>) -> TwitLikeResponse {
+        let mut page: TwitLikeResponse = serde_json::from_value(json!({
+            "data": tweets,
+            "includes": {
+                "media": [{"media_key": "3_1", "type": "photo", "url": "https://pbs.twimg.com/media/1.jpg"}],
+                "users": [mock_user("900", "author0", "Author Zero")]
+            },
+            "meta": {"result_count": 2}
+        }))
+        .unwrap();
+        page.index = Some(index);
+        page
+    }
+
+    #[test]
+    fn test_store_page_upserts_by_tweet_id() {
+        let mut cache = SqliteCache::open(Path::new(":memory:")).unwrap();
+        let mut tweet = mock_tweet(10, "900", "2022-11-30T12:00:00.000Z");
+        tweet["attachments"] = json!({"media_keys": ["3_1"]});
+        let other = mock_tweet(11, "900", "2022-11-29T12:00:00.000Z");
+
+        cache.store_page(ExportSource::Likes, &page(0, vec![tweet.clone(), other])).unwrap();
+        tweet["text"] = json!("Edited");
+        cache.store_page(ExportSource::Likes, &page(1, vec![tweet.clone()])).unwrap();
+        cache.store_page(ExportSource::Bookmarks, &page(0, vec![tweet])).unwrap();
+
+        let (_, likes) = cache.load_tweets(ExportSource::Likes).unwrap();
+        assert_eq!(likes.len(), 2);
+        let edited = likes.iter().find(|t| t.id == "10").unwrap();
+        assert_eq!(edited.text, "Edited");
+        assert_eq!(edited.includes.as_ref().unwrap().media.as_ref().unwrap().len(), 1);
+        assert_eq!(cache.load_tweet_ids(ExportSource::Bookmarks).unwrap().len(), 1);
+
+        let count: i64 = cache.conn.query_row("SELECT COUNT(*) FROM tweets", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, 2);
+        let pages: i64 = cache
+            .conn
+            .query_row("SELECT page_count FROM sync_state WHERE source = 'likes'", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(pages, 2);
+        assert!(cache.load_user_lookup().unwrap().users_by_id["900"].is_some());
+    }
+
+    #[test]
+    fn test_load_tweets_in_like_order() {
+        let mut cache = SqliteCache::open(Path::new(":memory:")).unwrap();
+        let day = |d: u32| Utc.with_ymd_and_hms(2022, 12, d, 0, 0, 0).unwrap();
+        let tweet = |id: u64| mock_tweet(id, "900", "2020-01-01T12:00:00.000Z");
+
+        // An export over two pages, then a sync which finds a new like.
+        let mut first = page(0, vec![tweet(20), tweet(21)]);
+        first.export_started_at = Some(day(1));
+        let mut second = page(1, vec![tweet(22)]);
+        second.export_started_at = Some(day(1));
+        let mut sync = page(0, vec![tweet(23), tweet(20)]);
+        sync.export_started_at = Some(day(2));
+        for page in [&first, &second, &sync] {
+            cache.store_page(ExportSource::Likes, page).unwrap();
+        }
+
+        let (_, likes) = cache.load_tweets(ExportSource::Likes).unwrap();
+        let ids: Vec<_> = likes.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, ["23", "20", "21", "22"]);
+        assert_eq!(likes[0].first_seen, Some(day(2)));
+        assert_eq!(likes[1].first_seen, Some(day(1)), "the earliest first-seen time is kept");
+    }
+
+    #[tokio::test]
+    async fn test_import_json_cache() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let likes = (0..5)
+            .map(|i| mock_tweet(7_000_000 - i, "900", "2022-11-30T12:00:00.000Z"))
+            .collect();
+        let server = MockTwitter::new()
+            .user("54", "mock_sqlite", "SQLite User")
+            .user("900", "author0", "Author Zero")
+            .likes("54", likes)
+            .page_size(2)
+            .start()
+            .await;
+        let mut client = TwitClient::new("test-token", &server.base_url, RetryPolicy::new(0, Duration::ZERO));
+        export_twitter_likes_for_username(&mut client, "mock_sqlite", &export_started_on(1))
+            .await
+            .unwrap();
+
+        let mut cache = SqliteCache::open_for_username("mock_sqlite").unwrap();
+        let report = cache.import_json_cache("mock_sqlite").unwrap();
+        assert_eq!(report, ImportReport { pages: 3, tweets: 5, users: 1 });
+        // Importing again doesn't duplicate anything.
+        cache.import_json_cache("mock_sqlite").unwrap();
+
+        let (user, tweets) = cache.load_tweets(ExportSource::Likes).unwrap();
+        assert_eq!(user.unwrap().username, "mock_sqlite");
+        assert_eq!(tweets.len(), 5);
+        assert_eq!(
+            cache.load_user_lookup().unwrap().users_by_id["900"].as_ref().unwrap().username,
+            "author0"
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! Health checks of an account's JSON cache (`cache verify`), and the offline
+//! part of `cache repair`: moving unreadable files out of the way.
+//!
+//! Pages of an export are chained by their pagination tokens: a page is
+//! cached as `{prefix}-{username}-{index}-{token}.json`, where `token` is the
+//! `meta.next_token` of the page before it, or with `-{started_at}` added
+//! when an earlier export cached a page with the same token. The first page
+//! of each export or sync has index 0 and no token.
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::args::{CacheBackend, ExportSource};
+use crate::cache::compression::strip_compression_extension;
+use crate::cache::{cache_backend, cached_page_paths, get_account_directory_path, lock_account};
+use crate::serialization::FsLoadable;
+use crate::twitter::json_types::{TwitLikeResponse, UserIdLookup};
+
+/// Subdirectory of an account's cache directory where `cache repair` moves
+/// the files it cannot read.
+pub const QUARANTINE_DIRNAME: &str = "quarantine";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The cache is damaged or incomplete; `verify` exits non-zero.
+    Error,
+    /// Worth knowing, but expected in a healthy cache.
+    Warning,
+}
+
+/// A problem found in the cache.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// A page that can't be read: truncated or invalid JSON, or a format
+    /// newer than this build.
+    InvalidPage { source: ExportSource, path: PathBuf, error: String },
+    /// The user lookup can't be read.
+    InvalidUserLookup { path: PathBuf, error: String },
+    /// The page fetched with `next_token` (the one after `after`) is missing
+    /// or unreadable, although the export went on past it.
+    MissingPage { source: ExportSource, after: PathBuf, index: u64, next_token: String },
+    /// The last page of an export still has a `next_token`: the export
+    /// stopped early, at `--not-before-date`, at already cached tweets
+    /// (`sync`), or because it was interrupted.
+    UnfinishedExport { source: ExportSource, after: PathBuf, next_token: String },
+    /// A page which no cached page leads to: the pages before it are missing.
+    OrphanPage { source: ExportSource, path: PathBuf, index: u64 },
+    /// A page whose index doesn't follow that of the page leading to it.
+    IndexMismatch { source: ExportSource, path: PathBuf, expected: u64, found: u64 },
+    /// An author of cached tweets who is not in the user lookup.
+    MissingUser { author_id: String, tweet_ids: Vec<String> },
+}
+
+impl Issue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Issue::UnfinishedExport { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+/// An issue, as it appears in the report.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    #[serde(flatten)]
+    pub issue: Issue,
+}
+
+/// Outcome of `verify_account`, printed as JSON by `cache verify`.
+#[derive(Serialize, Debug, Default)]
+pub struct VerifyReport {
+    pub username: String,
+    /// Pages which could be read.
+    pub pages: usize,
+    /// Tweets in those pages.
+    pub tweets: usize,
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<Finding>,
+}
+
+impl VerifyReport {
+    fn push(&mut self, issue: Issue) {
+        match issue.severity() {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        self.issues.push(Finding { severity: issue.severity(), issue });
+    }
+
+    /// `true` if no errors were found (warnings are fine).
+    pub fn is_healthy(&self) -> bool {
+        self.errors == 0
+    }
+
+    /// The issues found, without their severity.
+    pub fn issues(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().map(|finding| &finding.issue)
+    }
+}
+
+/// Outcome of `cache repair`: what was done, and the state of the cache
+/// afterwards.
+#[derive(Serialize, Debug, Default)]
+pub struct RepairReport {
+    /// Unreadable files, moved to `QUARANTINE_DIRNAME`.
+    pub quarantined: Vec<PathBuf>,
+    /// Pages fetched again from the API.
+    pub refetched_pages: usize,
+    /// Authors looked up again.
+    pub backfilled_users: usize,
+    /// Verification of the repaired cache.
+    pub report: VerifyReport,
+}
+
+/// A readable page, and where it is cached.
+struct CachedPage {
+    path: PathBuf,
+    page: TwitLikeResponse,
+}
+
+/// Checks every cached page and the user lookup of `username`. Only the JSON
+/// cache can be verified.
+pub fn verify_account(username: &str) -> Result<VerifyReport, Box<dyn Error>> {
+    if cache_backend() != CacheBackend::Json {
+        return Err("Only the JSON cache can be verified".into());
+    }
+    let mut report = VerifyReport {
+        username: username.to_string(),
+        ..Default::default()
+    };
+
+    // Tweet ids by author, across sources.
+    let mut authors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for source in ExportSource::value_variants() {
+        let mut pages = Vec::new();
+        let mut unreadable_tokens = HashSet::new();
+        for path in cached_page_paths(*source, username)? {
+            match TwitLikeResponse::load(&path) {
+                Ok(page) => pages.push(CachedPage { path, page }),
+                Err(err) => {
+                    if let Some((_, token, started_at)) = page_filename_parts(&path, *source, username) {
+                        unreadable_tokens.insert((started_at, token));
+                    }
+                    report.push(Issue::InvalidPage { source: *source, path, error: err.to_string() });
+                }
+            }
+        }
+
+        for cached in &pages {
+            for tweet in cached.page.data.iter().flatten() {
+                authors.entry(tweet.author_id.clone()).or_default().push(tweet.id.clone());
+                report.tweets += 1;
+            }
+        }
+        report.pages += pages.len();
+        verify_chain(*source, &pages, &unreadable_tokens, &mut report);
+    }
+
+    let user_id_lkup = if let Some(path) = UserIdLookup::find_cached(username)? {
+        match UserIdLookup::load(&path) {
+            Ok(user_id_lkup) => user_id_lkup,
+            Err(err) => {
+                report.push(Issue::InvalidUserLookup { path, error: err.to_string() });
+                UserIdLookup::default()
+            }
+        }
+    } else {
+        UserIdLookup::default()
+    };
+    for (author_id, tweet_ids) in authors {
+        if !user_id_lkup.has(&author_id) || user_id_lkup.is_missing(&author_id) {
+            report.push(Issue::MissingUser { author_id, tweet_ids });
+        }
+    }
+    Ok(report)
+}
+
+/// Follows the pagination tokens from page to page, reporting the pages
+/// which are missing or out of place.
+fn verify_chain(
+    source: ExportSource,
+    pages: &[CachedPage],
+    unreadable_tokens: &HashSet<(Option<i64>, String)>,
+    report: &mut VerifyReport,
+) {
+    // Tokens only chain the pages of the same export: a later sync fetches
+    // pages with the same tokens.
+    let by_token: HashMap<(Option<DateTime<Utc>>, &str), &CachedPage> = pages
+        .iter()
+        .filter_map(|cached| Some(((cached.page.export_started_at, cached.page.id.as_deref()?), cached)))
+        .collect();
+    let next_tokens: HashSet<(Option<DateTime<Utc>>, &str)> = pages
+        .iter()
+        .filter_map(|cached| Some((cached.page.export_started_at, next_token_of(cached)?)))
+        .collect();
+
+    // An unreadable page named without the start of its export was cached by
+    // the first export which fetched its token, whose chain now breaks there.
+    let mut first_to_lose: HashMap<&str, Option<DateTime<Utc>>> = HashMap::new();
+    for cached in pages {
+        let started_at = cached.page.export_started_at;
+        if let Some(token) = next_token_of(cached).filter(|token| !by_token.contains_key(&(started_at, token))) {
+            first_to_lose
+                .entry(token)
+                .and_modify(|first| *first = (*first).min(started_at))
+                .or_insert(started_at);
+        }
+    }
+    let is_unreadable = |started_at: Option<DateTime<Utc>>, token: &str| {
+        let named = started_at.map(|started_at| started_at.timestamp_millis());
+        unreadable_tokens.contains(&(named, token.to_string()))
+            || (unreadable_tokens.contains(&(None, token.to_string())) && first_to_lose.get(token) == Some(&started_at))
+    };
+
+    // Orphans only tell that the pages of their own export went on.
+    let mut orphan_indexes = HashSet::new();
+    for cached in pages {
+        let index = cached.page.index.unwrap_or(0);
+        let leads_here = cached
+            .page
+            .id
+            .as_deref()
+            .is_some_and(|id| next_tokens.contains(&(cached.page.export_started_at, id)));
+        if index > 0 && !leads_here {
+            orphan_indexes.insert((cached.page.export_started_at, index));
+            report.push(Issue::OrphanPage { source, path: cached.path.clone(), index });
+        }
+    }
+
+    for cached in pages {
+        let next_token = match next_token_of(cached) {
+            Some(token) => token,
+            None => continue,
+        };
+        let index = cached.page.index.unwrap_or(0);
+        let started_at = cached.page.export_started_at;
+        match by_token.get(&(started_at, next_token)) {
+            Some(next) if next.page.index != Some(index + 1) => report.push(Issue::IndexMismatch {
+                source,
+                path: next.path.clone(),
+                expected: index + 1,
+                found: next.page.index.unwrap_or(0),
+            }),
+            Some(_) => {}
+            // The export went on past the page: it's a gap, not the end.
+            None if is_unreadable(started_at, next_token)
+                || orphan_indexes.iter().any(|(orphan_started_at, i)| *orphan_started_at == started_at && *i > index) =>
+            {
+                report.push(Issue::MissingPage {
+                    source,
+                    after: cached.path.clone(),
+                    index: index + 1,
+                    next_token: next_token.to_string(),
+                })
+            }
+            None => report.push(Issue::UnfinishedExport {
+                source,
+                after: cached.path.clone(),
+                next_token: next_token.to_string(),
+            }),
+        }
+    }
+}
+
+fn next_token_of(cached: &CachedPage) -> Option<&str> {
+    cached.page.meta.as_ref()?.next_token.as_deref()
+}
+
+/// The index and token (or timestamp) in the name of a cached page, and the
+/// start of the export (in milliseconds) if the name has one.
+fn page_filename_parts(path: &Path, source: ExportSource, username: &str) -> Option<(u64, String, Option<i64>)> {
+    let filename = strip_compression_extension(path.file_name()?.to_str()?);
+    let rest = filename
+        .strip_prefix(&format!("{}-{username}-", source.file_prefix()))?
+        .strip_suffix(".json")?;
+    let (index, token) = rest.split_once('-')?;
+    let (token, started_at) = match token.split_once('-') {
+        Some((token, started_at)) => (token, Some(started_at.parse().ok()?)),
+        None => (token, None),
+    };
+    Some((index.parse().ok()?, token.to_string(), started_at))
+}
+
+/// Moves the unreadable files listed in `report` into `QUARANTINE_DIRNAME`,
+/// so that they no longer break `compile`. Returns their new paths.
+pub fn quarantine_invalid_files(username: &str, report: &VerifyReport) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let _lock = lock_account(username)?;
+    let quarantine = get_account_directory_path(username)?.join(QUARANTINE_DIRNAME);
+    let mut moved = Vec::new();
+    for issue in report.issues() {
+        let path = match issue {
+            Issue::InvalidPage { path, .. } | Issue::InvalidUserLookup { path, .. } => path,
+            _ => continue,
+        };
+        let filename = match path.file_name() {
+            Some(filename) => filename.to_string_lossy(),
+            None => continue,
+        };
+        fs::create_dir_all(&quarantine)?;
+        let mut destination = quarantine.join(filename.as_ref());
+        if destination.exists() {
+            let timestamp = Utc::now().timestamp_millis();
+            destination = quarantine.join(format!("{filename}.{timestamp}"));
+        }
+        fs::rename(path, &destination)?;
+        moved.push(destination);
+    }
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{get_account_file_path, write_user_lookup};
+    use crate::twitter::mock_server::use_scratch_cache_dir;
+    use serde_json::json;
+
+    /// Caches a page of `tweets` (`(id, author_id)`) fetched with `token`.
+    fn write_page(username: &str, index: u64, token: Option<&str>, next_token: Option<&str>, tweets: &[(&str, &str)]) {
+        let data: Vec<_> = tweets
+            .iter()
+            .map(|(id, author_id)| json!({"id": id, "author_id": author_id, "text": "", "created_at": "2022-11-30T12:00:00.000Z"}))
+            .collect();
+        let page = json!({
+            "id": token,
+            "index": index,
+            "user": {"id": "1", "name": "Verify", "username": username},
+            "data": data,
+            "meta": {"result_count": tweets.len(), "next_token": next_token},
+        });
+        let filename = format!("likes-{username}-{index}-{}.json", token.unwrap_or("1669800000000"));
+        let path = get_account_file_path(username, &filename).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, page.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_verify_tells_gaps_from_unfinished_exports() {
+        use_scratch_cache_dir();
+        let username = "verify_chain";
+        write_page(username, 0, None, Some("t1"), &[("1", "900")]);
+        write_page(username, 1, Some("t1"), Some("t2"), &[("2", "900")]);
+        write_page(username, 3, Some("t3"), Some("t4"), &[("3", "901")]);
+        let mut user_id_lkup = UserIdLookup::default();
+        user_id_lkup.insert("900".to_string(), None);
+        write_user_lookup(username, &user_id_lkup).unwrap();
+
+        let report = verify_account(username).unwrap();
+        assert_eq!((report.pages, report.tweets), (3, 3));
+        let kinds: Vec<_> = report.issues().collect();
+        assert!(kinds.iter().any(|i| matches!(i, Issue::OrphanPage { index: 3, .. })));
+        assert!(kinds.iter().any(|i| matches!(i, Issue::MissingPage { index: 2, next_token, .. } if next_token == "t2")));
+        assert!(kinds.iter().any(|i| matches!(i, Issue::UnfinishedExport { next_token, .. } if next_token == "t4")));
+        let missing_users: Vec<_> = kinds
+            .iter()
+            .filter_map(|i| match i {
+                Issue::MissingUser { author_id, .. } => Some(author_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(missing_users, ["900", "901"]);
+        assert_eq!((report.errors, report.warnings), (4, 1));
+
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["issues"][0]["severity"], "error");
+        assert_eq!(value["issues"][0]["kind"], "orphan_page");
+    }
+
+    /// Caches a page of the export started at `started_at` (milliseconds)
+    /// under `filename`.
+    fn write_export_page(
+        username: &str,
+        filename: &str,
+        started_at: i64,
+        index: u64,
+        token: Option<&str>,
+        next_token: Option<&str>,
+    ) {
+        let page = json!({
+            "id": token,
+            "index": index,
+            "user": {"id": "1", "name": "Verify", "username": username},
+            "data": [],
+            "meta": {"result_count": 0, "next_token": next_token},
+            "export_started_at": DateTime::from_timestamp_millis(started_at).unwrap(),
+        });
+        let path = get_account_file_path(username, filename).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, page.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_verify_keeps_exports_apart() {
+        use_scratch_cache_dir();
+        let username = "verify_exports";
+        let (first, second, third) = (1669852800000, 1669939200000, 1670025600000);
+        // Interrupted after its second page.
+        write_export_page(username, &format!("likes-{username}-0-{first}.json"), first, 0, None, Some("t1"));
+        write_export_page(username, &format!("likes-{username}-1-t1.json"), first, 1, Some("t1"), Some("t2"));
+        // Its third page is lost.
+        write_export_page(username, &format!("likes-{username}-0-{second}.json"), second, 0, None, Some("u1"));
+        write_export_page(username, &format!("likes-{username}-1-u1.json"), second, 1, Some("u1"), Some("u2"));
+        write_export_page(username, &format!("likes-{username}-3-u3.json"), second, 3, Some("u3"), None);
+        // Its second page, cached under the token of a page of the first, is unreadable.
+        write_export_page(username, &format!("likes-{username}-0-{third}.json"), third, 0, None, Some("t1"));
+        let unreadable = get_account_file_path(username, &format!("likes-{username}-1-t1-{third}.json")).unwrap();
+        fs::write(unreadable, "{").unwrap();
+
+        let report = verify_account(username).unwrap();
+        let kinds: Vec<_> = report.issues().collect();
+        let after = |i: &Issue, name: &str| match i {
+            Issue::MissingPage { after, .. } | Issue::UnfinishedExport { after, .. } => after.ends_with(name),
+            _ => false,
+        };
+        assert!(kinds.iter().any(|i| matches!(i, Issue::OrphanPage { index: 3, .. })));
+        assert!(kinds.iter().any(|i| matches!(i, Issue::UnfinishedExport { next_token, .. } if next_token == "t2")));
+        assert!(kinds.iter().any(|i| matches!(i, Issue::MissingPage { index: 2, next_token, .. } if next_token == "u2")));
+        assert!(kinds.iter().any(|i| matches!(i, Issue::MissingPage { index: 1, next_token, .. } if next_token == "t1")
+            && after(i, &format!("likes-{username}-0-{third}.json"))));
+        assert_eq!(kinds.iter().filter(|i| matches!(i, Issue::MissingPage { .. })).count(), 2);
+    }
+}
@@ -1,8 +1,13 @@
 use std::{error::Error, path::Path};
-use crate::{twitter::json_types::LikedTweets, cache};
-use std::fs::File;
+use crate::{twitter::json_types::LikedTweets, cache, encryption};
 use std::io::prelude::*;
 
+pub mod bookmarks;
+pub mod html;
+pub mod jsonl;
+pub mod table;
+pub mod vault;
+
 #[cfg(windows)]
 const LINE_ENDING: &'static str = "\r\n";
 #[cfg(not(windows))]
@@ -15,25 +20,26 @@ macro_rules! write_newlines  {
 }
 
 // TODO: consider moving to_* to impl on the LikedTweets struct.
+/// Writes a compiled output to `filename`, encrypted if encryption is
+/// enabled.
+fn write_output(filename: &Path, output: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    cache::write_atomic(filename, &encryption::seal(output)?)
+        .map_err(|err| format!("Failed to write {}: {err}", filename.display()).into())
+}
+
 /// Exports all LikedTweets into a JSON format and writes it to a single file
 /// on disk.
 pub fn to_json(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
-    // We use write_cache here, since cached data is output as JSON
-    cache::write_cache(
-        liked_tweets,
-        filename
-    )
+    // Compressed like a cache file if the extension asks for it, but
+    // encrypted like the other outputs.
+    let json = serde_json::to_string_pretty(liked_tweets)?.into_bytes();
+    write_output(filename, cache::compression::compress(filename, json)?)
 }
 
 /// Exports all LikedTweets into a markdown format and writes it to a single file
 /// on disk.
 pub fn to_markdown(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
-    let display = filename.display();
-
-    let mut file = match File::create(&filename) {
-        Err(why) => panic!("Failed to open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    let mut file = Vec::new();
 
     for tweet in &liked_tweets.tweets {
         if let Some(user) = &tweet.user {
@@ -45,6 +51,8 @@ pub fn to_markdown(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Bo
                 url, 
                 tweet.author_id
             )?;
+        } else {
+            writeln!(file, "{} id({})<br>", tweet.author_label(), tweet.author_id)?;
         }
 
         writeln!(file, "*{}*<br>", tweet.created_at)?;
@@ -56,9 +64,32 @@ pub fn to_markdown(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Bo
                 }
             }
         }
+        if let Some(includes) = &tweet.includes {
+            for media in includes.media.iter().flatten() {
+                let alt = media.alt_text.as_deref().unwrap_or(&media.media_type);
+                match (&media.local_path, &media.url, &media.preview_image_url) {
+                    // Videos and GIFs can't be embedded as images; link them.
+                    (Some(local), _, _) if media.media_type != "photo" => {
+                        writeln!(file, "[{}]({})<br>", alt, local)?
+                    }
+                    (Some(src), _, _) | (None, Some(src), _) | (None, None, Some(src)) => {
+                        writeln!(file, "![{}]({})", alt, src)?
+                    }
+                    _ => {}
+                }
+            }
+            for poll in includes.polls.iter().flatten() {
+                for option in &poll.options {
+                    writeln!(file, " - {} ({} votes)", option.label, option.votes)?;
+                }
+            }
+            for quoted in includes.tweets.iter().flatten() {
+                writeln!(file, "> {}<br>", quoted.text.replace('\n', "\n> "))?;
+            }
+        }
 
         write_newlines!(file)?;
     }
 
-    Ok(())
+    write_output(filename, file)
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+//! `--format netscape`, `--format pinboard` and `--format raindrop`: one
+//! bookmark per tweet, to import into a browser or a bookmark manager.
+use std::error::Error;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use super::html::escape;
+use super::table::write_record;
+use super::write_output;
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum};
+
+/// Longest bookmark title, in characters, before it is cut short.
+const TITLE_LENGTH: usize = 100;
+
+/// What a tweet is bookmarked as.
+struct Bookmark {
+    /// The first link in the tweet, or else the tweet itself.
+    url: String,
+    title: String,
+    /// The full text of the tweet.
+    description: String,
+    /// The author's username (none if the author is unknown).
+    tags: Vec<String>,
+    /// When the tweet was written, or else when it was first fetched.
+    added: Option<DateTime<Utc>>,
+}
+
+impl Bookmark {
+    fn of(tweet: &TwitLikeDatum) -> Bookmark {
+        let url = tweet
+            .entities
+            .iter()
+            .flat_map(|e| e.urls.iter().flatten())
+            .map(|url| url.expanded_url.clone())
+            .next()
+            .unwrap_or_else(|| tweet.permalink());
+        let text = tweet.plain_text();
+        let first_line = text.lines().next().unwrap_or_default();
+        let mut title: String = first_line.chars().take(TITLE_LENGTH).collect();
+        if title.len() < first_line.len() {
+            title.push('…');
+        }
+        Bookmark {
+            url,
+            title: format!("{}: {title}", tweet.author_label()),
+            description: text,
+            tags: tweet.user.iter().map(|user| user.username.clone()).collect(),
+            added: tweet
+                .created_at_timestamp()
+                .map(|date| date.with_timezone(&Utc))
+                .or(tweet.first_seen),
+        }
+    }
+
+    /// `added` as Pinboard and Raindrop expect it, e.g. `2022-11-30T12:00:00Z`.
+    fn added_iso(&self) -> String {
+        self.added.map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string()).unwrap_or_default()
+    }
+}
+
+/// Writes the tweets of `liked_tweets` as a Netscape Bookmark File, the HTML
+/// format every browser imports.
+pub fn to_netscape(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    let mut output = String::from(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n\
+         <META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n\
+         <TITLE>Bookmarks</TITLE>\n\
+         <H1>Bookmarks</H1>\n\
+         <DL><p>\n",
+    );
+    for bookmark in liked_tweets.tweets.iter().map(Bookmark::of) {
+        output.push_str(&format!("    <DT><A HREF=\"{}\"", escape(&bookmark.url)));
+        if let Some(added) = bookmark.added {
+            output.push_str(&format!(" ADD_DATE=\"{}\"", added.timestamp()));
+        }
+        if !bookmark.tags.is_empty() {
+            output.push_str(&format!(" TAGS=\"{}\"", escape(&bookmark.tags.join(","))));
+        }
+        output.push_str(&format!(">{}</A>\n", escape(&bookmark.title)));
+        // One line per bookmark: the description ends at the next tag.
+        let description = escape(&bookmark.description).replace(['\r', '\n'], "<br>");
+        output.push_str(&format!("    <DD>{description}\n"));
+    }
+    output.push_str("</DL><p>\n");
+    write_output(filename, output.into_bytes())
+}
+
+/// Writes the tweets of `liked_tweets` as Pinboard JSON (the format of
+/// Pinboard's own export, which it and most bookmark managers import).
+pub fn to_pinboard(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    let bookmarks: Vec<_> = liked_tweets
+        .tweets
+        .iter()
+        .map(Bookmark::of)
+        .map(|bookmark| {
+            json!({
+                "href": bookmark.url,
+                "description": bookmark.title,
+                "extended": bookmark.description,
+                "time": bookmark.added_iso(),
+                "shared": "no",
+                "toread": "no",
+                // Pinboard tags are separated by spaces.
+                "tags": bookmark.tags.join(" "),
+            })
+        })
+        .collect();
+    write_output(filename, serde_json::to_vec_pretty(&bookmarks)?)
+}
+
+/// Writes the tweets of `liked_tweets` as a CSV file in the columns Raindrop
+/// imports.
+pub fn to_raindrop(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    let mut output = String::new();
+    let header = ["url", "title", "note", "tags", "created"].map(String::from);
+    write_record(&mut output, &header, ',');
+    for bookmark in liked_tweets.tweets.iter().map(Bookmark::of) {
+        let created = bookmark.added_iso();
+        let record = [bookmark.url, bookmark.title, bookmark.description, bookmark.tags.join(","), created];
+        write_record(&mut output, &record, ',');
+    }
+    write_output(filename, output.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{mock_liked_tweets, mock_tweet, use_scratch_cache_dir, with_author};
+    use serde_json::Value;
+    use std::fs;
+
+    #[test]
+    fn test_bookmark_formats() {
+        let dir = use_scratch_cache_dir();
+        let mut linked = with_author(mock_tweet(3, "900", "2022-11-30T12:00:00.000Z"), "author0", "Author Zero");
+        linked["text"] = json!("Read &lt;this&gt;\nsecond line https://t.co/3");
+        let mut unlinked = mock_tweet(4, "901", "2022-12-01T08:30:00.000Z");
+        unlinked["entities"] = json!({});
+        let liked_tweets = mock_liked_tweets(vec![linked, unlinked]);
+
+        let netscape = dir.join("bookmarks-test.html");
+        to_netscape(&netscape, &liked_tweets).unwrap();
+        let html = fs::read_to_string(&netscape).unwrap();
+        assert!(html.starts_with("<!DOCTYPE NETSCAPE-Bookmark-file-1>"));
+        assert!(html.contains(
+            "<DT><A HREF=\"https://example.com/articles/3\" ADD_DATE=\"1669809600\" TAGS=\"author0\">\
+             Author Zero: Read &lt;this&gt;</A>\n    <DD>Read &lt;this&gt;<br>second line https://t.co/3\n"
+        ));
+        assert!(html.contains("<A HREF=\"https://twitter.com/i/web/status/4\" ADD_DATE=\"1669883400\">"));
+
+        let pinboard = dir.join("bookmarks-test.json");
+        to_pinboard(&pinboard, &liked_tweets).unwrap();
+        let bookmarks: Value = serde_json::from_slice(&fs::read(&pinboard).unwrap()).unwrap();
+        assert_eq!(bookmarks[0]["href"], "https://example.com/articles/3");
+        assert_eq!(bookmarks[0]["time"], "2022-11-30T12:00:00Z");
+        assert_eq!(bookmarks[0]["tags"], "author0");
+        assert_eq!(bookmarks[1]["tags"], "");
+
+        let raindrop = dir.join("bookmarks-test.csv");
+        to_raindrop(&raindrop, &liked_tweets).unwrap();
+        let csv = fs::read_to_string(&raindrop).unwrap();
+        assert!(csv.starts_with(
+            "url,title,note,tags,created\r\n\
+             https://example.com/articles/3,Author Zero: Read <this>,\
+             \"Read <this>\nsecond line https://t.co/3\",author0,2022-11-30T12:00:00Z\r\n"
+        ));
+    }
+}
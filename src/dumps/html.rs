@@ -0,0 +1,326 @@
+//! `--format html`: a static site which can be opened from disk in a browser,
+//! with no server and nothing fetched but the media not downloaded locally.
+//!
+//! ```text
+//! {dir}/index.html                months, authors and a search box
+//! {dir}/months/{yyyy-mm}.html     tweets written in each month
+//! {dir}/authors/{username}.html   tweets of each author
+//! {dir}/search-index.json         what the search box looks through
+//! {dir}/search-index.js           the same, loadable by pages opened from disk
+//! {dir}/media/                    copies of the media downloaded with `media`
+//! {dir}/style.css, search.js
+//! ```
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::cache::write_atomic;
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum, TwitMedia};
+
+const STYLE: &str = include_str!("html/style.css");
+const SEARCH_SCRIPT: &str = include_str!("html/search.js");
+/// Name of the month page of tweets whose date can't be parsed.
+const UNDATED: &str = "undated";
+
+/// A tweet, as listed in the search index.
+#[derive(Serialize)]
+struct SearchEntry<'a> {
+    id: &'a str,
+    author: String,
+    username: Option<&'a str>,
+    date: String,
+    text: String,
+    /// The page showing the tweet, relative to the index.
+    page: String,
+}
+
+/// Writes the tweets of `liked_tweets`, in their order, as a static site in
+/// the directory `dir`. Downloaded media (see `MediaManifest::link`) is copied
+/// into the site, so that the directory can be handed around on its own.
+pub fn to_html(dir: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    for subdir in ["months", "authors", "media"] {
+        fs::create_dir_all(dir.join(subdir))?;
+    }
+    let mut months: BTreeMap<String, Vec<&TwitLikeDatum>> = BTreeMap::new();
+    let mut authors: BTreeMap<String, Vec<&TwitLikeDatum>> = BTreeMap::new();
+    for tweet in &liked_tweets.tweets {
+        months.entry(month_of(tweet)).or_default().push(tweet);
+        authors.entry(author_slug(tweet)).or_default().push(tweet);
+    }
+    copy_media(dir, liked_tweets)?;
+
+    for (month, tweets) in &months {
+        let body = cards(tweets, "../");
+        let html = page(&format!("Tweets of {month}"), "../", &body);
+        write_atomic(&dir.join("months").join(format!("{month}.html")), html.as_bytes())?;
+    }
+    for (slug, tweets) in &authors {
+        let html = page(&author_title(tweets[0]), "../", &cards(tweets, "../"));
+        write_atomic(&dir.join("authors").join(format!("{slug}.html")), html.as_bytes())?;
+    }
+
+    let title = match &liked_tweets.user {
+        Some(user) => format!("Tweets compiled for @{}", escape(&user.username)),
+        None => "Compiled tweets".to_string(),
+    };
+    let mut body = format!(
+        "<p>{} tweets.</p>\n<input id=\"search\" type=\"search\" placeholder=\"Search tweets and authors\" autofocus>\n<ul id=\"results\"></ul>\n",
+        liked_tweets.tweets.len()
+    );
+    body.push_str("<h2>By month</h2>\n<ul class=\"index\">\n");
+    // Most recent first.
+    for (month, tweets) in months.iter().rev() {
+        body.push_str(&format!(
+            "<li><a href=\"months/{month}.html\">{month}</a> ({})</li>\n",
+            tweets.len()
+        ));
+    }
+    body.push_str("</ul>\n<h2>By author</h2>\n<ul class=\"index\">\n");
+    let mut by_count: Vec<_> = authors.iter().collect();
+    by_count.sort_by_key(|(_, tweets)| std::cmp::Reverse(tweets.len()));
+    for (slug, tweets) in by_count {
+        body.push_str(&format!(
+            "<li><a href=\"authors/{slug}.html\">{}</a> ({})</li>\n",
+            author_title(tweets[0]),
+            tweets.len()
+        ));
+    }
+    body.push_str("</ul>\n<script src=\"search-index.js\"></script>\n<script src=\"search.js\"></script>\n");
+    write_atomic(&dir.join("index.html"), page(&title, "", &body).as_bytes())?;
+
+    let index: Vec<SearchEntry> = liked_tweets
+        .tweets
+        .iter()
+        .map(|tweet| SearchEntry {
+            id: &tweet.id,
+            author: tweet.author_label(),
+            username: tweet.user.as_ref().map(|u| u.username.as_str()),
+            date: display_date(tweet),
+            text: tweet.plain_text(),
+            page: format!("months/{}.html", month_of(tweet)),
+        })
+        .collect();
+    let json = serde_json::to_string(&index)?;
+    write_atomic(&dir.join("search-index.json"), json.as_bytes())?;
+    // `<` is escaped so that no tweet can close the script.
+    let script = format!("window.SEARCH_INDEX = {};\n", json.replace('<', "\\u003c"));
+    write_atomic(&dir.join("search-index.js"), script.as_bytes())?;
+    write_atomic(&dir.join("style.css"), STYLE.as_bytes())?;
+    write_atomic(&dir.join("search.js"), SEARCH_SCRIPT.as_bytes())?;
+    Ok(())
+}
+
+/// A complete page; `root` is the relative path from it to the index.
+fn page(title: &str, root: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<title>{title}</title>
+<link rel=\"stylesheet\" href=\"{root}style.css\">
+</head>
+<body>
+<nav><a href=\"{root}index.html\">Index</a></nav>
+<h1>{title}</h1>
+{body}</body>
+</html>
+"
+    )
+}
+
+/// The cards of `tweets`, on a page at `root` from the index.
+fn cards(tweets: &[&TwitLikeDatum], root: &str) -> String {
+    tweets.iter().map(|tweet| card(tweet, root)).collect()
+}
+
+/// A tweet with its media, poll and quoted tweets.
+fn card(tweet: &TwitLikeDatum, root: &str) -> String {
+    let mut html = format!("<article class=\"tweet\" id=\"tweet-{}\">\n<header>", escape(&tweet.id));
+    html.push_str(&format!(
+        "<a class=\"author\" href=\"{root}authors/{}.html\">{}</a>",
+        author_slug(tweet),
+        escape(&tweet.author_label())
+    ));
+    if let Some(user) = &tweet.user {
+        html.push_str(&format!(" <span class=\"username\">@{}</span>", escape(&user.username)));
+    }
+    html.push_str(&format!(
+        " <a class=\"date\" href=\"{}\">{}</a></header>\n",
+        escape(&tweet.permalink()),
+        display_date(tweet)
+    ));
+    html.push_str(&format!("<p class=\"text\">{}</p>\n", render_text(tweet)));
+
+    if let Some(includes) = &tweet.includes {
+        let media: String = includes.media.iter().flatten().map(|m| render_media(m, root)).collect();
+        if !media.is_empty() {
+            html.push_str(&format!("<div class=\"media\">{media}</div>\n"));
+        }
+        for poll in includes.polls.iter().flatten() {
+            html.push_str("<ul class=\"poll\">\n");
+            for option in &poll.options {
+                html.push_str(&format!("<li>{} ({} votes)</li>\n", escape(&option.label), option.votes));
+            }
+            html.push_str("</ul>\n");
+        }
+        for quoted in includes.tweets.iter().flatten() {
+            html.push_str(&format!("<blockquote>{}</blockquote>\n", render_text(quoted)));
+        }
+    }
+    html.push_str("</article>\n");
+    html
+}
+
+/// The text of `tweet`, escaped, with its t.co links expanded.
+fn render_text(tweet: &TwitLikeDatum) -> String {
+    let mut text = escape(&tweet.plain_text());
+    let urls = tweet.entities.iter().flat_map(|e| e.urls.iter().flatten());
+    for url in urls {
+        let link = format!(
+            "<a href=\"{}\">{}</a>",
+            escape(&url.expanded_url),
+            escape(&url.display_url)
+        );
+        text = text.replace(&escape(&url.url), &link);
+    }
+    text.replace('\n', "<br>\n")
+}
+
+/// A photo, or a playable video or GIF, preferring the copy in the site.
+fn render_media(media: &TwitMedia, root: &str) -> String {
+    let local = media.local_path.as_deref().and_then(local_media_name);
+    let alt = escape(media.alt_text.as_deref().unwrap_or(&media.media_type));
+    match (local, media.media_type.as_str()) {
+        (Some(name), "photo") => format!("<img src=\"{root}media/{}\" alt=\"{alt}\">", escape(&name)),
+        (Some(name), _) => format!(
+            "<video src=\"{root}media/{}\" controls loop title=\"{alt}\"></video>",
+            escape(&name)
+        ),
+        (None, _) => match media.url.as_ref().or(media.preview_image_url.as_ref()) {
+            Some(src) => format!("<img src=\"{}\" alt=\"{alt}\">", escape(src)),
+            None => String::new(),
+        },
+    }
+}
+
+/// Name under `media/` of the copy of the downloaded file at `local_path`.
+fn local_media_name(local_path: &str) -> Option<String> {
+    Path::new(local_path).file_name().map(|name| name.to_string_lossy().to_string())
+}
+
+/// Copies the downloaded media of `liked_tweets` into `{dir}/media`. Files are
+/// named by their hash, so those already copied are skipped.
+fn copy_media(dir: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    let local_paths = liked_tweets
+        .tweets
+        .iter()
+        .flat_map(|t| t.includes.iter().flat_map(|i| i.media.iter().flatten()))
+        .filter_map(|media| media.local_path.as_deref());
+    for local_path in local_paths {
+        let name = match local_media_name(local_path) {
+            Some(name) => name,
+            None => continue,
+        };
+        let target = dir.join("media").join(name);
+        if !target.exists() {
+            fs::copy(local_path, &target)
+                .map_err(|err| format!("Failed to copy {local_path} into the site: {err}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// The month `tweet` was written in, as `yyyy-mm`.
+pub(super) fn month_of(tweet: &TwitLikeDatum) -> String {
+    match tweet.created_at_timestamp() {
+        Some(timestamp) => timestamp.format("%Y-%m").to_string(),
+        None => UNDATED.to_string(),
+    }
+}
+
+fn display_date(tweet: &TwitLikeDatum) -> String {
+    match tweet.created_at_timestamp() {
+        Some(timestamp) => timestamp.format("%Y-%m-%d %H:%M").to_string(),
+        None => escape(&tweet.created_at),
+    }
+}
+
+/// Name of the author page of `tweet`: the author's username, or their id if
+/// they could not be resolved.
+pub(super) fn author_slug(tweet: &TwitLikeDatum) -> String {
+    match &tweet.user {
+        Some(user) => user.username.clone(),
+        None => format!("id-{}", tweet.author_id),
+    }
+}
+
+fn author_title(tweet: &TwitLikeDatum) -> String {
+    match &tweet.user {
+        Some(user) => format!("{} (@{})", escape(&user.name), escape(&user.username)),
+        None => escape(&tweet.author_label()),
+    }
+}
+
+/// Escapes the characters with a meaning in HTML text and attributes.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{mock_liked_tweets, mock_tweet, use_scratch_cache_dir, with_author};
+    use serde_json::json;
+
+    #[test]
+    fn test_site_has_months_authors_and_search_index() {
+        let dir = use_scratch_cache_dir().join("html-site");
+        let media_file = use_scratch_cache_dir().join("html-media-abc123.jpg");
+        fs::write(&media_file, b"jpeg").unwrap();
+
+        let mut first = with_author(mock_tweet(3, "900", "2022-11-30T12:00:00.000Z"), "author0", "Author Zero");
+        // As the API sends it.
+        first["text"] = json!("&lt;b&gt;Bold&lt;/b&gt; claim &amp; more\nhttps://t.co/3");
+        first["includes"] = json!({"media": [{
+            "media_key": "3_1", "type": "photo", "url": "https://pbs.twimg.com/media/x.jpg",
+            "local_path": media_file.to_string_lossy(),
+        }]});
+        let second = mock_tweet(2, "901", "2022-10-01T08:00:00.000Z");
+        let liked_tweets = mock_liked_tweets(vec![first, second]);
+
+        to_html(&dir, &liked_tweets).unwrap();
+        let november = fs::read_to_string(dir.join("months/2022-11.html")).unwrap();
+        assert!(november.contains("&lt;b&gt;Bold&lt;/b&gt; claim &amp; more<br>"));
+        assert!(november.contains("<a href=\"https://example.com/articles/3\">example.com/articles/3</a>"));
+        assert!(november.contains("<img src=\"../media/html-media-abc123.jpg\" alt=\"photo\">"));
+        assert!(november.contains("href=\"https://twitter.com/author0/status/3\""));
+        assert!(dir.join("media/html-media-abc123.jpg").exists());
+        assert!(dir.join("months/2022-10.html").exists());
+        assert!(dir.join("authors/author0.html").exists());
+        assert!(dir.join("authors/id-901.html").exists());
+
+        let index = fs::read_to_string(dir.join("index.html")).unwrap();
+        assert!(index.find("2022-11.html").unwrap() < index.find("2022-10.html").unwrap());
+        let search: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("search-index.json")).unwrap()).unwrap();
+        assert_eq!(search[0]["page"], "months/2022-11.html");
+        assert_eq!(search[0]["username"], "author0");
+        assert_eq!(search[0]["text"], "<b>Bold</b> claim & more\nhttps://t.co/3");
+        assert!(!fs::read_to_string(dir.join("search-index.js")).unwrap().contains("<b>"));
+    }
+}
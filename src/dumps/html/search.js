@@ -0,0 +1,33 @@
+// Searches the tweets listed in search-index.js (a copy of search-index.json
+// which, unlike the JSON file, can be loaded from pages opened from disk).
+(function () {
+  var input = document.getElementById("search");
+  var results = document.getElementById("results");
+  var index = window.SEARCH_INDEX || [];
+  var limit = 100;
+
+  function escape(text) {
+    var div = document.createElement("div");
+    div.textContent = text;
+    return div.innerHTML;
+  }
+
+  function search() {
+    var terms = input.value.toLowerCase().split(/\s+/).filter(Boolean);
+    if (terms.length === 0) {
+      results.innerHTML = "";
+      return;
+    }
+    var matches = index.filter(function (entry) {
+      var haystack = (entry.text + " " + entry.author + " " + (entry.username || "")).toLowerCase();
+      return terms.every(function (term) { return haystack.indexOf(term) !== -1; });
+    });
+    results.innerHTML = matches.slice(0, limit).map(function (entry) {
+      return "<li><a href=\"" + escape(entry.page) + "#tweet-" + escape(entry.id) + "\">" +
+        escape(entry.author) + ", " + escape(entry.date) + "</a>: " + escape(entry.text) + "</li>";
+    }).join("") + (matches.length > limit ? "<li>… and " + (matches.length - limit) + " more</li>" : "");
+  }
+
+  input.addEventListener("input", search);
+  search();
+})();
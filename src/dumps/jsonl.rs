@@ -0,0 +1,111 @@
+//! `--format jsonl`: JSON Lines, one tweet per line with its author inlined,
+//! written as the tweets are read from the cache rather than all at once.
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cache::temp_path_for;
+use crate::encryption::SealedWriter;
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum};
+
+/// The `--filename` which writes to stdout.
+pub const STDOUT_FILENAME: &str = "-";
+
+/// Writes tweets one per line, to stdout or to a file. The file is written
+/// under a temporary name and only renamed into place by `finish`, so an
+/// interrupted compile never leaves a truncated output behind.
+pub struct JsonlWriter {
+    output: Option<SealedWriter<BufWriter<Box<dyn Write>>>>,
+    /// The temporary file written to, and the file it becomes.
+    file: Option<(PathBuf, PathBuf)>,
+}
+
+impl JsonlWriter {
+    /// Starts writing to `filename`, or to stdout if it is `-`.
+    pub fn create(filename: &Path) -> Result<JsonlWriter, Box<dyn Error>> {
+        let (output, file): (Box<dyn Write>, _) = if filename == Path::new(STDOUT_FILENAME) {
+            (Box::new(io::stdout()), None)
+        } else {
+            let temp_path = temp_path_for(filename)?;
+            let output = File::create(&temp_path)
+                .map_err(|err| format!("Failed to write {}: {err}", filename.display()))?;
+            (Box::new(output), Some((temp_path, filename.to_path_buf())))
+        };
+        let mut writer = JsonlWriter { output: None, file };
+        writer.output = Some(SealedWriter::new(BufWriter::new(output))?);
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, tweet: &TwitLikeDatum) -> Result<(), Box<dyn Error>> {
+        if let Some(output) = self.output.as_mut() {
+            serde_json::to_writer(&mut *output, tweet)?;
+            output.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Flushes what is left and moves the file into place.
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(output) = self.output.take() {
+            output.finish()?.flush()?;
+        }
+        if let Some((temp_path, path)) = self.file.take() {
+            fs::rename(temp_path, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for JsonlWriter {
+    fn drop(&mut self) {
+        // Not finished: leave no temporary file behind.
+        if let Some((temp_path, _)) = self.file.take() {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// Writes the tweets of `liked_tweets`, in their order, as JSON Lines.
+pub fn to_jsonl(filename: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    let mut writer = JsonlWriter::create(filename)?;
+    for tweet in &liked_tweets.tweets {
+        writer.write(tweet)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{mock_tweet, use_scratch_cache_dir, with_author};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_one_tweet_per_line_and_nothing_left_unfinished() {
+        let path = use_scratch_cache_dir().join("jsonl-test.jsonl");
+        let mut tweet = with_author(mock_tweet(9, "900", "2022-11-30T12:00:00.000Z"), "author0", "Author Zero");
+        tweet["text"] = json!("two\nlines");
+        let tweet: TwitLikeDatum = serde_json::from_value(tweet).unwrap();
+
+        let mut writer = JsonlWriter::create(&path).unwrap();
+        writer.write(&tweet).unwrap();
+        writer.write(&tweet).unwrap();
+        assert!(!path.exists(), "only written once finished");
+        writer.finish().unwrap();
+        let output = fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = output.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["user"]["username"], "author0");
+        assert_eq!(lines[1]["text"], "two\nlines");
+
+        let mut abandoned = JsonlWriter::create(&path.with_file_name("jsonl-abandoned.jsonl")).unwrap();
+        abandoned.write(&tweet).unwrap();
+        drop(abandoned);
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().contains("jsonl-abandoned"))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
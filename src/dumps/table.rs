@@ -0,0 +1,117 @@
+//! `--format csv` and `--format tsv`: one row per tweet (or per link, with
+//! `--row-per-url`), with the columns chosen with `--columns`.
+use std::error::Error;
+use std::path::Path;
+
+use super::write_output;
+use crate::args::{TableArgs, TableColumn};
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum};
+
+/// Records end with CRLF, as RFC 4180 asks.
+const RECORD_END: &str = "\r\n";
+
+/// Writes the tweets of `liked_tweets`, in their order, as a table with fields
+/// separated by `delimiter` (`,` or `\t`). Fields containing the delimiter, a
+/// double quote or a line break (as multiline tweets do) are quoted, with
+/// double quotes doubled.
+pub fn to_table(
+    filename: &Path,
+    liked_tweets: &LikedTweets,
+    delimiter: char,
+    args: &TableArgs,
+) -> Result<(), Box<dyn Error>> {
+    let mut output = String::new();
+    let header: Vec<String> = args.columns.iter().map(|column| column.name()).collect();
+    write_record(&mut output, &header, delimiter);
+    for tweet in &liked_tweets.tweets {
+        let urls: Vec<&str> = tweet
+            .entities
+            .iter()
+            .flat_map(|e| e.urls.iter().flatten())
+            .map(|url| url.expanded_url.as_str())
+            .collect();
+        if args.row_per_url {
+            for url in &urls {
+                write_record(&mut output, &row(tweet, &args.columns, url), delimiter);
+            }
+        } else {
+            write_record(&mut output, &row(tweet, &args.columns, &urls.join(" ")), delimiter);
+        }
+    }
+    write_output(filename, output.into_bytes())
+}
+
+/// The fields of `tweet` for `columns`, with `urls` in the `urls` column.
+fn row(tweet: &TwitLikeDatum, columns: &[TableColumn], urls: &str) -> Vec<String> {
+    columns
+        .iter()
+        .map(|column| match column {
+            TableColumn::Id => tweet.id.clone(),
+            TableColumn::CreatedAt => tweet.created_at.clone(),
+            TableColumn::AuthorUsername => tweet.user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
+            TableColumn::AuthorName => tweet.author_label(),
+            TableColumn::Text => tweet.plain_text(),
+            TableColumn::Urls => urls.to_string(),
+            TableColumn::Lang => tweet.lang.clone().unwrap_or_default(),
+            TableColumn::LikePosition => tweet.like_position.map(|p| p.to_string()).unwrap_or_default(),
+            TableColumn::Permalink => tweet.permalink(),
+        })
+        .collect()
+}
+
+pub(super) fn write_record(output: &mut String, fields: &[String], delimiter: char) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            output.push(delimiter);
+        }
+        output.push_str(&quote(field, delimiter));
+    }
+    output.push_str(RECORD_END);
+}
+
+/// `field`, quoted if it has to be.
+fn quote(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{mock_liked_tweets, mock_tweet, use_scratch_cache_dir, with_author};
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn test_quoting_columns_and_rows_per_url() {
+        let dir = use_scratch_cache_dir();
+        let mut tweet = with_author(mock_tweet(7, "900", "2022-11-30T12:00:00.000Z"), "author0", "Author, Zero");
+        tweet["text"] = json!("He said \"hi &amp; bye\",\nthen left\thttps://t.co/7");
+        tweet["entities"]["urls"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({"url": "https://t.co/8", "expanded_url": "https://example.org/", "display_url": "example.org"}));
+        tweet["like_position"] = json!(0);
+        let liked_tweets = mock_liked_tweets(vec![tweet]);
+
+        let csv = dir.join("table-test.csv");
+        to_table(&csv, &liked_tweets, ',', &TableArgs::default()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&csv).unwrap(),
+            "id,created_at,author_username,author_name,text,urls,lang,like_position,permalink\r\n\
+             7,2022-11-30T12:00:00.000Z,author0,\"Author, Zero\",\"He said \"\"hi & bye\"\",\nthen left\thttps://t.co/7\",\
+             https://example.com/articles/7 https://example.org/,en,0,https://twitter.com/author0/status/7\r\n"
+        );
+
+        let tsv = dir.join("table-test.tsv");
+        let args = TableArgs { columns: vec![TableColumn::Id, TableColumn::Urls], row_per_url: true };
+        to_table(&tsv, &liked_tweets, '\t', &args).unwrap();
+        assert_eq!(
+            fs::read_to_string(&tsv).unwrap(),
+            "id\turls\r\n7\thttps://example.com/articles/7\r\n7\thttps://example.org/\r\n"
+        );
+    }
+}
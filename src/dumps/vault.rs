@@ -0,0 +1,259 @@
+//! `--format vault`: a directory of Markdown notes for Obsidian or Logseq,
+//! one per tweet, linked to a note per author and per month.
+//!
+//! ```text
+//! {dir}/tweets/tweet-{id}.md      a tweet, with YAML front matter
+//! {dir}/authors/@{username}.md    links to the tweets of each author
+//! {dir}/months/{yyyy-mm}.md       links to the tweets written in each month
+//! ```
+//!
+//! Notes are linked by name (`[[@author0]]`), which both apps resolve
+//! wherever the note is. Their names don't change between runs, so compiling
+//! into the same directory again updates the notes in place: everything above
+//! `MARKER` is rewritten and everything below it, where notes of your own go,
+//! is kept.
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::html::{author_slug, month_of};
+use crate::cache::write_atomic;
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum};
+
+/// Separates the generated part of a note from what was added to it by hand.
+pub const MARKER: &str = "<!-- Your notes go below this line; the export keeps them. -->";
+
+/// What became of a note.
+#[derive(Debug, PartialEq)]
+enum NoteWrite {
+    Written,
+    Unchanged,
+    /// The note exists without `MARKER`, so it can't be told which part of it
+    /// was generated; it is left as it is.
+    NoMarker,
+}
+
+/// Writes the tweets of `liked_tweets` as notes in the vault directory `dir`.
+/// Notes of tweets no longer compiled are left in place.
+pub fn to_vault(dir: &Path, liked_tweets: &LikedTweets) -> Result<(), Box<dyn Error>> {
+    for subdir in ["tweets", "authors", "months"] {
+        fs::create_dir_all(dir.join(subdir))?;
+    }
+    let mut months: BTreeMap<String, Vec<&TwitLikeDatum>> = BTreeMap::new();
+    let mut authors: BTreeMap<String, Vec<&TwitLikeDatum>> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    let mut write = |path: &Path, generated: &str| -> Result<(), Box<dyn Error>> {
+        if write_note(path, generated)? == NoteWrite::NoMarker {
+            skipped.push(path.display().to_string());
+        }
+        Ok(())
+    };
+
+    for tweet in &liked_tweets.tweets {
+        months.entry(month_of(tweet)).or_default().push(tweet);
+        authors.entry(author_slug(tweet)).or_default().push(tweet);
+        write(&dir.join("tweets").join(format!("{}.md", tweet_note(tweet))), &render_tweet(tweet))?;
+    }
+    for (month, tweets) in &months {
+        let generated = format!("# Tweets of {month}\n\n{}", tweet_list(tweets));
+        write(&dir.join("months").join(format!("{month}.md")), &generated)?;
+    }
+    for (slug, tweets) in &authors {
+        let generated = format!("# {}\n\n{}", author_title(tweets[0]), tweet_list(tweets));
+        write(&dir.join("authors").join(format!("@{slug}.md")), &generated)?;
+    }
+
+    if !skipped.is_empty() {
+        eprintln!(
+            "Left {} notes as they were, since the line `{MARKER}` was removed from them: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Writes `generated` above the marker of the note at `path`, keeping what is
+/// below it. The note is only rewritten if that changes it.
+fn write_note(path: &Path, generated: &str) -> Result<NoteWrite, Box<dyn Error>> {
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => Some(existing),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(format!("Failed to read {}: {err}", path.display()).into()),
+    };
+    let kept = match &existing {
+        Some(existing) => match existing.split_once(MARKER) {
+            Some((_, kept)) => kept,
+            None => return Ok(NoteWrite::NoMarker),
+        },
+        None => "\n",
+    };
+    let note = format!("{generated}\n{MARKER}{kept}");
+    if existing.as_deref() == Some(note.as_str()) {
+        return Ok(NoteWrite::Unchanged);
+    }
+    write_atomic(path, note.as_bytes())?;
+    Ok(NoteWrite::Written)
+}
+
+/// The note of `tweet`: its front matter, text and links.
+fn render_tweet(tweet: &TwitLikeDatum) -> String {
+    let urls: Vec<&str> = tweet
+        .entities
+        .iter()
+        .flat_map(|e| e.urls.iter().flatten())
+        .map(|url| url.expanded_url.as_str())
+        .collect();
+    let mut tags = vec!["twitter"];
+    tags.extend(tweet.source.map(|source| source.file_prefix()));
+
+    let mut note = String::from("---\n");
+    note.push_str(&format!("id: {}\n", yaml_string(&tweet.id)));
+    match &tweet.user {
+        Some(user) => {
+            note.push_str(&format!("author: {}\n", yaml_string(&user.username)));
+            note.push_str(&format!("author_name: {}\n", yaml_string(&user.name)));
+        }
+        None => note.push_str(&format!("author: null\nauthor_id: {}\n", yaml_string(&tweet.author_id))),
+    }
+    note.push_str(&format!("created_at: {}\n", yaml_string(&tweet.created_at)));
+    note.push_str(&yaml_list("urls", &urls));
+    match &tweet.lang {
+        Some(lang) => note.push_str(&format!("lang: {}\n", yaml_string(lang))),
+        None => note.push_str("lang: null\n"),
+    }
+    note.push_str(&yaml_list("tags", &tags));
+    note.push_str(&format!("permalink: {}\n", yaml_string(&tweet.permalink())));
+    note.push_str("---\n\n");
+
+    let month = month_of(tweet);
+    note.push_str(&format!(
+        "[[@{}|{}]] · [[{month}]]\n\n",
+        author_slug(tweet),
+        link_alias(&author_title(tweet))
+    ));
+    let mut text = tweet.text.clone();
+    for url in tweet.entities.iter().flat_map(|e| e.urls.iter().flatten()) {
+        text = text.replace(&url.url, &url.expanded_url);
+    }
+    note.push_str(&text);
+    note.push_str(&format!("\n\n[View on Twitter]({})\n", tweet.permalink()));
+    note
+}
+
+/// A list linking to the notes of `tweets`, with their author, date and
+/// first line.
+fn tweet_list(tweets: &[&TwitLikeDatum]) -> String {
+    tweets
+        .iter()
+        .map(|tweet| {
+            let first_line = tweet.text.lines().next().unwrap_or_default();
+            format!(
+                "- [[{}]] {}, {}: {first_line}\n",
+                tweet_note(tweet),
+                tweet.author_label(),
+                tweet.created_at
+            )
+        })
+        .collect()
+}
+
+/// Name of the note of `tweet`, which stays the same between runs.
+fn tweet_note(tweet: &TwitLikeDatum) -> String {
+    format!("tweet-{}", tweet.id)
+}
+
+fn author_title(tweet: &TwitLikeDatum) -> String {
+    match &tweet.user {
+        Some(user) => format!("{} (@{})", user.name, user.username),
+        None => tweet.author_label(),
+    }
+}
+
+/// `text` made safe to show in a wiki-link, `[[note|text]]`: brackets would
+/// end the link early, and a `|` start another alias.
+fn link_alias(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '[' => '(',
+            ']' => ')',
+            '|' => '¦',
+            '\n' | '\r' => ' ',
+            c => c,
+        })
+        .collect()
+}
+
+/// `value` as a double-quoted YAML scalar. (JSON strings are valid YAML.)
+fn yaml_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+fn yaml_list(key: &str, values: &[&str]) -> String {
+    if values.is_empty() {
+        return format!("{key}: []\n");
+    }
+    let mut list = format!("{key}:\n");
+    for value in values {
+        list.push_str(&format!("  - {}\n", yaml_string(value)));
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{mock_liked_tweets, mock_tweet, use_scratch_cache_dir, with_author};
+    use serde_json::json;
+
+    #[test]
+    fn test_notes_are_updated_in_place_keeping_added_notes() {
+        let dir = use_scratch_cache_dir().join("vault-test");
+        let mut tweet = with_author(mock_tweet(5, "900", "2022-11-30T12:00:00.000Z"), "author0", "Author Zero");
+        tweet["text"] = json!("A \"quoted\" line\nhttps://t.co/5");
+        tweet["source"] = json!("likes");
+        let mut liked_tweets = mock_liked_tweets(vec![tweet]);
+
+        to_vault(&dir, &liked_tweets).unwrap();
+        let path = dir.join("tweets/tweet-5.md");
+        let note = fs::read_to_string(&path).unwrap();
+        assert!(note.starts_with(
+            "---\nid: \"5\"\nauthor: \"author0\"\nauthor_name: \"Author Zero\"\n\
+             created_at: \"2022-11-30T12:00:00.000Z\"\nurls:\n  - \"https://example.com/articles/5\"\n\
+             lang: \"en\"\ntags:\n  - \"twitter\"\n  - \"likes\"\n"
+        ));
+        assert!(note.contains("[[@author0|Author Zero (@author0)]] · [[2022-11]]\n\nA \"quoted\" line\nhttps://example.com/articles/5\n"));
+        assert!(note.ends_with(&format!("{MARKER}\n")));
+        let month = fs::read_to_string(dir.join("months/2022-11.md")).unwrap();
+        assert!(month.contains("- [[tweet-5]] Author Zero, 2022-11-30T12:00:00.000Z: A \"quoted\" line\n"));
+        assert!(dir.join("authors/@author0.md").exists());
+
+        // Notes added below the marker survive a re-run which changes the tweet.
+        fs::write(&path, format!("{note}My own thoughts.\n")).unwrap();
+        liked_tweets.tweets[0].text = "Edited".to_string();
+        to_vault(&dir, &liked_tweets).unwrap();
+        let note = fs::read_to_string(&path).unwrap();
+        assert!(note.contains("\nEdited\n"));
+        assert!(note.ends_with(&format!("{MARKER}\nMy own thoughts.\n")));
+        assert_eq!(write_note(&path, &note[..note.find(MARKER).unwrap() - 1]).unwrap(), NoteWrite::Unchanged);
+
+        // Without the marker, the note is left alone.
+        fs::write(&path, "All mine.\n").unwrap();
+        to_vault(&dir, &liked_tweets).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "All mine.\n");
+    }
+
+    #[test]
+    fn test_author_names_do_not_break_links() {
+        let dir = use_scratch_cache_dir().join("vault-names-test");
+        let tweet = with_author(mock_tweet(6, "901", "2022-11-30T12:00:00.000Z"), "author1", "[[A]] | B]");
+        to_vault(&dir, &mock_liked_tweets(vec![tweet])).unwrap();
+
+        let note = fs::read_to_string(dir.join("tweets/tweet-6.md")).unwrap();
+        assert!(note.contains("\n[[@author1|((A)) ¦ B) (@author1)]] · [[2022-11]]\n"));
+        // The front matter keeps the name as it is.
+        assert!(note.contains("author_name: \"[[A]] | B]\"\n"));
+    }
+}
@@ -0,0 +1,503 @@
+//! Optional encryption at rest of the cache and of compiled outputs, with
+//! [age](https://age-encryption.org).
+//!
+//! The first time encryption is used, an age key pair (an X25519 identity) is
+//! generated and kept in `{cache_dir}/identity.age`, itself encrypted with a
+//! passphrase. The passphrase is read from `TLE_PASSPHRASE`, or prompted for;
+//! it is never taken as an argument. Encrypted files are recognised by the age
+//! header rather than by their name, so they are read back whatever the
+//! current setting. Once a cache has an identity file, every cache file written
+//! to it is encrypted, with or without `--encrypt`, so that a later run can't
+//! leave tweets in plaintext. Outputs can be decrypted with the `age` tool,
+//! e.g. `age -d -i identity.age likes.json.age`.
+use age::secrecy::{ExposeSecret, SecretString};
+use age::x25519;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::cache::compression::strip_compression_extension;
+use crate::cache::{account_directories, get_cache_directory_path, lock_account, write_atomic};
+
+/// Environment variable holding the passphrase of the identity file.
+pub const PASSPHRASE_ENV: &str = "TLE_PASSPHRASE";
+/// Environment variable holding the new passphrase for `cache rekey`.
+pub const NEW_PASSPHRASE_ENV: &str = "TLE_NEW_PASSPHRASE";
+/// Name of the identity file, in the cache directory.
+const IDENTITY_FILENAME: &str = "identity.age";
+/// Extension added to the default filename of encrypted outputs.
+pub const OUTPUT_EXTENSION: &str = ".age";
+/// Every (binary) age file starts with this line.
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+/// Whether new files are encrypted, chosen with `--encrypt` / `TLE_ENCRYPT`.
+static ENCRYPT: OnceLock<bool> = OnceLock::new();
+/// The keys of the identity file, loaded when first needed.
+static KEYRING: OnceLock<Keyring> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum EncryptionError {
+    /// No passphrase in the environment, and no terminal to prompt on.
+    NoPassphrase(&'static str),
+    /// The passphrase entered twice didn't match.
+    PassphraseMismatch,
+    /// The identity file could not be decrypted with the passphrase.
+    WrongPassphrase(PathBuf),
+    /// The identity file is missing, so encrypted files can't be read.
+    NoIdentity(PathBuf),
+    /// A file could not be decrypted with any of the keys.
+    Decrypt(String),
+    /// Encryption failed.
+    Encrypt(String),
+    /// Writing the file would replace an encrypted file with plaintext.
+    Downgrade(PathBuf),
+}
+
+impl Error for EncryptionError {}
+impl fmt::Display for EncryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionError::NoPassphrase(env) => {
+                write!(f, "no passphrase: set {env}, or run in a terminal to be prompted for it")
+            }
+            EncryptionError::PassphraseMismatch => write!(f, "the passphrases don't match"),
+            EncryptionError::WrongPassphrase(path) => {
+                write!(f, "wrong passphrase for {}", path.display())
+            }
+            EncryptionError::NoIdentity(path) => write!(
+                f,
+                "the cache is encrypted but its identity file {} is missing",
+                path.display()
+            ),
+            EncryptionError::Decrypt(msg) => write!(f, "could not decrypt: {msg}"),
+            EncryptionError::Encrypt(msg) => write!(f, "could not encrypt: {msg}"),
+            EncryptionError::Downgrade(path) => write!(
+                f,
+                "{} is encrypted, and would be replaced with plaintext",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// The age identities files are decrypted with. New files are encrypted to
+/// the first; the others are only kept while `cache rekey` runs.
+pub struct Keyring {
+    identities: Vec<x25519::Identity>,
+}
+
+impl Keyring {
+    /// A new keyring with a freshly generated identity.
+    pub fn generate() -> Keyring {
+        Keyring { identities: vec![x25519::Identity::generate()] }
+    }
+
+    /// This keyring followed by the identities of `previous`, so that files
+    /// not yet re-encrypted can still be read.
+    fn with_previous(&self, previous: Option<&Keyring>) -> Keyring {
+        let identities = self
+            .identities
+            .iter()
+            .chain(previous.map(|p| p.identities.as_slice()).unwrap_or_default())
+            .map(|identity| identity.to_string().expose_secret().parse().unwrap())
+            .collect();
+        Keyring { identities }
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        age::encrypt(&self.identities[0].to_public(), plaintext)
+            .map_err(|err| EncryptionError::Encrypt(err.to_string()))
+    }
+
+    /// Wraps `output` so that what is written to it is encrypted.
+    pub fn encrypt_stream<W: Write>(&self, output: W) -> Result<age::stream::StreamWriter<W>, EncryptionError> {
+        let recipient = self.identities[0].to_public();
+        age::Encryptor::with_recipients(iter::once(&recipient as &dyn age::Recipient))
+            .map_err(|err| EncryptionError::Encrypt(err.to_string()))?
+            .wrap_output(output)
+            .map_err(|err| EncryptionError::Encrypt(err.to_string()))
+    }
+
+    pub fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let decrypt = || -> Result<Vec<u8>, Box<dyn Error>> {
+            let decryptor = age::Decryptor::new_buffered(ciphertext)?;
+            let identities = self.identities.iter().map(|i| i as &dyn age::Identity);
+            let mut plaintext = Vec::new();
+            decryptor.decrypt(identities)?.read_to_end(&mut plaintext)?;
+            Ok(plaintext)
+        };
+        decrypt().map_err(|err| EncryptionError::Decrypt(err.to_string()))
+    }
+
+    /// Loads the identity file at `path`, decrypting it with `passphrase`.
+    pub fn load(path: &Path, passphrase: SecretString) -> Result<Keyring, Box<dyn Error>> {
+        let ciphertext = fs::read(path)?;
+        let identity = age::scrypt::Identity::new(passphrase);
+        let text = age::decrypt(&identity, &ciphertext)
+            .map_err(|_| EncryptionError::WrongPassphrase(path.to_path_buf()))?;
+        let identities = String::from_utf8(text)?
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(x25519::Identity::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid identity file {}: {err}", path.display()))?;
+        if identities.is_empty() {
+            return Err(format!("no identity in {}", path.display()).into());
+        }
+        Ok(Keyring { identities })
+    }
+
+    /// Writes the identities to `path`, encrypted with `passphrase`, in the
+    /// format of age identity files. `work_factor` is the log2 of the scrypt
+    /// cost; `None` picks the default, which takes about a second.
+    pub fn save(&self, path: &Path, passphrase: SecretString, work_factor: Option<u8>) -> Result<(), Box<dyn Error>> {
+        let mut text = String::new();
+        for identity in &self.identities {
+            text.push_str(&format!("# public key: {}\n", identity.to_public()));
+            text.push_str(identity.to_string().expose_secret());
+            text.push('\n');
+        }
+        let mut recipient = age::scrypt::Recipient::new(passphrase);
+        if let Some(work_factor) = work_factor {
+            recipient.set_work_factor(work_factor);
+        }
+        let ciphertext = age::encrypt(&recipient, text.as_bytes())
+            .map_err(|err| EncryptionError::Encrypt(err.to_string()))?;
+        write_atomic(path, &ciphertext)?;
+        Ok(())
+    }
+}
+
+/// Encrypts the files written for the rest of the process if `encrypt`. Only
+/// the first call has an effect; returns `false` if it was already set.
+pub fn set_encryption(encrypt: bool) -> bool {
+    ENCRYPT.set(encrypt).is_ok()
+}
+
+/// Whether new files are encrypted (not unless set otherwise).
+pub fn encryption_enabled() -> bool {
+    ENCRYPT.get().copied().unwrap_or_default()
+}
+
+/// `true` if `bytes` are the content of an age file.
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(AGE_HEADER)
+}
+
+/// `true` if the file at `path` exists and is an age file.
+fn file_is_encrypted(path: &Path) -> std::io::Result<bool> {
+    let mut header = [0; AGE_HEADER.len()];
+    match fs::File::open(path).and_then(|mut file| file.read_exact(&mut header)) {
+        Ok(()) => Ok(is_encrypted(&header)),
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::UnexpectedEof) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether cache files are encrypted: if encryption is enabled, or if the
+/// cache already has an identity file.
+pub fn cache_encrypted() -> bool {
+    encryption_enabled() || identity_path().is_ok_and(|path| path.exists())
+}
+
+/// Path to the identity file.
+pub fn identity_path() -> std::io::Result<PathBuf> {
+    Ok(get_cache_directory_path()?.join(IDENTITY_FILENAME))
+}
+
+/// The keys of the identity file, which is decrypted with the passphrase the
+/// first time they are needed, or created (with a new passphrase) if
+/// encryption is enabled and there is none yet.
+pub fn keyring() -> Result<&'static Keyring, Box<dyn Error>> {
+    if let Some(keyring) = KEYRING.get() {
+        return Ok(keyring);
+    }
+    let path = identity_path()?;
+    let keyring = if path.exists() {
+        Keyring::load(&path, read_passphrase(PASSPHRASE_ENV, "Passphrase: ", false)?)?
+    } else if encryption_enabled() {
+        fs::create_dir_all(get_cache_directory_path()?)?;
+        let keyring = Keyring::generate();
+        keyring.save(&path, read_passphrase(PASSPHRASE_ENV, "New passphrase: ", true)?, None)?;
+        eprintln!("Created the identity file {} (keep a copy, it can't be recreated)", path.display());
+        keyring
+    } else {
+        return Err(EncryptionError::NoIdentity(path).into());
+    };
+    Ok(KEYRING.get_or_init(|| keyring))
+}
+
+/// Reads a passphrase from the environment variable `env`, or else prompts
+/// for it on the terminal (twice if `confirm`).
+fn read_passphrase(env: &'static str, prompt: &str, confirm: bool) -> Result<SecretString, Box<dyn Error>> {
+    if let Ok(passphrase) = std::env::var(env) {
+        return Ok(SecretString::from(passphrase));
+    }
+    let passphrase =
+        rpassword::prompt_password(prompt).map_err(|_| EncryptionError::NoPassphrase(env))?;
+    if confirm && rpassword::prompt_password("Confirm passphrase: ")? != passphrase {
+        return Err(EncryptionError::PassphraseMismatch.into());
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+/// Encrypts `bytes` if encryption is enabled.
+pub fn seal(bytes: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !encryption_enabled() {
+        return Ok(bytes);
+    }
+    Ok(keyring()?.encrypt(&bytes)?)
+}
+
+/// Encrypts `bytes`, which are about to be written to the cache file at
+/// `path`, if the cache is encrypted (see `cache_encrypted`). Refuses to
+/// replace an encrypted file with plaintext.
+pub fn seal_cache_file(path: &Path, bytes: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if cache_encrypted() {
+        return Ok(keyring()?.encrypt(&bytes)?);
+    }
+    if file_is_encrypted(path)? {
+        return Err(EncryptionError::Downgrade(path.to_path_buf()).into());
+    }
+    Ok(bytes)
+}
+
+/// A writer which encrypts what is written to it if encryption is enabled.
+/// `finish` must be called once everything is written.
+pub enum SealedWriter<W: Write> {
+    Plain(W),
+    Encrypted(age::stream::StreamWriter<W>),
+}
+
+impl<W: Write> SealedWriter<W> {
+    /// Wraps `output`, encrypting if encryption is enabled.
+    pub fn new(output: W) -> Result<SealedWriter<W>, Box<dyn Error>> {
+        if !encryption_enabled() {
+            return Ok(SealedWriter::Plain(output));
+        }
+        Ok(SealedWriter::Encrypted(keyring()?.encrypt_stream(output)?))
+    }
+
+    /// Writes the end of the encrypted stream, and returns the output.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            SealedWriter::Plain(output) => Ok(output),
+            SealedWriter::Encrypted(stream) => stream.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for SealedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            SealedWriter::Plain(output) => output.write(buf),
+            SealedWriter::Encrypted(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            SealedWriter::Plain(output) => output.flush(),
+            SealedWriter::Encrypted(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Decrypts `bytes` if they are encrypted.
+pub fn open(bytes: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !is_encrypted(&bytes) {
+        return Ok(bytes);
+    }
+    Ok(keyring()?.decrypt(&bytes)?)
+}
+
+/// Outcome of `rekey_cache`.
+#[derive(Debug, Default, PartialEq)]
+pub struct RekeyReport {
+    /// Encrypted files re-encrypted with the new key.
+    pub rekeyed: usize,
+    /// Plaintext files encrypted for the first time.
+    pub encrypted: usize,
+    /// Files that could not be re-encrypted, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// `cache rekey`: generates a new identity, protected by a new passphrase, and
+/// re-encrypts every cache file with it (encrypting those which weren't). The
+/// old identity stays in the identity file until every file is done, so an
+/// interrupted rekey can be run again with the new passphrase.
+pub fn rekey_cache() -> Result<RekeyReport, Box<dyn Error>> {
+    let path = identity_path()?;
+    let old = if path.exists() { Some(keyring()?) } else { None };
+    let passphrase = read_passphrase(NEW_PASSPHRASE_ENV, "New passphrase: ", true)?;
+    let new = Keyring::generate();
+    fs::create_dir_all(get_cache_directory_path()?)?;
+    new.with_previous(old).save(&path, passphrase.clone(), None)?;
+    let mut report = RekeyReport::default();
+    reencrypt_directory(&get_cache_directory_path()?, old, &new, &mut report)?;
+    for dir in account_directories(None)? {
+        let _lock = match dir.file_name().and_then(|name| name.to_str()) {
+            Some(account) => Some(lock_account(account)?),
+            None => None,
+        };
+        reencrypt_directory(&dir, old, &new, &mut report)?;
+    }
+    if report.failed.is_empty() {
+        new.save(&path, passphrase, None)?;
+    }
+    Ok(report)
+}
+
+/// Re-encrypts the cache files in `dir` from `old` (if they were encrypted)
+/// to `new`.
+fn reencrypt_directory(
+    dir: &Path,
+    old: Option<&Keyring>,
+    new: &Keyring,
+    report: &mut RekeyReport,
+) -> Result<(), Box<dyn Error>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && is_cache_file(path))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let result = fs::read(&path).map_err(|err| err.into()).and_then(|bytes| {
+            let was_encrypted = is_encrypted(&bytes);
+            let plaintext = match (was_encrypted, old) {
+                (false, _) => bytes,
+                (true, Some(old)) => old.decrypt(&bytes)?,
+                (true, None) => return Err(EncryptionError::NoIdentity(identity_path()?).into()),
+            };
+            write_atomic(&path, &new.encrypt(&plaintext)?)?;
+            Ok::<_, Box<dyn Error>>(was_encrypted)
+        });
+        match result {
+            Ok(true) => report.rekeyed += 1,
+            Ok(false) => report.encrypted += 1,
+            Err(err) => report.failed.push((path, err.to_string())),
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` is one of the JSON files of the cache (pages, user lookups,
+/// checkpoints, the user token), whatever its compression.
+fn is_cache_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| !name.starts_with('.') && strip_compression_extension(name).ends_with(".json"))
+}
+
+/// Sets the keyring used in tests, which never prompt for a passphrase.
+#[cfg(test)]
+pub fn use_test_keyring() -> &'static Keyring {
+    KEYRING.get_or_init(Keyring::generate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::ExportSource;
+    use crate::cache::compact::compact_account;
+    use crate::cache::load_all_liked_tweets_from_cache;
+    use crate::twitter::mock_server::{export_started_on, mock_tweet, use_scratch_cache_dir, MockTwitter, EXPORT_LOCK};
+    use crate::twitter::retry::RetryPolicy;
+    use crate::twitter::serialization::write_bytes;
+    use crate::twitter::twitter::{export_twitter_likes_for_username, sync_twitter_likes_for_username, TwitClient};
+    use std::time::Duration;
+
+    #[test]
+    fn test_identity_file_needs_the_passphrase() {
+        let _scratch = use_scratch_cache_dir();
+        let path = get_cache_directory_path().unwrap().join("identity-test.age");
+        let keyring = Keyring::generate();
+        // A low work factor keeps the test fast.
+        keyring.save(&path, SecretString::from("correct horse".to_string()), Some(10)).unwrap();
+        assert!(is_encrypted(&fs::read(&path).unwrap()));
+
+        let loaded = Keyring::load(&path, SecretString::from("correct horse".to_string())).unwrap();
+        let ciphertext = keyring.encrypt(b"{}").unwrap();
+        assert_eq!(loaded.decrypt(&ciphertext).unwrap(), b"{}");
+        let err = Keyring::load(&path, SecretString::from("battery staple".to_string())).err().unwrap();
+        assert!(err.to_string().starts_with("wrong passphrase"));
+        assert!(Keyring::generate().decrypt(&ciphertext).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_cache_is_read_and_rekeyed() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let keyring = use_test_keyring();
+        let dir = get_cache_directory_path().unwrap().join("fixture-encrypt");
+        fs::create_dir_all(&dir).unwrap();
+        let page = include_bytes!("../fixtures/cache/v2/likes-fixture-0-0.json");
+        fs::write(dir.join("likes-fixture-encrypt-0-0.json"), keyring.encrypt(page).unwrap()).unwrap();
+        let lookup = include_bytes!("../fixtures/cache/v2/user_id_lookup.json");
+        fs::write(dir.join("user_id_lookup.json"), lookup).unwrap();
+
+        let liked = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "fixture-encrypt").unwrap();
+        assert_eq!(liked.tweets.len(), 2);
+        assert!(liked.tweets.iter().all(|t| t.user.is_some()));
+
+        let new = Keyring::generate();
+        let mut report = RekeyReport::default();
+        reencrypt_directory(&dir, Some(keyring), &new, &mut report).unwrap();
+        assert_eq!(report, RekeyReport { rekeyed: 1, encrypted: 1, failed: vec![] });
+        for name in ["likes-fixture-encrypt-0-0.json", "user_id_lookup.json"] {
+            let ciphertext = fs::read(dir.join(name)).unwrap();
+            assert!(keyring.decrypt(&ciphertext).is_err(), "{name}");
+            assert!(new.decrypt(&ciphertext).is_ok(), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_stays_encrypted_without_encrypt() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        // What the first run with `--encrypt` leaves behind.
+        let keyring = use_test_keyring();
+        let identity = identity_path().unwrap();
+        keyring.save(&identity, SecretString::from("correct horse".to_string()), Some(10)).unwrap();
+        assert!(!encryption_enabled());
+
+        let likes: Vec<_> = (0..150)
+            .map(|i| mock_tweet(4_000_000 - i, "900", "2022-11-30T12:00:00.000Z"))
+            .collect();
+        let server = MockTwitter::new()
+            .user("60", "mock_encrypted", "Encrypting User")
+            .user("900", "author0", "Author Zero")
+            .likes("60", likes)
+            .start()
+            .await;
+        let mut client = TwitClient::new("test-token", &server.base_url, RetryPolicy::new(0, Duration::ZERO));
+        export_twitter_likes_for_username(&mut client, "mock_encrypted", &export_started_on(1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        sync_twitter_likes_for_username(&mut client, "mock_encrypted", &export_started_on(2)).await.unwrap();
+        compact_account("mock_encrypted").unwrap();
+
+        let dir = get_cache_directory_path().unwrap().join("mock_encrypted");
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| is_cache_file(path))
+            .collect();
+        assert!(files.iter().any(|path| path.ends_with("user_id_lookup.json")));
+        for path in &files {
+            assert!(is_encrypted(&fs::read(path).unwrap()), "{}", path.display());
+        }
+        let liked = load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_encrypted").unwrap();
+        assert_eq!(liked.tweets.len(), 150);
+
+        // Without the identity file, an encrypted file isn't replaced with plaintext.
+        fs::remove_file(&identity).unwrap();
+        let err = write_bytes(&files[0], b"{}".to_vec()).err().unwrap();
+        assert!(err.to_string().contains("would be replaced with plaintext"), "{err}");
+        assert!(is_encrypted(&fs::read(&files[0]).unwrap()));
+    }
+}
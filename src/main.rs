@@ -5,17 +5,66 @@ mod args;
 mod cache;
 /// Functions to output compiled favorites.
 mod dumps;
+/// Optional encryption of the cache and of compiled outputs.
+mod encryption;
+/// Downloads media of cached tweets into a content-addressed directory.
+mod media;
 /// Functions and traits to support serialization and deserialization.
 mod serialization;
 mod twitter;
 pub mod dotenv;
 
-use args::Commands;
-use chrono::NaiveDate;
-use std::env;
+use args::{AuthCommands, CacheCommands, Commands, ExportSource};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use std::path::Path;
+use std::{env, error::Error, process};
+use crate::twitter::json_types::ExportCheckpoint;
+use crate::twitter::oauth::{self, OAuthConfig};
 use crate::twitter::twitter as tw;
 use dotenv::to_env;
 
+/// Returns the token used to authenticate requests for `source`: the app-only
+/// `BEARER_TOKEN` for likes and tweets, or the user token stored by `auth login`
+/// (refreshed if it has expired) for bookmarks.
+async fn access_token(source: ExportSource, api_base_url: &str) -> Result<String, Box<dyn Error>> {
+    match source {
+        ExportSource::Likes | ExportSource::Tweets => env::var("BEARER_TOKEN")
+            .map_err(|_| "BEARER_TOKEN environment variable is missing.".into()),
+        ExportSource::Bookmarks => {
+            let token_url = tw::TwitApiUrl::new(api_base_url).oauth2_token_url();
+            let config = OAuthConfig::from_env(oauth::DEFAULT_AUTHORIZE_URL, &token_url, 0)?;
+            Ok(oauth::load_valid_token(&config).await?.access_token)
+        }
+    }
+}
+
+/// Prints the error and exits with a non-zero status.
+fn exit_with_error(context: &str, err: Box<dyn Error>) -> ! {
+    eprintln!("{context}: {err}");
+    process::exit(1);
+}
+
+/// Prints `report` as JSON, to `path` if given or else to stdout, and exits
+/// with a non-zero status unless `healthy`.
+fn finish_cache_report<T: Serialize>(report: &T, path: Option<&Path>, healthy: bool) {
+    let written = serde_json::to_string_pretty(report)
+        .map_err(|err| err.into())
+        .and_then(|json| match path {
+            Some(path) => cache::write_atomic(path, json.as_bytes()).map_err(|err| err.into()),
+            None => {
+                println!("{json}");
+                Ok(())
+            }
+        });
+    if let Err(err) = written {
+        exit_with_error("Could not write the report", err);
+    }
+    if !healthy {
+        process::exit(1);
+    }
+}
+
 /// ```
 /// export BEARER_TOKEN=REPLACE_ME
 /// cargo run -- export --username matsuzine
@@ -25,17 +74,50 @@ use dotenv::to_env;
 async fn main() {
     // println!("cwd: {:?}", env::current_dir().unwrap().to_str().unwrap());
     match to_env() {
-        Ok(_) => println!("Loaded .env successfully"),
-        Err(err) => println!("Error loading .env: {}", err)
+        Ok(_) => eprintln!("Loaded .env successfully"),
+        Err(err) => eprintln!("Error loading .env: {}", err)
     };
-    let token = env::var("BEARER_TOKEN").expect("BEARER_TOKEN environment variable is missing.");
     let args = args::parse();
+    cache::set_cache_backend(args.cache_backend);
+    cache::set_cache_compression(args.cache_compression);
+    if args.encrypt && args.cache_backend != args::CacheBackend::Json {
+        exit_with_error(
+            "Invalid options",
+            "--encrypt needs the JSON cache backend, as the SQLite cache can't be encrypted".into(),
+        );
+    }
+    encryption::set_encryption(args.encrypt);
+    if let Some(cache_dir) = &args.cache_dir {
+        cache::set_cache_directory(cache_dir.clone());
+    }
+    if let Some(legacy) = cache::legacy_cache_directory() {
+        let adopted = cache::get_cache_directory_path()
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|cache_dir| Ok((cache::adopt_legacy_cache(&legacy, &cache_dir)?, cache_dir)));
+        match adopted {
+            Ok((accounts, cache_dir)) if !accounts.is_empty() => eprintln!(
+                "Copied the cache of {} in {} left by an earlier version into {}; the old directory can be removed.",
+                accounts.join(", "),
+                legacy.display(),
+                cache_dir.display()
+            ),
+            Ok(_) => eprintln!(
+                "Note: ignoring the cache in {} left by an earlier version. Pass `--cache-dir` to choose where the cache lives.",
+                legacy.display()
+            ),
+            Err(err) => eprintln!("Could not copy the cache in {}: {err}", legacy.display()),
+        }
+    }
 
     match &args.command {
         Some(Commands::Export {
             username,
+            source,
+            timeline,
             not_before_date,
             next_token,
+            resume,
+            retry,
         }) => {
             // Either parse a date from the option, or get a date in prehistory.
             let not_before_date = if let Some(not_before_date) = not_before_date {
@@ -44,30 +126,240 @@ async fn main() {
                 NaiveDate::MIN
             };
 
-            let next_token = next_token.as_deref();
-            match tw::export_twitter_likes_for_username(
-                &username,
-                &token,
-                next_token,
+            // When resuming, the checkpoint decides which tweets are exported.
+            let source = match ExportCheckpoint::load_for_username(username) {
+                Ok(Some(checkpoint)) if *resume => checkpoint.source,
+                _ => *source,
+            };
+            let token = access_token(source, &args.api_base_url)
+                .await
+                .unwrap_or_else(|err| exit_with_error("Export failed", err));
+
+            let options = tw::ExportOptions {
+                source,
+                exclude_replies: timeline.exclude_replies,
+                exclude_retweets: timeline.exclude_retweets,
+                next_token: next_token.clone(),
                 not_before_date,
-            )
-            .await {
+                started_at: Some(Utc::now()),
+                checkpoint: true,
+                ..Default::default()
+            };
+            let mut client = tw::TwitClient::new(&token, &args.api_base_url, retry.policy());
+            let result = if *resume {
+                tw::resume_twitter_likes_export(&mut client, username).await
+            } else {
+                tw::export_twitter_likes_for_username(&mut client, username, &options).await
+            };
+            match result {
                 Ok(_) => println!("Completed with success"),
-                Err(err) => panic!("{:?}", err),
+                Err(err) => exit_with_error("Export failed", err),
+            }
+        }
+        Some(Commands::Sync {
+            username,
+            source,
+            timeline,
+            retry,
+        }) => {
+            let token = access_token(*source, &args.api_base_url)
+                .await
+                .unwrap_or_else(|err| exit_with_error("Sync failed", err));
+            let options = tw::ExportOptions {
+                source: *source,
+                exclude_replies: timeline.exclude_replies,
+                exclude_retweets: timeline.exclude_retweets,
+                started_at: Some(Utc::now()),
+                ..Default::default()
+            };
+            let mut client = tw::TwitClient::new(&token, &args.api_base_url, retry.policy());
+            match tw::sync_twitter_likes_for_username(&mut client, username, &options).await {
+                Ok(_) => println!("Completed sync successfully"),
+                Err(err) => exit_with_error("Sync failed", err),
+            }
+        }
+        Some(Commands::Media {
+            username,
+            sources,
+            media_dir,
+            concurrency,
+            retry,
+        }) => {
+            let options = media::DownloadOptions {
+                media_dir: media_dir.clone(),
+                concurrency: *concurrency,
+                retry: retry.policy(),
+            };
+            match media::download_media_for_username(username, sources, &options).await {
+                Ok(report) => {
+                    println!(
+                        "Downloaded {} media files ({} already present) into {}",
+                        report.downloaded,
+                        report.skipped,
+                        media_dir.display()
+                    );
+                    if !report.failed.is_empty() {
+                        exit_with_error(
+                            "Media download incomplete",
+                            format!("{} files failed, run `media` again to retry", report.failed.len()).into(),
+                        );
+                    }
+                }
+                Err(err) => exit_with_error("Media download failed", err),
             }
         }
+        Some(Commands::Cache { command }) => match command {
+            CacheCommands::Migrate { username } => {
+                match cache::migrations::migrate_cache(username.as_deref()) {
+                    Ok(report) => {
+                        for (path, err) in &report.failed {
+                            eprintln!("Could not migrate {}: {err}", path.display());
+                        }
+                        println!(
+                            "Migrated {} cache files to format version {} ({} already up to date, {} failed)",
+                            report.migrated.len(),
+                            cache::migrations::CURRENT_VERSION,
+                            report.current,
+                            report.failed.len()
+                        );
+                        if !report.failed.is_empty() {
+                            process::exit(1);
+                        }
+                    }
+                    Err(err) => exit_with_error("Migration failed", err),
+                }
+            }
+            CacheCommands::Verify { username, report } => match cache::verify::verify_account(username) {
+                Ok(verified) => {
+                    eprintln!(
+                        "Checked {} pages ({} tweets): {} errors, {} warnings",
+                        verified.pages, verified.tweets, verified.errors, verified.warnings
+                    );
+                    finish_cache_report(&verified, report.as_deref(), verified.is_healthy());
+                }
+                Err(err) => exit_with_error("Verification failed", err),
+            },
+            CacheCommands::Repair {
+                username,
+                source,
+                report,
+                retry,
+            } => {
+                let token = access_token(*source, &args.api_base_url)
+                    .await
+                    .unwrap_or_else(|err| exit_with_error("Repair failed", err));
+                let mut client = tw::TwitClient::new(&token, &args.api_base_url, retry.policy());
+                match tw::repair_cache_for_username(&mut client, username, *source).await {
+                    Ok(repaired) => {
+                        eprintln!(
+                            "Quarantined {} files, fetched {} pages and {} users again: {} errors remain",
+                            repaired.quarantined.len(),
+                            repaired.refetched_pages,
+                            repaired.backfilled_users,
+                            repaired.report.errors
+                        );
+                        finish_cache_report(&repaired, report.as_deref(), repaired.report.is_healthy());
+                    }
+                    Err(err) => exit_with_error("Repair failed", err),
+                }
+            }
+            CacheCommands::Compact { username } => match cache::compact::compact_account(username) {
+                Ok(report) => println!(
+                    "Compacted {} pages into {}: {} tweets kept, {} duplicates removed",
+                    report.pages_before, report.pages_after, report.tweets, report.duplicates
+                ),
+                Err(err) => exit_with_error("Compaction failed", err),
+            },
+            CacheCommands::Rekey => match encryption::rekey_cache() {
+                Ok(report) => {
+                    for (path, err) in &report.failed {
+                        eprintln!("Could not re-encrypt {}: {err}", path.display());
+                    }
+                    println!(
+                        "Re-encrypted {} cache files and encrypted {} more with the new key ({} failed)",
+                        report.rekeyed,
+                        report.encrypted,
+                        report.failed.len()
+                    );
+                    if !report.failed.is_empty() {
+                        eprintln!("The old key is kept until `cache rekey` succeeds; run it again with the new passphrase");
+                        process::exit(1);
+                    }
+                }
+                Err(err) => exit_with_error("Rekey failed", err),
+            },
+            CacheCommands::Compress { username, to } => {
+                match cache::compression::compress_cache(username.as_deref(), *to) {
+                    Ok(report) => {
+                        for (path, err) in &report.failed {
+                            eprintln!("Could not convert {}: {err}", path.display());
+                        }
+                        println!(
+                            "Converted {} cache files to {:?} ({} -> {} bytes, {} already converted, {} failed)",
+                            report.converted,
+                            to,
+                            report.bytes_before,
+                            report.bytes_after,
+                            report.unchanged,
+                            report.failed.len()
+                        );
+                        if !report.failed.is_empty() {
+                            process::exit(1);
+                        }
+                    }
+                    Err(err) => exit_with_error("Compression failed", err),
+                }
+            }
+            #[cfg(feature = "sqlite")]
+            CacheCommands::ImportJson { username } => {
+                let result = cache::sqlite::SqliteCache::open_for_username(username)
+                    .and_then(|mut db| db.import_json_cache(username));
+                match result {
+                    Ok(report) => println!(
+                        "Imported {} pages ({} tweets) and {} users into the SQLite cache",
+                        report.pages, report.tweets, report.users
+                    ),
+                    Err(err) => exit_with_error("Import failed", err),
+                }
+            }
+        },
+        Some(Commands::Auth { command }) => match command {
+            AuthCommands::Login { port, authorize_url } => {
+                let token_url = tw::TwitApiUrl::new(&args.api_base_url).oauth2_token_url();
+                let result = match OAuthConfig::from_env(authorize_url, &token_url, *port) {
+                    Ok(config) => oauth::login(&config, *port).await,
+                    Err(err) => Err(err.into()),
+                };
+                match result {
+                    Ok(_) => println!("Logged in. The user token is stored in the cache."),
+                    Err(err) => exit_with_error("Login failed", err),
+                }
+            }
+            AuthCommands::Logout => match oauth::logout() {
+                Ok(_) => println!("Logged out."),
+                Err(err) => exit_with_error("Logout failed", err.into()),
+            },
+        },
         Some(Commands::Compile { 
             username, 
+            sources,
             format,
             filename,
+            sort,
+            media_dir,
+            table,
         }) => {
             match tw::compile_twitter_exports_for_username(
                 username,
+                sources,
                 format,
                 filename.as_deref(),
+                *sort,
+                media_dir,
+                table,
             ) {
-                Ok(_) => println!("Completed compilation successfully"),
-                Err(err) => println!("{:?}", err),
+                Ok(_) => eprintln!("Completed compilation successfully"),
+                Err(err) => exit_with_error("Compile failed", err),
             };
         }
         None => {}
@@ -0,0 +1,405 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::args::ExportSource;
+use crate::cache;
+use crate::serialization::{FsCacheable, FsLoadable};
+use crate::twitter::errors::TwitterApiError;
+use crate::twitter::json_types::{LikedTweets, TwitLikeDatum, TwitMedia};
+use crate::twitter::retry::RetryPolicy;
+
+/// Default directory (relative to the working directory) for downloaded media.
+pub const DEFAULT_MEDIA_DIRNAME: &str = "media";
+const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// A downloaded media file, stored under the SHA-256 hash of its content.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MediaFile {
+    /// Path of the file, relative to the media directory.
+    pub path: String,
+    /// Hex encoded SHA-256 of the file content.
+    pub sha256: String,
+    /// Size of the file in bytes.
+    pub bytes: u64,
+}
+
+/// Maps the remote url of every downloaded media file to where it is stored.
+/// Written to `manifest.json` in the media directory after each download, so
+/// that an interrupted download picks up where it stopped.
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct MediaManifest {
+    pub files: BTreeMap<String, MediaFile>,
+}
+
+impl MediaManifest {
+    /// Returns the full file path to the manifest in `media_dir`.
+    pub fn fs_full_path(media_dir: &Path) -> PathBuf {
+        media_dir.join(MANIFEST_FILENAME)
+    }
+
+    /// Loads the manifest from `media_dir`, or an empty one if nothing has
+    /// been downloaded there yet.
+    pub fn load_or_default(media_dir: &Path) -> Result<MediaManifest, Box<dyn Error>> {
+        let path = Self::fs_full_path(media_dir);
+        if !path.exists() {
+            return Ok(MediaManifest::default());
+        }
+        Self::load(&path)
+    }
+
+    /// `true` if the file downloaded from `url` is still on disk and its
+    /// content matches the recorded checksum.
+    pub fn verify(&self, media_dir: &Path, url: &str) -> bool {
+        let file = match self.files.get(url) {
+            Some(file) => file,
+            None => return false,
+        };
+        match fs::read(media_dir.join(&file.path)) {
+            Ok(content) => sha256_hex(&content) == file.sha256,
+            Err(_) => false,
+        }
+    }
+
+    /// Points each media item of `liked_tweets` that has been downloaded at
+    /// its local copy under `media_dir`. Returns the number of items linked.
+    pub fn link(&self, media_dir: &Path, liked_tweets: &mut LikedTweets) -> usize {
+        liked_tweets.tweets.iter_mut().map(|tweet| self.link_tweet(media_dir, tweet)).sum()
+    }
+
+    /// Like `link`, for the media of a single tweet.
+    pub fn link_tweet(&self, media_dir: &Path, tweet: &mut TwitLikeDatum) -> usize {
+        let mut linked = 0;
+        let media = tweet.includes.iter_mut().flat_map(|i| i.media.iter_mut().flatten());
+        for media in media {
+            let file = media_url(media).and_then(|url| self.files.get(&url));
+            if let Some(file) = file {
+                media.local_path = Some(media_dir.join(&file.path).to_string_lossy().to_string());
+                linked += 1;
+            }
+        }
+        linked
+    }
+}
+
+impl FsCacheable<MediaManifest> for MediaManifest {
+    fn cache(&self, path: &Path) -> Result<&Self, Box<dyn Error>> {
+        cache::write_atomic(path, serde_json::to_string_pretty(self)?.as_bytes())?;
+        Ok(self)
+    }
+}
+
+impl FsLoadable<MediaManifest> for MediaManifest {
+    fn load(path: &Path) -> Result<MediaManifest, Box<dyn Error>> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Returns the url to download for a media item: the image for photos, or
+/// the highest bitrate variant for videos and animated GIFs.
+pub fn media_url(media: &TwitMedia) -> Option<String> {
+    if let Some(url) = &media.url {
+        return Some(url.clone());
+    }
+    media
+        .variants
+        .iter()
+        .flatten()
+        .max_by_key(|v| v.bit_rate.unwrap_or(0))
+        .map(|v| v.url.clone())
+}
+
+/// Hex encoded SHA-256 hash of `content`.
+pub fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// File extension of the url's path (without query), e.g. "jpg" or "mp4".
+fn extension(url: &str) -> &str {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let filename = path.rsplit('/').next().unwrap_or(path);
+    match filename.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() && ext.len() <= 5 => ext,
+        _ => "bin",
+    }
+}
+
+/// Options controlling where and how media is downloaded.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Directory that receives the files and the manifest.
+    pub media_dir: PathBuf,
+    /// Maximum number of downloads in flight.
+    pub concurrency: usize,
+    /// How failed downloads are retried.
+    pub retry: RetryPolicy,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> DownloadOptions {
+        DownloadOptions {
+            media_dir: PathBuf::from(DEFAULT_MEDIA_DIRNAME),
+            concurrency: 4,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// Outcome of a media download run.
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    /// Files fetched in this run.
+    pub downloaded: usize,
+    /// Files already present with a matching checksum.
+    pub skipped: usize,
+    /// Urls that could not be downloaded, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Downloads every photo, GIF and video of the tweets cached for `username`
+/// from `sources` into `options.media_dir`. Files already downloaded (and
+/// whose checksum still matches) are skipped, so an interrupted run can simply
+/// be started again.
+pub async fn download_media_for_username(
+    username: &str,
+    sources: &[ExportSource],
+    options: &DownloadOptions,
+) -> Result<DownloadReport, Box<dyn Error>> {
+    let liked_tweets = cache::load_all_liked_tweets_from_cache(sources, username)?;
+    let media_dir = &options.media_dir;
+    fs::create_dir_all(media_dir)?;
+    let mut manifest = MediaManifest::load_or_default(media_dir)?;
+    let mut report = DownloadReport::default();
+
+    let mut urls: Vec<String> = liked_tweets
+        .tweets
+        .iter()
+        .flat_map(|t| t.includes.iter().flat_map(|i| i.media.iter().flatten()))
+        .filter_map(media_url)
+        .collect();
+    urls.sort();
+    urls.dedup();
+
+    let client = reqwest::Client::new();
+    let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let mut downloads = JoinSet::new();
+    for url in urls {
+        if manifest.verify(media_dir, &url) {
+            report.skipped += 1;
+            continue;
+        }
+        let client = client.clone();
+        let semaphore = semaphore.clone();
+        let media_dir = media_dir.clone();
+        let retry = options.retry;
+        downloads.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let result = download(&client, &url, &media_dir, retry).await;
+            (url, result)
+        });
+    }
+
+    while let Some(joined) = downloads.join_next().await {
+        let (url, result) = joined?;
+        match result {
+            Ok(file) => {
+                println!("Downloaded: {url} -> {}", file.path);
+                manifest.files.insert(url, file);
+                manifest.cache(&MediaManifest::fs_full_path(media_dir))?;
+                report.downloaded += 1;
+            }
+            Err(err) => {
+                eprintln!("Failed to download {url}: {err}");
+                report.failed.push((url, err.to_string()));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Downloads `url` into `media_dir` under its content hash, retrying
+/// transient failures.
+async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    media_dir: &Path,
+    retry: RetryPolicy,
+) -> Result<MediaFile, Box<dyn Error + Send + Sync>> {
+    let mut attempt: u32 = 0;
+    let content = loop {
+        match fetch(client, url).await {
+            Ok(content) => break content,
+            Err(err) if err.is_transient() && attempt < retry.max_retries => {
+                tokio::time::sleep(retry.delay(attempt)).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+
+    let sha256 = sha256_hex(&content);
+    let path = format!("{}/{sha256}.{}", &sha256[..2], extension(url));
+    let full_path = media_dir.join(&path);
+    // Identical content is stored once, unless the copy on disk is damaged.
+    let intact = fs::read(&full_path).is_ok_and(|existing| sha256_hex(&existing) == sha256);
+    if !intact {
+        if let Some(parent) = full_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // An interrupted download never leaves a truncated file under a
+        // content hash.
+        cache::write_atomic(&full_path, &content)?;
+    }
+
+    Ok(MediaFile {
+        path,
+        sha256,
+        bytes: content.len() as u64,
+    })
+}
+
+/// Fetches the body of `url`, checking it against the `content-length`.
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, TwitterApiError> {
+    let resp = client.get(url).send().await?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return Err(TwitterApiError::RateLimited {
+            retry_after: Duration::ZERO,
+        });
+    }
+    if !status.is_success() {
+        return Err(TwitterApiError::from_status(status, url.to_string()));
+    }
+
+    let expected_len = resp.content_length();
+    let content = resp.bytes().await?.to_vec();
+    match expected_len {
+        Some(len) if len != content.len() as u64 => Err(TwitterApiError::Server {
+            status,
+            body: format!("{url}: expected {len} bytes, got {}", content.len()),
+        }),
+        _ => Ok(content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::json_types::TwitMediaVariant;
+    use crate::twitter::mock_server::{export_started_on, mock_tweet, use_scratch_cache_dir, MockTwitter, EXPORT_LOCK};
+    use crate::twitter::twitter::{export_twitter_likes_for_username, TwitClient};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_download_media_resumes_and_verifies() {
+        let _lock = EXPORT_LOCK.lock().await;
+        let scratch_dir = use_scratch_cache_dir();
+        let mut liked = mock_tweet(3000, "903", "2022-11-30T12:00:00.000Z");
+        liked["attachments"] = json!({"media_keys": ["3_1", "7_1", "3_2", "3_404"]});
+        let server = MockTwitter::new()
+            .user("50", "mock_media", "Media User")
+            .user("903", "author3", "Author Three")
+            .likes("50", vec![liked])
+            .file("/media/cat.jpg", b"cat picture")
+            .file("/media/same-cat.jpg", b"cat picture")
+            .file("/video/high.mp4", b"high bitrate video")
+            .file("/video/low.mp4", b"low bitrate video")
+            .start()
+            .await;
+        let base = &server.base_url;
+        server.state.lock().unwrap().includes = Some(json!({"media": [
+            {"media_key": "3_1", "type": "photo", "url": format!("{base}/media/cat.jpg")},
+            {"media_key": "3_2", "type": "photo", "url": format!("{base}/media/same-cat.jpg")},
+            {"media_key": "3_404", "type": "photo", "url": format!("{base}/media/gone.jpg")},
+            {"media_key": "7_1", "type": "video", "variants": [
+                {"bit_rate": 256000, "content_type": "video/mp4", "url": format!("{base}/video/low.mp4")},
+                {"bit_rate": 2176000, "content_type": "video/mp4", "url": format!("{base}/video/high.mp4")}
+            ]}
+        ]}));
+        let mut client = TwitClient::new("test-token", base, RetryPolicy::new(0, Duration::ZERO));
+        export_twitter_likes_for_username(&mut client, "mock_media", &export_started_on(1))
+            .await
+            .unwrap();
+
+        let options = DownloadOptions {
+            media_dir: scratch_dir.join("media-mock_media"),
+            concurrency: 2,
+            retry: RetryPolicy::new(0, Duration::ZERO),
+        };
+        let report = download_media_for_username("mock_media", &[ExportSource::Likes], &options)
+            .await
+            .unwrap();
+        assert_eq!(report.downloaded, 3);
+        assert_eq!(report.failed.len(), 1);
+        assert!(!server.requests().iter().any(|r| r == "/video/low.mp4"));
+
+        // Identical content is stored once.
+        let manifest = MediaManifest::load_or_default(&options.media_dir).unwrap();
+        let cat = &manifest.files[&format!("{base}/media/cat.jpg")];
+        assert_eq!(cat, &manifest.files[&format!("{base}/media/same-cat.jpg")]);
+        assert_eq!(cat.sha256, sha256_hex(b"cat picture"));
+        assert_eq!(fs::read(options.media_dir.join(&cat.path)).unwrap(), b"cat picture");
+
+        // A second run only fetches what is missing or no longer matches.
+        let video = &manifest.files[&format!("{base}/video/high.mp4")];
+        fs::write(options.media_dir.join(&video.path), b"truncated").unwrap();
+        let report = download_media_for_username("mock_media", &[ExportSource::Likes], &options)
+            .await
+            .unwrap();
+        assert_eq!((report.downloaded, report.skipped, report.failed.len()), (1, 2, 1));
+        assert!(manifest.verify(&options.media_dir, &format!("{base}/video/high.mp4")));
+
+        let mut compiled = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_media").unwrap();
+        assert_eq!(manifest.link(&options.media_dir, &mut compiled), 3);
+        let media = compiled.tweets[0].includes.as_ref().unwrap().media.as_ref().unwrap();
+        let local_cat = media.iter().find(|m| m.media_key == "3_1").unwrap();
+        assert_eq!(
+            local_cat.local_path.as_deref(),
+            Some(options.media_dir.join(&cat.path).to_str().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_media_url_prefers_best_variant() {
+        let variant = |bit_rate, url: &str| TwitMediaVariant {
+            bit_rate,
+            content_type: "video/mp4".to_string(),
+            url: url.to_string(),
+        };
+        let video = TwitMedia {
+            media_key: "7_1".to_string(),
+            media_type: "video".to_string(),
+            url: None,
+            preview_image_url: Some("https://pbs.twimg.com/preview.jpg".to_string()),
+            width: None,
+            height: None,
+            alt_text: None,
+            duration_ms: Some(1000),
+            variants: Some(vec![
+                variant(None, "https://video.twimg.com/pl.m3u8"),
+                variant(Some(2176000), "https://video.twimg.com/high.mp4"),
+                variant(Some(832000), "https://video.twimg.com/low.mp4"),
+            ]),
+            local_path: None,
+        };
+        assert_eq!(media_url(&video).as_deref(), Some("https://video.twimg.com/high.mp4"));
+    }
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(extension("https://pbs.twimg.com/media/abc.jpg"), "jpg");
+        assert_eq!(extension("https://video.twimg.com/v/abc.mp4?tag=12"), "mp4");
+        assert_eq!(extension("https://example.com/media/abc"), "bin");
+    }
+}
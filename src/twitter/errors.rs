@@ -0,0 +1,188 @@
+use crate::twitter::json_types::{TwitApiErrorEntry, TwitErrorResponse};
+use reqwest::StatusCode;
+use std::{error::Error, fmt, time::Duration};
+
+/// Errors returned when making requests to the Twitter API.
+#[derive(Debug)]
+pub enum TwitterApiError {
+    /// The request could not be sent or the response could not be read.
+    Network(reqwest::Error),
+    /// The API rejected our credentials (401 Unauthorized / 403 Forbidden).
+    Auth {
+        status: StatusCode,
+        errors: Vec<TwitApiErrorEntry>,
+    },
+    /// The requested user does not exist (or is not visible to us).
+    UserNotFound(String),
+    /// The API responded 404 Not Found: the endpoint, or the resource it
+    /// was asked for, does not exist. `errors` holds any errors it returned.
+    NotFound {
+        errors: Vec<TwitApiErrorEntry>,
+        body: String,
+    },
+    /// Rate limited (429). `TwitClient::send_request` waits for the window to
+    /// reset and tries again rather than returning this.
+    RateLimited { retry_after: Duration },
+    /// The API failed with a 5xx status.
+    Server { status: StatusCode, body: String },
+    /// Any other unexpected status.
+    Status { status: StatusCode, body: String },
+    /// The response body could not be decoded as the expected type. `errors`
+    /// holds any `errors` returned by the API in place of `data`, and `body`
+    /// is the raw response.
+    Decode {
+        message: String,
+        errors: Vec<TwitApiErrorEntry>,
+        body: String,
+    },
+}
+
+impl TwitterApiError {
+    /// Builds a decode error from the raw body, surfacing any `errors` the
+    /// API returned instead of the serde message when possible.
+    pub fn decode(err: serde_json::Error, body: String) -> TwitterApiError {
+        let errors = serde_json::from_str::<TwitErrorResponse>(&body)
+            .map(|resp| resp.into_entries())
+            .unwrap_or_default();
+        TwitterApiError::Decode {
+            message: err.to_string(),
+            errors,
+            body,
+        }
+    }
+
+    /// Builds an error for a non-OK HTTP status.
+    pub fn from_status(status: StatusCode, body: String) -> TwitterApiError {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => TwitterApiError::Auth {
+                status,
+                errors: serde_json::from_str::<TwitErrorResponse>(&body)
+                    .map(|resp| resp.into_entries())
+                    .unwrap_or_default(),
+            },
+            StatusCode::NOT_FOUND => TwitterApiError::NotFound {
+                errors: serde_json::from_str::<TwitErrorResponse>(&body)
+                    .map(|resp| resp.into_entries())
+                    .unwrap_or_default(),
+                body,
+            },
+            status if status.is_server_error() => TwitterApiError::Server { status, body },
+            status => TwitterApiError::Status { status, body },
+        }
+    }
+
+    /// `true` if the request may succeed if tried again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TwitterApiError::Network(err) => {
+                err.is_timeout() || err.is_connect() || err.is_request() || err.is_body()
+            }
+            TwitterApiError::RateLimited { .. } | TwitterApiError::Server { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// `true` if the API reported that a requested resource does not exist.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            TwitterApiError::UserNotFound(_) | TwitterApiError::NotFound { .. } => true,
+            TwitterApiError::Decode { errors, .. } => errors.iter().any(|e| {
+                e.error_type
+                    .as_deref()
+                    .is_some_and(|t| t.ends_with("resource-not-found"))
+            }),
+            _ => false,
+        }
+    }
+}
+
+impl Error for TwitterApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TwitterApiError::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TwitterApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |errors: &[TwitApiErrorEntry]| {
+            errors
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<String>>()
+                .join("; ")
+        };
+
+        match self {
+            TwitterApiError::Network(err) => write!(f, "Network error: {err}"),
+            TwitterApiError::Auth { status, errors } if errors.is_empty() => {
+                write!(f, "Authentication failed ({status}). Check BEARER_TOKEN (or run `auth login` for bookmarks).")
+            }
+            TwitterApiError::Auth { status, errors } => {
+                write!(f, "Authentication failed ({status}): {}", join(errors))
+            }
+            TwitterApiError::UserNotFound(user) => write!(f, "User not found: {user}"),
+            TwitterApiError::NotFound { errors, .. } if !errors.is_empty() => {
+                write!(f, "Not found (404): {}", join(errors))
+            }
+            TwitterApiError::NotFound { body, .. } => write!(f, "Not found (404): {body}"),
+            TwitterApiError::RateLimited { retry_after } => write!(
+                f,
+                "Rate limited by the API, retry after {}s",
+                retry_after.as_secs()
+            ),
+            TwitterApiError::Server { status, body } => write!(f, "Server error ({status}): {body}"),
+            TwitterApiError::Status { status, body } => {
+                write!(f, "Unexpected response ({status}): {body}")
+            }
+            TwitterApiError::Decode { errors, .. } if !errors.is_empty() => {
+                write!(f, "Twitter API returned errors: {}", join(errors))
+            }
+            TwitterApiError::Decode { message, body, .. } => {
+                write!(f, "Failed to decode response ({message}): {body}")
+            }
+        }
+    }
+}
+
+impl From<reqwest::Error> for TwitterApiError {
+    fn from(err: reqwest::Error) -> TwitterApiError {
+        TwitterApiError::Network(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_surfaces_api_errors() {
+        let body = r#"{"errors":[{"value":"nobody","detail":"Could not find user with usernames: [nobody].","title":"Not Found Error","resource_type":"user","parameter":"usernames","resource_id":"nobody","type":"https://api.twitter.com/2/problems/resource-not-found"}]}"#;
+        let serde_err = serde_json::from_str::<Vec<u8>>(body).unwrap_err();
+        let err = TwitterApiError::decode(serde_err, body.to_string());
+
+        assert!(err.is_not_found());
+        assert!(!err.is_transient());
+        assert_eq!(
+            err.to_string(),
+            "Twitter API returned errors: Not Found Error: Could not find user with usernames: [nobody]."
+        );
+    }
+
+    #[test]
+    fn test_from_status() {
+        let body = r#"{"title":"Unauthorized","type":"about:blank","status":401,"detail":"Unauthorized"}"#;
+        let err = TwitterApiError::from_status(StatusCode::UNAUTHORIZED, body.to_string());
+        assert!(matches!(err, TwitterApiError::Auth { ref errors, .. } if errors.len() == 1));
+
+        let err = TwitterApiError::from_status(StatusCode::SERVICE_UNAVAILABLE, "".to_string());
+        assert!(err.is_transient());
+
+        let body = r#"{"title":"Not Found Error","type":"about:blank","status":404,"detail":"Sorry, that page does not exist"}"#;
+        let err = TwitterApiError::from_status(StatusCode::NOT_FOUND, body.to_string());
+        assert!(err.is_not_found());
+        assert_eq!(err.to_string(), "Not found (404): Not Found Error: Sorry, that page does not exist");
+    }
+}
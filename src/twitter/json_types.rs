@@ -1,19 +1,87 @@
-use chrono::{DateTime, NaiveDate};
+use chrono::{DateTime, FixedOffset, NaiveDate};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::path::{Path, PathBuf};
 use chrono::Utc;
 
-use crate::twitter::serialization::{read, write};
+use crate::twitter::serialization::{read, read_versioned, write, write_versioned};
 use crate::serialization::{FsCacheable, FsLoadable};
-use crate::cache::{get_cache_file_path, get_cache_directory_path};
+use crate::cache::{cache_compression, compression, get_account_directory_path, get_account_file_path};
+use crate::cache::migrations::CacheKind;
+use crate::args::{ExportSource, SortOrder};
+
+/// A single entry in the `errors` array the Twitter v2 API returns alongside
+/// (or instead of) `data`, e.g. for a user that doesn't exist.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitApiErrorEntry {
+    pub title: Option<String>,
+    pub detail: Option<String>,
+    /// URI identifying the kind of problem, e.g.
+    /// `https://api.twitter.com/2/problems/resource-not-found`.
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    pub value: Option<String>,
+    pub parameter: Option<String>,
+    pub resource_type: Option<String>,
+    pub resource_id: Option<String>,
+}
+
+impl fmt::Display for TwitApiErrorEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let title = self.title.as_deref().unwrap_or("Error");
+        match &self.detail {
+            Some(detail) => write!(f, "{title}: {detail}"),
+            None => write!(f, "{title}"),
+        }
+    }
+}
+
+/// Body of an API response which could not be decoded as the expected type.
+/// Twitter returns either an `errors` array or a single problem object with
+/// `title` and `detail` (e.g. for authentication failures).
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct TwitErrorResponse {
+    pub errors: Option<Vec<TwitApiErrorEntry>>,
+    pub title: Option<String>,
+    pub detail: Option<String>,
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+}
+
+impl TwitErrorResponse {
+    /// Flattens the response into a list of error entries, treating a
+    /// top-level problem object as a single entry.
+    pub fn into_entries(self) -> Vec<TwitApiErrorEntry> {
+        match self.errors {
+            Some(errors) => errors,
+            None if self.title.is_some() || self.detail.is_some() => vec![TwitApiErrorEntry {
+                title: self.title,
+                detail: self.detail,
+                error_type: self.error_type,
+                value: None,
+                parameter: None,
+                resource_type: None,
+                resource_id: None,
+            }],
+            None => vec![],
+        }
+    }
+}
 
 /// Twitter Users v2 API returns an array of user data. TwitUserResponse
 /// represents the JSON response.
 #[derive(Deserialize, Serialize, Debug)]
 pub struct TwitUserResponse {
+    /// Users that were found. Absent when none of them were.
+    #[serde(default)]
     pub data: Vec<TwitUserDatum>,
+    /// One entry for each user that could not be returned, e.g. because the
+    /// account was deleted or suspended.
+    #[serde(default)]
+    pub errors: Vec<TwitApiErrorEntry>,
 }
 
 /// Twitter Users v2 API representation of a User.
@@ -40,8 +108,18 @@ pub struct TwitLikeResponse {
     pub user: Option<TwitUserDatum>,
     /// Tweet data returned from the API.
     pub data: Option<Vec<TwitLikeDatum>>,
+    /// Objects referenced by the tweets in `data`, returned because of the
+    /// `expansions` we request.
+    pub includes: Option<TwitIncludes>,
     /// Metadata for this list of tweets.
     pub meta: Option<TwitLikeMeta>,
+    /// When the page was fetched from the API. Not set in pages cached by
+    /// earlier versions.
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// When the export (or sync) which fetched the page started. The pages of
+    /// an export share it, across resumes. Not set in pages cached by earlier
+    /// versions.
+    pub export_started_at: Option<DateTime<Utc>>,
 }
 
 impl TwitLikeResponse {
@@ -89,41 +167,62 @@ impl TwitLikeResponse {
     }
 
     // Returns an optional PathBuf to the filesystem path where this response
-    // would be cached, or None if there's an error getting the current working
-    // directory (which should be unusual/unexpected).
-    pub fn fs_full_path(&self) -> Option<PathBuf> {
-        let directory = match get_cache_directory_path() {
-            Ok(d) => d,
-            Err(_) => return None,
-        };
+    // would be cached (in the liking user's account directory), or None if
+    // there's an error getting the cache directory (which should be
+    // unusual/unexpected). The extension follows `--cache-compression`.
+    pub fn fs_full_path(&self, source: ExportSource) -> Option<PathBuf> {
         let username = match &self.user {
             Some(user) => user.username.clone(),
             None => panic!("User should never be unset in fs_full_path!"),
         };
+        let directory = match get_account_directory_path(&username) {
+            Ok(d) => d,
+            Err(_) => return None,
+        };
+        let prefix = source.file_prefix();
+        let ext = cache_compression().extension();
         if let Some(id) = &self.id {
             if let Some(index) = self.index {
                 return Some(
-                    directory.join(format!("likes-{username}-{index}-{id}.json"))
+                    directory.join(format!("{prefix}-{username}-{index}-{id}.json{ext}"))
                 )
             };
         }
 
+        // Milliseconds, so that back-to-back syncs don't collide.
         let dt = Utc::now();
-        let timestamp: i64 = dt.timestamp();
-        Some(directory.join(format!("likes-{username}-0-{timestamp}.json")))
+        let timestamp: i64 = dt.timestamp_millis();
+        Some(directory.join(format!("{prefix}-{username}-0-{timestamp}.json{ext}")))
+    }
+
+    /// Like `fs_full_path`, with the start of the export which fetched the
+    /// page added to the name: `{prefix}-{username}-{index}-{token}-{started_at}.json`.
+    /// Pages after the first are named after their pagination token, which a
+    /// later sync or export fetches again when the same page of the list holds
+    /// different tweets; those are cached under this name instead.
+    pub fn fs_export_path(&self, source: ExportSource) -> Option<PathBuf> {
+        let path = self.fs_full_path(source)?;
+        let started_at = match self.export_started_at {
+            Some(started_at) => started_at.timestamp_millis(),
+            None => return Some(path),
+        };
+        let filename = path.file_name()?.to_str()?;
+        let ext = cache_compression().extension();
+        let stem = filename.strip_suffix(&format!(".json{ext}"))?;
+        Some(path.with_file_name(format!("{stem}-{started_at}.json{ext}")))
     }
 }
 
 impl FsCacheable<Self> for TwitLikeResponse {
     fn cache(&self, path: &Path) -> Result<&Self, Box<dyn Error>> {
-        write::<Self>(path, &self)?;
+        write_versioned::<Self>(path, CacheKind::Page, self)?;
         Ok(self)
     }
 }
 
 impl FsLoadable<TwitLikeResponse> for TwitLikeResponse {
     fn load(path: &Path) -> Result<TwitLikeResponse, Box<dyn Error>> {
-        let resp = read::<TwitLikeResponse>(path)?;
+        let resp = read_versioned::<TwitLikeResponse>(path, CacheKind::Page)?;
         Ok(resp)
     }
 }
@@ -137,28 +236,214 @@ pub struct TwitLikeMeta {
 }
 
 /// Represents a single "liked" Tweet returned from the API.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TwitLikeDatum {
     pub id: String,
     pub author_id: String,
     pub text: String,
+    pub lang: Option<String>,
     pub entities: Option<TwitLikeEntities>,
+    pub attachments: Option<TwitAttachments>,
+    pub referenced_tweets: Option<Vec<TwitReferencedTweet>>,
+    pub geo: Option<TwitGeo>,
     pub created_at: String, // date (ISO 8601)
+    /// The author, or None if they could not be resolved (see `author_error`).
     pub user: Option<TwitUserDatum>,
+    /// Why the author could not be resolved (e.g. a deleted or suspended
+    /// account), as reported by the API. Only set in compiled output.
+    pub author_error: Option<TwitApiErrorEntry>,
+    /// The media, polls, quoted/retweeted tweets, users and places this tweet
+    /// refers to, picked out of the page's `includes`. Only set in compiled
+    /// output.
+    pub includes: Option<TwitIncludes>,
+    /// Which export this tweet was compiled from (likes, bookmarks, tweets).
+    /// Only set in compiled output.
+    pub source: Option<ExportSource>,
+    /// When this copy of the tweet was fetched from the API, taken from its
+    /// page. Set in compiled output and in compacted pages.
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// When the tweet was first seen in an export: the start of the earliest
+    /// export which fetched it. An estimate of when it was liked (bookmarked,
+    /// tweeted). Set in compiled output and in compacted pages.
+    pub first_seen: Option<DateTime<Utc>>,
+    /// Position of the tweet in the order the API returns it in (most
+    /// recently liked, bookmarked or tweeted first), starting at 0, among
+    /// the tweets of its source. Only set in compiled output.
+    pub like_position: Option<u64>,
 }
 
+/// Placeholder shown in place of an author that could not be resolved.
+pub const UNKNOWN_AUTHOR: &str = "Unknown author";
+
 impl TwitLikeDatum {
-    pub fn created_at_datetime(&self) -> NaiveDate {
-        let created_at = &self.created_at; // TODO: unwrap
-        DateTime::parse_from_rfc3339(&created_at)
-            .unwrap()
-            .date_naive()
+    /// Name of the author, or `UNKNOWN_AUTHOR` (with the reason, if known)
+    /// for deleted and suspended accounts.
+    pub fn author_label(&self) -> String {
+        match (&self.user, &self.author_error) {
+            (Some(user), _) => user.name.clone(),
+            (None, Some(error)) => format!("{UNKNOWN_AUTHOR} ({error})"),
+            (None, None) => UNKNOWN_AUTHOR.to_string(),
+        }
+    }
+
+    /// Link to the tweet on twitter.com.
+    pub fn permalink(&self) -> String {
+        match &self.user {
+            Some(user) => format!("https://twitter.com/{}/status/{}", user.username, self.id),
+            None => format!("https://twitter.com/i/web/status/{}", self.id),
+        }
+    }
+
+    /// The text of the tweet as it was written. The API sends `&`, `<` and
+    /// `>` as `&amp;`, `&lt;` and `&gt;`; outputs which escape the text
+    /// themselves (or don't need it escaped) start from this instead.
+    pub fn plain_text(&self) -> String {
+        self.text.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+    }
+
+    /// When the tweet was written, or None if `created_at` is invalid.
+    pub fn created_at_timestamp(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.created_at).ok()
     }
 }
 
+/// Keys of the media and polls attached to a tweet. The objects themselves are
+/// returned in `includes`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitAttachments {
+    pub media_keys: Option<Vec<String>>,
+    pub poll_ids: Option<Vec<String>>,
+}
+
+/// A tweet this tweet quotes, retweets or replies to.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitReferencedTweet {
+    /// One of `quoted`, `retweeted` or `replied_to`.
+    #[serde(rename = "type")]
+    pub reference_type: String,
+    pub id: String,
+}
+
+/// Location tagged on a tweet. The place itself is returned in `includes`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitGeo {
+    pub place_id: Option<String>,
+}
+
+/// Objects expanded by the API and returned once per page, rather than
+/// inline in every tweet that refers to them.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct TwitIncludes {
+    pub media: Option<Vec<TwitMedia>>,
+    /// Quoted, retweeted and replied-to tweets.
+    pub tweets: Option<Vec<TwitLikeDatum>>,
+    pub users: Option<Vec<TwitUserDatum>>,
+    pub polls: Option<Vec<TwitPoll>>,
+    pub places: Option<Vec<TwitPlace>>,
+}
+
+impl TwitIncludes {
+    /// Picks out the objects `tweet` refers to, or None if it refers to none.
+    pub fn for_tweet(&self, tweet: &TwitLikeDatum) -> Option<TwitIncludes> {
+        let attachments = tweet.attachments.as_ref();
+        let media_keys = attachments.and_then(|a| a.media_keys.as_deref()).unwrap_or_default();
+        let poll_ids = attachments.and_then(|a| a.poll_ids.as_deref()).unwrap_or_default();
+        let tweet_ids: Vec<&str> = tweet
+            .referenced_tweets
+            .iter()
+            .flatten()
+            .map(|r| r.id.as_str())
+            .collect();
+        let place_id = tweet.geo.as_ref().and_then(|g| g.place_id.as_deref());
+
+        let media = pick(&self.media, |m| media_keys.contains(&m.media_key));
+        let tweets = pick(&self.tweets, |t| tweet_ids.contains(&t.id.as_str()));
+        // The authors of the referenced tweets, so that they can be displayed.
+        let users = pick(&self.users, |u| {
+            tweets.iter().flatten().any(|t| t.author_id == u.id)
+        });
+        let polls = pick(&self.polls, |p| poll_ids.contains(&p.id));
+        let places = pick(&self.places, |p| place_id == Some(p.id.as_str()));
+
+        if media.is_none() && tweets.is_none() && users.is_none() && polls.is_none() && places.is_none() {
+            return None;
+        }
+        Some(TwitIncludes { media, tweets, users, polls, places })
+    }
+}
+
+/// Clones the items matching `predicate`, or None if there are none.
+fn pick<T: Clone>(items: &Option<Vec<T>>, predicate: impl Fn(&T) -> bool) -> Option<Vec<T>> {
+    let picked: Vec<T> = items.iter().flatten().filter(|i| predicate(i)).cloned().collect();
+    if picked.is_empty() {
+        None
+    } else {
+        Some(picked)
+    }
+}
+
+/// A photo, video or animated GIF attached to a tweet.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitMedia {
+    pub media_key: String,
+    /// One of `photo`, `video` or `animated_gif`.
+    #[serde(rename = "type")]
+    pub media_type: String,
+    /// Url of the image (photos only).
+    pub url: Option<String>,
+    /// Url of a still image (videos and GIFs).
+    pub preview_image_url: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub alt_text: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// Encodings of a video or GIF.
+    pub variants: Option<Vec<TwitMediaVariant>>,
+    /// Path of the downloaded copy (see the `media` command). Only set in
+    /// compiled output.
+    pub local_path: Option<String>,
+}
+
+/// A single encoding of a video or animated GIF.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitMediaVariant {
+    pub bit_rate: Option<u64>,
+    pub content_type: String,
+    pub url: String,
+}
+
+/// A poll attached to a tweet.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitPoll {
+    pub id: String,
+    pub options: Vec<TwitPollOption>,
+    pub duration_minutes: Option<u32>,
+    pub end_datetime: Option<String>, // date (ISO 8601)
+    pub voting_status: Option<String>,
+}
+
+/// A single choice in a poll.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitPollOption {
+    pub position: u32,
+    pub label: String,
+    pub votes: u64,
+}
+
+/// A place tagged on a tweet.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TwitPlace {
+    pub id: String,
+    pub full_name: String,
+    pub name: Option<String>,
+    pub country: Option<String>,
+    pub country_code: Option<String>,
+    pub place_type: Option<String>,
+}
+
 /// The Twitter API Returns a list of "entities" as part of the tweet, which
 /// include a list of url objects.
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TwitLikeEntities {
     /// List of structs containing short, expanded and display urls.
     pub urls: Option<Vec<TwitLikeUrl>>,
@@ -167,7 +452,7 @@ pub struct TwitLikeEntities {
 /// Collection of related urls in shortened and original form, with and without
 /// scheme.
 #[allow(rustdoc::bare_urls)]
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct TwitLikeUrl {
     /// {
     ///     "url": "https://t.co/6Txgbp3VS4",
@@ -191,12 +476,17 @@ type UsersByIdHashMap = HashMap<String, Option<TwitUserDatum>>;
 pub struct UserIdLookup {
     /// Allows easy lookup of users by twitter user id.
     pub users_by_id: UsersByIdHashMap,
+    /// Errors the API returned for users it could not resolve (deleted or
+    /// suspended accounts). These users are not looked up again.
+    #[serde(default)]
+    pub errors_by_id: HashMap<String, TwitApiErrorEntry>,
 }
 
 impl Default for UserIdLookup {
     fn default() -> UserIdLookup {
         UserIdLookup {
             users_by_id: HashMap::new(),
+            errors_by_id: HashMap::new(),
         }
     }
 }
@@ -217,33 +507,124 @@ impl UserIdLookup {
 
     /// Adds a user to the id => user mapping.
     pub fn insert(&mut self, key: String, value: Option<TwitUserDatum>) -> &Self {
+        if value.is_some() {
+            self.errors_by_id.remove(&key);
+        }
         self.users_by_id.insert(key, value);
         self
     }
 
-    /// Returns the full file path to where the user_id_lookup is cached.
-    pub fn fs_full_path() -> std::io::Result<PathBuf> {
-        Ok(get_cache_file_path("user_id_lookup.json")?)
+    /// Records why the API could not resolve the user `key`.
+    pub fn insert_error(&mut self, key: String, error: TwitApiErrorEntry) -> &Self {
+        self.users_by_id.entry(key.clone()).or_insert(None);
+        self.errors_by_id.insert(key, error);
+        self
+    }
+
+    /// `true` if `key` is neither resolved nor known to be unresolvable, and
+    /// so should be looked up.
+    pub fn is_missing(&self, key: &str) -> bool {
+        matches!(self.users_by_id.get(key), Some(None)) && !self.errors_by_id.contains_key(key)
     }
 
-    /// Loads a UserIdLookup from cache.
-    pub fn load_default() -> Result<UserIdLookup, Box<dyn Error>> {
-        Ok(
-            Self::load(&Self::fs_full_path()?)?
-        )
+    /// Returns the full file path to where the user_id_lookup of `username`
+    /// is cached (compressed according to `--cache-compression`).
+    pub fn fs_full_path(username: &str) -> std::io::Result<PathBuf> {
+        let filename = format!("user_id_lookup.json{}", cache_compression().extension());
+        get_account_file_path(username, &filename)
+    }
+
+    /// The file the user_id_lookup of `username` is cached in, whatever its
+    /// compression, or None if it isn't cached.
+    pub fn find_cached(username: &str) -> std::io::Result<Option<PathBuf>> {
+        Ok(compression::find_variant(&Self::fs_full_path(username)?))
+    }
+
+    /// Loads the UserIdLookup of `username` from cache.
+    pub fn load_default(username: &str) -> Result<UserIdLookup, Box<dyn Error>> {
+        match Self::find_cached(username)? {
+            Some(path) => Self::load(&path),
+            None => Self::load(&Self::fs_full_path(username)?),
+        }
     }
 }
 
 impl FsCacheable<UserIdLookup> for UserIdLookup {
     fn cache(&self, path: &Path) -> Result<&Self, Box<dyn Error>> {
-        write::<Self>(path, &self)?;
+        write_versioned::<Self>(path, CacheKind::UserLookup, self)?;
         Ok(self)
     }
 }
 
 impl FsLoadable<UserIdLookup> for UserIdLookup {
     fn load(path: &Path) -> Result<UserIdLookup, Box<dyn Error>> {
-        Ok(read::<UserIdLookup>(path)?)
+        Ok(read_versioned::<UserIdLookup>(path, CacheKind::UserLookup)?)
+    }
+}
+
+/// Progress of an export for a single user, written to the cache after each
+/// page so that an interrupted export can be continued with `export --resume`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ExportCheckpoint {
+    /// Twitter username being exported.
+    pub username: String,
+    /// Which tweets are being exported.
+    #[serde(default)]
+    pub source: ExportSource,
+    /// Pagination token for the next page to fetch.
+    pub next_token: Option<String>,
+    /// Index of the next page to fetch.
+    pub page_index: u64,
+    /// When the export was first started.
+    pub started_at: DateTime<Utc>,
+    /// When the checkpoint was last written.
+    pub updated_at: DateTime<Utc>,
+    /// The export stops at the first page with tweets older than this date.
+    pub not_before_date: Option<NaiveDate>,
+    /// Replies are left out of the user's own tweets.
+    #[serde(default)]
+    pub exclude_replies: bool,
+    /// Retweets are left out of the user's own tweets.
+    #[serde(default)]
+    pub exclude_retweets: bool,
+}
+
+impl ExportCheckpoint {
+    /// Returns the full file path to where the checkpoint for `username` is
+    /// cached.
+    pub fn fs_full_path(username: &str) -> std::io::Result<PathBuf> {
+        get_account_file_path(username, &format!("checkpoint-{username}.json"))
+    }
+
+    /// Loads the checkpoint for `username` from cache, if there is one.
+    pub fn load_for_username(username: &str) -> Result<Option<ExportCheckpoint>, Box<dyn Error>> {
+        let path = Self::fs_full_path(username)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::load(&path)?))
+    }
+
+    /// Removes the checkpoint for `username` (once the export has finished).
+    pub fn clear(username: &str) -> std::io::Result<()> {
+        let path = Self::fs_full_path(username)?;
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl FsCacheable<ExportCheckpoint> for ExportCheckpoint {
+    fn cache(&self, path: &Path) -> Result<&Self, Box<dyn Error>> {
+        write::<Self>(path, self)?;
+        Ok(self)
+    }
+}
+
+impl FsLoadable<ExportCheckpoint> for ExportCheckpoint {
+    fn load(path: &Path) -> Result<ExportCheckpoint, Box<dyn Error>> {
+        read::<ExportCheckpoint>(path)
     }
 }
 
@@ -255,13 +636,18 @@ pub struct LikedTweets {
     pub user: Option<TwitUserDatum>,
     /// List of the tweets that were liked.
     pub tweets: Vec<TwitLikeDatum>,
+    /// How many copies of tweets cached more than once were left out (see
+    /// `dedupe_tweets`).
+    #[serde(skip)]
+    pub duplicates: usize,
 }
 
 impl Default for LikedTweets {
     fn default() -> LikedTweets {
         LikedTweets {
             user: None,
-            tweets: Vec::new()
+            tweets: Vec::new(),
+            duplicates: 0,
         }
     }
 }
@@ -271,10 +657,22 @@ impl LikedTweets {
         LikedTweets { ..Default::default() }
     }
 
-    pub fn sort_by_date(&mut self) -> () {
-        self.tweets.sort_by(|tw1, tw2|
-            tw2.created_at_datetime().partial_cmp(&tw1.created_at_datetime()).unwrap()
-        );
+    /// Sorts the tweets in `order`. The sort is stable.
+    pub fn sort(&mut self, order: SortOrder) {
+        match order {
+            // Tweets first seen together keep the order of the API.
+            SortOrder::Like => self.tweets.sort_by(|tw1, tw2| {
+                tw2.first_seen
+                    .cmp(&tw1.first_seen)
+                    .then(tw1.like_position.cmp(&tw2.like_position))
+            }),
+            SortOrder::TweetTime => self.tweets.sort_by_key(|tweet| Reverse(tweet.created_at_timestamp())),
+            // Unknown authors last.
+            SortOrder::Author => self.tweets.sort_by_cached_key(|tweet| {
+                let username = tweet.user.as_ref().map(|user| user.username.to_lowercase());
+                (username.is_none(), username)
+            }),
+        }
     }
 }
 
@@ -290,3 +688,39 @@ impl FsLoadable<LikedTweets> for LikedTweets {
         read::<LikedTweets>(path)
     }
 }
+
+/// Keeps a single copy of each tweet in `tweets`, the most recently fetched
+/// one (copies without a fetch time count as oldest), in the position of its
+/// first copy, and with the earliest `first_seen` of all the copies.
+pub fn dedupe_tweets(tweets: Vec<TwitLikeDatum>) -> Vec<TwitLikeDatum> {
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut deduped: Vec<TwitLikeDatum> = Vec::with_capacity(tweets.len());
+    for mut tweet in tweets {
+        match positions.get(&tweet.id) {
+            Some(&position) => {
+                let kept = &mut deduped[position];
+                let first_seen = earliest_first_seen(kept.first_seen, tweet.first_seen);
+                if tweet.fetched_at >= kept.fetched_at {
+                    tweet.first_seen = first_seen;
+                    *kept = tweet;
+                } else {
+                    kept.first_seen = first_seen;
+                }
+            }
+            None => {
+                positions.insert(tweet.id.clone(), deduped.len());
+                deduped.push(tweet);
+            }
+        }
+    }
+    deduped
+}
+
+/// The `first_seen` of a tweet cached twice: seen first by whichever copy was
+/// seen first.
+pub fn earliest_first_seen(kept: Option<DateTime<Utc>>, copy: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+    match (kept, copy) {
+        (Some(kept), Some(copy)) => Some(kept.min(copy)),
+        (kept, copy) => kept.or(copy),
+    }
+}
@@ -0,0 +1,480 @@
+//! In-process stand-in for the Twitter API v2, used by tests to exercise the
+//! client and the export loop without network access. Serves `/2/users/by`,
+//! `/2/users`, `/2/users/:id/liked_tweets`, `/2/users/:id/bookmarks` and
+//! `/2/users/:id/tweets` with
+//! pagination, rate-limit headers and scripted error responses, plus an
+//! OAuth 2.0 token endpoint at `/2/oauth2/token` and static media files.
+use crate::twitter::json_types::LikedTweets;
+use crate::twitter::twitter::ExportOptions;
+use chrono::{TimeZone, Utc};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// A response to return instead of the normal one, e.g. a 503 or a 429.
+#[derive(Debug, Clone)]
+pub struct ScriptedResponse {
+    pub status: StatusCode,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Data served by the mock API.
+#[derive(Debug, Default)]
+pub struct MockTwitterState {
+    /// Users by id.
+    pub users: HashMap<String, Value>,
+    /// Liked tweets by the id of the user who liked them, newest first.
+    pub likes: HashMap<String, Vec<Value>>,
+    /// Bookmarked tweets by the id of the user who bookmarked them.
+    pub bookmarks: HashMap<String, Vec<Value>>,
+    /// Tweets by the id of the user who wrote them.
+    pub tweets: HashMap<String, Vec<Value>>,
+    /// Static files (e.g. media) by path, served without authentication.
+    pub files: HashMap<String, Vec<u8>>,
+    /// `includes` object returned with every non-empty page of tweets.
+    pub includes: Option<Value>,
+    /// Maximum number of tweets per page.
+    pub page_size: usize,
+    /// Responses returned (in order) before any normal response.
+    pub scripted: VecDeque<ScriptedResponse>,
+    /// Responses returned once, in place of the normal response to the first
+    /// request whose path and query contain the given string.
+    pub scripted_for: Vec<(String, ScriptedResponse)>,
+    /// Paths (with query) of every request received.
+    pub requests: Vec<String>,
+    /// Form bodies posted to the token endpoint.
+    pub token_requests: Vec<String>,
+}
+
+/// Builder for a mock Twitter API server.
+pub struct MockTwitter {
+    state: MockTwitterState,
+}
+
+impl MockTwitter {
+    pub fn new() -> MockTwitter {
+        MockTwitter {
+            state: MockTwitterState {
+                page_size: 100,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Adds a user to the API.
+    pub fn user(mut self, id: &str, username: &str, name: &str) -> MockTwitter {
+        self.state.users.insert(id.to_string(), mock_user(id, username, name));
+        self
+    }
+
+    /// Sets the tweets liked by `user_id` (newest first).
+    pub fn likes(mut self, user_id: &str, tweets: Vec<Value>) -> MockTwitter {
+        self.state.likes.insert(user_id.to_string(), tweets);
+        self
+    }
+
+    /// Sets the tweets bookmarked by `user_id` (newest first).
+    pub fn bookmarks(mut self, user_id: &str, tweets: Vec<Value>) -> MockTwitter {
+        self.state.bookmarks.insert(user_id.to_string(), tweets);
+        self
+    }
+
+    /// Sets the tweets written by `user_id` (newest first).
+    pub fn tweets(mut self, user_id: &str, tweets: Vec<Value>) -> MockTwitter {
+        self.state.tweets.insert(user_id.to_string(), tweets);
+        self
+    }
+
+    /// Sets the `includes` (media, tweets, users, polls, places) returned with
+    /// every page of tweets.
+    pub fn includes(mut self, includes: Value) -> MockTwitter {
+        self.state.includes = Some(includes);
+        self
+    }
+
+    /// Serves `body` at `path`, like the media CDN.
+    pub fn file(mut self, path: &str, body: &[u8]) -> MockTwitter {
+        self.state.files.insert(path.to_string(), body.to_vec());
+        self
+    }
+
+    /// Sets the number of tweets returned per page of likes.
+    pub fn page_size(mut self, page_size: usize) -> MockTwitter {
+        self.state.page_size = page_size;
+        self
+    }
+
+    /// Queues a response to be returned in place of the next normal one.
+    pub fn respond_with(mut self, status: StatusCode, body: &str, headers: &[(&str, &str)]) -> MockTwitter {
+        self.state.scripted.push_back(ScriptedResponse {
+            status,
+            body: body.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        });
+        self
+    }
+
+    /// Responds once with `status` to the first request containing `pattern`
+    /// in its path or query.
+    pub fn respond_to(mut self, pattern: &str, status: StatusCode, body: &str) -> MockTwitter {
+        self.state.scripted_for.push((
+            pattern.to_string(),
+            ScriptedResponse {
+                status,
+                body: body.to_string(),
+                headers: vec![],
+            },
+        ));
+        self
+    }
+
+    /// Starts the server on a random local port.
+    pub async fn start(self) -> MockTwitterServer {
+        let state = Arc::new(Mutex::new(self.state));
+        let service_state = state.clone();
+
+        let make_svc = make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let state = state.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                        let req = Request::from_parts(parts, String::from_utf8_lossy(&body).to_string());
+                        Ok::<_, Infallible>(handle(&state, req))
+                    }
+                }))
+            }
+        });
+
+        let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+        let server = Server::bind(&addr).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        MockTwitterServer {
+            base_url: format!("http://{addr}"),
+            state,
+        }
+    }
+}
+
+/// Handle to a running mock server.
+pub struct MockTwitterServer {
+    pub base_url: String,
+    pub state: Arc<Mutex<MockTwitterState>>,
+}
+
+impl MockTwitterServer {
+    /// Paths (with query) of every request received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+/// Builds a user object as returned by the users endpoints.
+pub fn mock_user(id: &str, username: &str, name: &str) -> Value {
+    json!({
+        "id": id,
+        "username": username,
+        "name": name,
+        "url": format!("https://example.com/{username}"),
+    })
+}
+
+/// Builds a tweet object as returned by the liked tweets endpoint.
+pub fn mock_tweet(id: u64, author_id: &str, created_at: &str) -> Value {
+    json!({
+        "id": id.to_string(),
+        "author_id": author_id,
+        "text": format!("Tweet number {id} https://t.co/{id}"),
+        "created_at": created_at,
+        "lang": "en",
+        "entities": {
+            "urls": [{
+                "url": format!("https://t.co/{id}"),
+                "expanded_url": format!("https://example.com/articles/{id}"),
+                "display_url": format!("example.com/articles/{id}"),
+            }]
+        }
+    })
+}
+
+/// `tweet` (from `mock_tweet`) with its author attached, as compiling does.
+pub fn with_author(mut tweet: Value, username: &str, name: &str) -> Value {
+    let author_id = tweet["author_id"].as_str().unwrap().to_string();
+    tweet["user"] = mock_user(&author_id, username, name);
+    tweet
+}
+
+/// Compiled tweets made of `tweets` (from `mock_tweet`), for the tests of the
+/// output formats.
+pub fn mock_liked_tweets(tweets: Vec<Value>) -> LikedTweets {
+    LikedTweets {
+        tweets: tweets.into_iter().map(|tweet| serde_json::from_value(tweet).unwrap()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Options of an export started on the `day` of December 2022. Exports
+/// started on the same day count as one; later days sort first in like order.
+pub fn export_started_on(day: u32) -> ExportOptions {
+    ExportOptions {
+        started_at: Some(Utc.with_ymd_and_hms(2022, 12, day, 0, 0, 0).unwrap()),
+        ..Default::default()
+    }
+}
+
+fn handle(state: &Arc<Mutex<MockTwitterState>>, req: Request<String>) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let path = req.uri().path().to_string();
+    let query = parse_query(req.uri().query().unwrap_or(""));
+    state.requests.push(req.uri().to_string());
+
+    let uri = req.uri().to_string();
+    let scripted = match state.scripted_for.iter().position(|(p, _)| uri.contains(p.as_str())) {
+        Some(i) => Some(state.scripted_for.remove(i).1),
+        None => state.scripted.pop_front(),
+    };
+    if let Some(scripted) = scripted {
+        let mut builder = Response::builder().status(scripted.status);
+        for (name, value) in &scripted.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        return builder.body(Body::from(scripted.body)).unwrap();
+    }
+
+    if path == "/2/oauth2/token" {
+        return token(&mut state, req.body());
+    }
+
+    if let Some(file) = state.files.get(&path) {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(file.clone()))
+            .unwrap();
+    }
+
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .to_string();
+    if bearer.is_empty() {
+        return json_response(
+            StatusCode::UNAUTHORIZED,
+            json!({"title": "Unauthorized", "type": "about:blank", "status": 401, "detail": "Unauthorized"}),
+        );
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["2", "users", "by"] => {
+            let usernames = query.get("usernames").cloned().unwrap_or_default();
+            let usernames: Vec<&str> = usernames.split(',').collect();
+            lookup_users(&state, "usernames", &usernames, |user, key| {
+                user["username"].as_str() == Some(key)
+            })
+        }
+        ["2", "users"] => {
+            let ids = query.get("ids").cloned().unwrap_or_default();
+            let ids: Vec<&str> = ids.split(',').collect();
+            lookup_users(&state, "ids", &ids, |user, key| user["id"].as_str() == Some(key))
+        }
+        ["2", "users", user_id, "liked_tweets"] => paginate(&state, state.likes.get(*user_id), &query),
+        ["2", "users", user_id, "bookmarks"] => {
+            if !bearer.starts_with("user-access-") {
+                return json_response(
+                    StatusCode::FORBIDDEN,
+                    json!({"title": "Unsupported Authentication", "detail": "Authenticating with OAuth 2.0 Application-Only is forbidden for this endpoint.", "type": "https://api.twitter.com/2/problems/unsupported-authentication", "status": 403}),
+                );
+            }
+            paginate(&state, state.bookmarks.get(*user_id), &query)
+        }
+        ["2", "users", user_id, "tweets"] => paginate(&state, state.tweets.get(*user_id), &query),
+        _ => json_response(
+            StatusCode::NOT_FOUND,
+            json!({"title": "Not Found", "detail": format!("No route for {path}")}),
+        ),
+    }
+}
+
+/// Responds like the users lookup endpoints: found users in `data`, and a
+/// `resource-not-found` entry in `errors` for each missing one.
+fn lookup_users<F>(state: &MockTwitterState, parameter: &str, keys: &[&str], matches: F) -> Response<Body>
+where
+    F: Fn(&Value, &str) -> bool,
+{
+    let mut data = vec![];
+    let mut errors = vec![];
+    for key in keys {
+        match state.users.values().find(|user| matches(user, key)) {
+            Some(user) => data.push(user.clone()),
+            None => errors.push(json!({
+                "value": key,
+                "detail": format!("Could not find user with {parameter}: [{key}]."),
+                "title": "Not Found Error",
+                "resource_type": "user",
+                "parameter": parameter,
+                "resource_id": key,
+                "type": "https://api.twitter.com/2/problems/resource-not-found",
+            })),
+        }
+    }
+
+    let mut body = json!({});
+    if !data.is_empty() {
+        body["data"] = Value::Array(data);
+    }
+    if !errors.is_empty() {
+        body["errors"] = Value::Array(errors);
+    }
+    json_response(StatusCode::OK, body)
+}
+
+/// Responds like the token endpoint, handing out numbered access tokens for
+/// both authorization codes and refresh tokens.
+fn token(state: &mut MockTwitterState, form: &str) -> Response<Body> {
+    state.token_requests.push(form.to_string());
+    let params = parse_query(form);
+    let grant_ok = match params.get("grant_type").map(|g| g.as_str()) {
+        Some("authorization_code") => params.contains_key("code") && params.contains_key("code_verifier"),
+        Some("refresh_token") => params.contains_key("refresh_token"),
+        _ => false,
+    };
+    if !grant_ok {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            json!({"error": "invalid_request", "error_description": "Value passed for the token was invalid."}),
+        );
+    }
+
+    let n = state.token_requests.len();
+    json_response(
+        StatusCode::OK,
+        json!({
+            "token_type": "bearer",
+            "expires_in": 7200,
+            "access_token": format!("user-access-{n}"),
+            "scope": "tweet.read users.read bookmark.read offline.access",
+            "refresh_token": format!("user-refresh-{n}"),
+        }),
+    )
+}
+
+/// Responds like the liked tweets, bookmarks and timeline endpoints,
+/// paginating with tokens that encode the offset of the next page.
+fn paginate(state: &MockTwitterState, tweets: Option<&Vec<Value>>, query: &HashMap<String, String>) -> Response<Body> {
+    let likes = match tweets {
+        Some(likes) => likes,
+        None => return json_response(StatusCode::OK, json!({"meta": {"result_count": 0}})),
+    };
+
+    let max_results = query
+        .get("max_results")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(100);
+    let page_size = max_results.min(state.page_size);
+    let offset = query
+        .get("pagination_token")
+        .and_then(|t| t.strip_prefix("tok"))
+        .and_then(|t| t.parse::<usize>().ok())
+        .unwrap_or(0);
+    let end = (offset + page_size).min(likes.len());
+    let page = &likes[offset.min(end)..end];
+
+    let mut meta = json!({"result_count": page.len()});
+    if end < likes.len() {
+        meta["next_token"] = json!(format!("tok{end}"));
+    }
+    if offset > 0 {
+        meta["previous_token"] = json!(format!("tok{}", offset.saturating_sub(page_size)));
+    }
+
+    let mut body = json!({"meta": meta});
+    if !page.is_empty() {
+        body["data"] = Value::Array(page.to_vec());
+        if let Some(includes) = &state.includes {
+            body["includes"] = includes.clone();
+        }
+    }
+    json_response(StatusCode::OK, body)
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("x-rate-limit-limit", "75")
+        .header("x-rate-limit-remaining", "74")
+        .header("x-rate-limit-reset", "0")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// Tests that export write to the shared scratch cache directory, so they run
+/// one at a time.
+pub static EXPORT_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Prefix of the scratch directories, followed by the id of the process.
+const SCRATCH_DIR_PREFIX: &str = "twitter-likes-exporter-tests-";
+
+/// Scratch directories untouched for this long were left by earlier runs.
+const STALE_SCRATCH_DIR_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Points the cache directory of the test process at a scratch directory, so
+/// that tests never touch a real cache, and returns it. Safe to call from every
+/// test; only the first call creates the directory.
+///
+/// Statics are never dropped, so the directory outlives the process (a
+/// `TempDir` kept here would too); instead, the first call removes those left
+/// by earlier runs.
+pub fn use_scratch_cache_dir() -> &'static std::path::Path {
+    static SCRATCH_DIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+    SCRATCH_DIR.get_or_init(|| {
+        remove_stale_scratch_dirs();
+        let dir = std::env::temp_dir().join(format!("{SCRATCH_DIR_PREFIX}{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        crate::cache::set_cache_directory(dir.clone());
+        dir
+    })
+}
+
+/// Removes the scratch directories of earlier test runs. Those of test
+/// processes still running are recent, and left alone.
+fn remove_stale_scratch_dirs() {
+    let entries = match std::fs::read_dir(std::env::temp_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let is_scratch_dir = entry.file_name().to_string_lossy().starts_with(SCRATCH_DIR_PREFIX);
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        if is_scratch_dir && age.is_some_and(|age| age > STALE_SCRATCH_DIR_AGE) {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
@@ -1,3 +1,9 @@
 pub mod twitter;
 pub mod json_types;
-mod serialization;
\ No newline at end of file
+pub mod errors;
+pub mod oauth;
+pub mod rate_limit;
+pub mod retry;
+pub mod serialization;
+#[cfg(test)]
+pub mod mock_server;
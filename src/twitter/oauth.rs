@@ -0,0 +1,508 @@
+use crate::cache::{self, get_cache_file_path};
+use crate::serialization::{FsCacheable, FsLoadable};
+use crate::twitter::errors::TwitterApiError;
+use crate::twitter::serialization::{read, write_private};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::{error::Error, fmt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Page where the user authorizes the app.
+pub const DEFAULT_AUTHORIZE_URL: &str = "https://twitter.com/i/oauth2/authorize";
+
+/// Scopes needed to read bookmarks. `offline.access` gets us a refresh token.
+pub const BOOKMARK_SCOPES: &str = "tweet.read users.read bookmark.read offline.access";
+
+/// Tokens are refreshed when they expire within this many seconds.
+const EXPIRY_MARGIN_SECS: i64 = 5 * 60;
+
+/// Errors in the OAuth 2.0 login flow.
+#[derive(Debug)]
+pub enum OAuthError {
+    /// The app's client id is not configured.
+    MissingClientId,
+    /// There is no stored user token; `auth login` must be run first.
+    NotLoggedIn,
+    /// The token expired and can't be refreshed (no refresh token).
+    Expired,
+    /// The user (or the API) declined the authorization request.
+    Denied(String),
+    /// The callback didn't carry the `state` we sent, so it can't be trusted.
+    StateMismatch,
+    /// The callback request was not understood.
+    BadCallback(String),
+    /// The configured authorization page is not a valid URL.
+    BadAuthorizeUrl(String),
+}
+
+impl Error for OAuthError {}
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OAuthError::MissingClientId => {
+                write!(f, "TWITTER_CLIENT_ID environment variable is missing.")
+            }
+            OAuthError::NotLoggedIn => write!(f, "No user token found. Run `auth login` first."),
+            OAuthError::Expired => write!(f, "The user token has expired. Run `auth login` again."),
+            OAuthError::Denied(reason) => write!(f, "Authorization was denied: {reason}"),
+            OAuthError::StateMismatch => write!(f, "Authorization callback had an unexpected state."),
+            OAuthError::BadCallback(request) => write!(f, "Unexpected callback request: {request}"),
+            OAuthError::BadAuthorizeUrl(url) => write!(f, "Invalid authorize URL: {url}"),
+        }
+    }
+}
+
+/// Settings for the OAuth 2.0 Authorization Code flow with PKCE.
+#[derive(Debug, Clone)]
+pub struct OAuthConfig {
+    /// OAuth 2.0 client id of the app.
+    pub client_id: String,
+    /// Client secret, for apps registered as confidential clients.
+    pub client_secret: Option<String>,
+    /// Page where the user authorizes the app.
+    pub authorize_url: String,
+    /// Endpoint where codes and refresh tokens are exchanged for tokens.
+    pub token_url: String,
+    /// Where the authorization page redirects to (our local listener).
+    pub redirect_uri: String,
+    /// Space separated list of scopes to request.
+    pub scopes: String,
+}
+
+impl OAuthConfig {
+    /// Reads the client id (`TWITTER_CLIENT_ID`) and optional secret
+    /// (`TWITTER_CLIENT_SECRET`) from the environment.
+    pub fn from_env(
+        authorize_url: &str,
+        token_url: &str,
+        redirect_port: u16,
+    ) -> Result<OAuthConfig, OAuthError> {
+        let client_id = std::env::var("TWITTER_CLIENT_ID").map_err(|_| OAuthError::MissingClientId)?;
+        Ok(OAuthConfig {
+            client_id,
+            client_secret: std::env::var("TWITTER_CLIENT_SECRET").ok(),
+            authorize_url: authorize_url.to_string(),
+            token_url: token_url.to_string(),
+            redirect_uri: format!("http://127.0.0.1:{redirect_port}/callback"),
+            scopes: BOOKMARK_SCOPES.to_string(),
+        })
+    }
+}
+
+/// PKCE code verifier and its S256 challenge.
+#[derive(Debug, Clone)]
+pub struct PkceChallenge {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl PkceChallenge {
+    /// Generates a random verifier (RFC 7636 allows 43-128 characters).
+    pub fn new() -> Result<PkceChallenge, getrandom::Error> {
+        Ok(Self::from_verifier(&random_string(48)?))
+    }
+
+    pub fn from_verifier(verifier: &str) -> PkceChallenge {
+        let digest = Sha256::digest(verifier.as_bytes());
+        PkceChallenge {
+            verifier: verifier.to_string(),
+            challenge: URL_SAFE_NO_PAD.encode(digest),
+        }
+    }
+}
+
+/// A login waiting for the user to authorize the app in their browser.
+#[derive(Debug)]
+pub struct PendingLogin {
+    /// URL to open in the browser.
+    pub authorize_url: String,
+    pub state: String,
+    pub pkce: PkceChallenge,
+}
+
+/// Token response from the OAuth 2.0 token endpoint.
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    access_token: String,
+    token_type: Option<String>,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+/// A user-context access token, as stored in the cache.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct OAuthToken {
+    pub access_token: String,
+    pub token_type: Option<String>,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub scope: Option<String>,
+}
+
+impl OAuthToken {
+    fn from_response(resp: TokenResponse, now: DateTime<Utc>) -> OAuthToken {
+        OAuthToken {
+            access_token: resp.access_token,
+            token_type: resp.token_type,
+            refresh_token: resp.refresh_token,
+            expires_at: resp.expires_in.map(|secs| now + Duration::seconds(secs)),
+            scope: resp.scope,
+        }
+    }
+
+    /// `true` if the token has expired, or is about to, at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at - Duration::seconds(EXPIRY_MARGIN_SECS) <= now,
+            None => false,
+        }
+    }
+
+    /// Returns the full file path to where the user token is cached.
+    pub fn fs_full_path() -> std::io::Result<PathBuf> {
+        get_cache_file_path("oauth_token.json")
+    }
+
+    /// Loads the user token from cache, if there is one.
+    pub fn load_default() -> Result<Option<OAuthToken>, Box<dyn Error>> {
+        let path = Self::fs_full_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::load(&path)?))
+    }
+}
+
+/// Only readable by the user (on unix), and encrypted with `--encrypt`.
+impl FsCacheable<OAuthToken> for OAuthToken {
+    fn cache(&self, path: &Path) -> Result<&Self, Box<dyn Error>> {
+        write_private::<Self>(path, self)?;
+        Ok(self)
+    }
+}
+
+impl FsLoadable<OAuthToken> for OAuthToken {
+    fn load(path: &Path) -> Result<OAuthToken, Box<dyn Error>> {
+        read::<OAuthToken>(path)
+    }
+}
+
+/// Starts a login: generates the PKCE verifier and `state`, and builds the
+/// authorization URL for the user to open. Returns an error if the configured
+/// authorize URL is not valid.
+pub fn begin_login(config: &OAuthConfig) -> Result<PendingLogin, Box<dyn Error>> {
+    let pkce = PkceChallenge::new()?;
+    let state = random_string(24)?;
+
+    let mut url = reqwest::Url::parse(&config.authorize_url)
+        .map_err(|err| OAuthError::BadAuthorizeUrl(format!("{} ({err})", config.authorize_url)))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_uri)
+        .append_pair("scope", &config.scopes)
+        .append_pair("state", &state)
+        .append_pair("code_challenge", &pkce.challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(PendingLogin {
+        authorize_url: url.to_string(),
+        state,
+        pkce,
+    })
+}
+
+/// Waits for the browser to be redirected to our local listener, then
+/// exchanges the authorization code for a token.
+pub async fn complete_login(
+    client: &reqwest::Client,
+    config: &OAuthConfig,
+    pending: &PendingLogin,
+    listener: &TcpListener,
+) -> Result<OAuthToken, Box<dyn Error>> {
+    let code = wait_for_callback(listener, &pending.state).await?;
+
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", config.redirect_uri.as_str()),
+        ("code_verifier", pending.pkce.verifier.as_str()),
+        ("client_id", config.client_id.as_str()),
+    ];
+    Ok(request_token(client, config, &form).await?)
+}
+
+/// Runs the whole login flow: prints the authorization URL, waits for the
+/// redirect on 127.0.0.1 and stores the resulting token in the cache.
+pub async fn login(config: &OAuthConfig, port: u16) -> Result<OAuthToken, Box<dyn Error>> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    let pending = begin_login(config)?;
+
+    println!("Open this URL in your browser to authorize the exporter:");
+    println!();
+    println!("{}", pending.authorize_url);
+    println!();
+    println!("Waiting for the redirect to {}...", config.redirect_uri);
+
+    let client = reqwest::Client::new();
+    let token = complete_login(&client, config, &pending, &listener).await?;
+    cache::write_cache(&token, &OAuthToken::fs_full_path()?)?;
+    Ok(token)
+}
+
+/// Exchanges a refresh token for a new access token.
+pub async fn refresh(
+    client: &reqwest::Client,
+    config: &OAuthConfig,
+    refresh_token: &str,
+) -> Result<OAuthToken, TwitterApiError> {
+    let form = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", config.client_id.as_str()),
+    ];
+    request_token(client, config, &form).await
+}
+
+/// Loads the stored user token, refreshing (and re-storing) it if it has
+/// expired or is about to.
+pub async fn load_valid_token(config: &OAuthConfig) -> Result<OAuthToken, Box<dyn Error>> {
+    let token = match OAuthToken::load_default()? {
+        Some(token) => token,
+        None => return Err(Box::new(OAuthError::NotLoggedIn)),
+    };
+
+    if !token.is_expired(Utc::now()) {
+        return Ok(token);
+    }
+
+    let refresh_token = match &token.refresh_token {
+        Some(refresh_token) => refresh_token,
+        None => return Err(Box::new(OAuthError::Expired)),
+    };
+
+    println!("Refreshing the user token...");
+    let mut refreshed = refresh(&reqwest::Client::new(), config, refresh_token).await?;
+    // The token endpoint may not rotate the refresh token.
+    if refreshed.refresh_token.is_none() {
+        refreshed.refresh_token = token.refresh_token.clone();
+    }
+    cache::write_cache(&refreshed, &OAuthToken::fs_full_path()?)?;
+    Ok(refreshed)
+}
+
+/// Removes the stored user token.
+pub fn logout() -> std::io::Result<()> {
+    let path = OAuthToken::fs_full_path()?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// POSTs a form to the token endpoint, authenticating with the client secret
+/// when there is one (confidential clients).
+async fn request_token(
+    client: &reqwest::Client,
+    config: &OAuthConfig,
+    form: &[(&str, &str)],
+) -> Result<OAuthToken, TwitterApiError> {
+    let mut request = client.post(&config.token_url).form(form);
+    if let Some(secret) = &config.client_secret {
+        request = request.basic_auth(&config.client_id, Some(secret));
+    }
+
+    let resp = request.send().await?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status != reqwest::StatusCode::OK {
+        return Err(TwitterApiError::from_status(status, body));
+    }
+
+    let token = serde_json::from_str::<TokenResponse>(&body)
+        .map_err(|err| TwitterApiError::decode(err, body))?;
+    Ok(OAuthToken::from_response(token, Utc::now()))
+}
+
+/// Accepts connections on the redirect listener until one arrives at the
+/// callback path, answers it, and returns the authorization code.
+async fn wait_for_callback(listener: &TcpListener, expected_state: &str) -> Result<String, Box<dyn Error>> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut buf = vec![0u8; 8192];
+        let mut len = 0;
+        while len < buf.len() {
+            let n = stream.read(&mut buf[len..]).await?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+            if buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                break;
+            }
+        }
+
+        let request = String::from_utf8_lossy(&buf[..len]).to_string();
+        let target = request
+            .lines()
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .unwrap_or("");
+
+        // Browsers also ask for e.g. /favicon.ico; ignore anything else.
+        if !target.starts_with("/callback") {
+            respond(&mut stream, "404 Not Found", "Not found").await;
+            continue;
+        }
+
+        let result = parse_callback(target, expected_state);
+        let message = match &result {
+            Ok(_) => "Authorization complete. You can close this window and return to the terminal.",
+            Err(_) => "Authorization failed. Check the terminal for details.",
+        };
+        respond(&mut stream, "200 OK", message).await;
+        return result.map_err(|err| err.into());
+    }
+}
+
+/// Extracts the authorization code from the callback's request target.
+fn parse_callback(target: &str, expected_state: &str) -> Result<String, OAuthError> {
+    let url = reqwest::Url::parse(&format!("http://127.0.0.1{target}"))
+        .map_err(|_| OAuthError::BadCallback(target.to_string()))?;
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+    };
+
+    if let Some(error) = param("error") {
+        return Err(OAuthError::Denied(error));
+    }
+    if param("state").as_deref() != Some(expected_state) {
+        return Err(OAuthError::StateMismatch);
+    }
+    param("code").ok_or_else(|| OAuthError::BadCallback(target.to_string()))
+}
+
+async fn respond(stream: &mut tokio::net::TcpStream, status: &str, message: &str) {
+    let body = format!("<!DOCTYPE html><html><body><p>{message}</p></body></html>");
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    // The browser going away early is not our problem.
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// `bytes` random bytes from the operating system's secure generator, as a
+/// string of unreserved URL characters (4 for every 3 bytes).
+fn random_string(bytes: usize) -> Result<String, getrandom::Error> {
+    let mut random = vec![0u8; bytes];
+    getrandom::getrandom(&mut random)?;
+    Ok(URL_SAFE_NO_PAD.encode(random))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::mock_server::{use_scratch_cache_dir, MockTwitter};
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Example from RFC 7636, Appendix B.
+        let pkce = PkceChallenge::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+
+        let pkce = PkceChallenge::new().unwrap();
+        assert_eq!(pkce.verifier.len(), 64);
+        assert!(pkce.verifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(pkce.verifier, PkceChallenge::new().unwrap().verifier);
+    }
+
+    #[test]
+    fn test_parse_callback() {
+        assert_eq!(parse_callback("/callback?state=abc&code=xyz", "abc").unwrap(), "xyz");
+        assert!(matches!(
+            parse_callback("/callback?state=other&code=xyz", "abc"),
+            Err(OAuthError::StateMismatch)
+        ));
+        assert!(matches!(
+            parse_callback("/callback?error=access_denied&state=abc", "abc"),
+            Err(OAuthError::Denied(_))
+        ));
+    }
+
+    #[test]
+    fn test_token_file_is_private() {
+        let path = use_scratch_cache_dir().join("oauth-test-token.json");
+        let token = OAuthToken {
+            access_token: "secret".to_string(),
+            token_type: None,
+            refresh_token: Some("also-secret".to_string()),
+            expires_at: None,
+            scope: None,
+        };
+        // Replacing a world readable file makes it private too.
+        std::fs::write(&path, "{}").unwrap();
+        token.cache(&path).unwrap();
+        assert_eq!(OAuthToken::load(&path).unwrap(), token);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_login_and_refresh_against_mock() {
+        let server = MockTwitter::new().start().await;
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = OAuthConfig {
+            client_id: "test-client".to_string(),
+            client_secret: None,
+            authorize_url: DEFAULT_AUTHORIZE_URL.to_string(),
+            token_url: format!("{}/2/oauth2/token", server.base_url),
+            redirect_uri: format!("http://127.0.0.1:{port}/callback"),
+            scopes: BOOKMARK_SCOPES.to_string(),
+        };
+
+        let bad_url = OAuthConfig { authorize_url: "twitter.com/authorize".to_string(), ..config.clone() };
+        let err = begin_login(&bad_url).unwrap_err();
+        assert!(matches!(err.downcast_ref::<OAuthError>(), Some(OAuthError::BadAuthorizeUrl(_))));
+
+        let pending = begin_login(&config).unwrap();
+        let authorize_url = reqwest::Url::parse(&pending.authorize_url).unwrap();
+        assert!(authorize_url
+            .query_pairs()
+            .any(|(k, v)| k == "code_challenge" && v == pending.pkce.challenge));
+
+        // Play the part of the browser, following the redirect back to us.
+        let callback = format!("{}?state={}&code=mock-code", config.redirect_uri, pending.state);
+        let browser = tokio::spawn(async move { reqwest::get(callback).await.unwrap().status() });
+
+        let client = reqwest::Client::new();
+        let token = complete_login(&client, &config, &pending, &listener).await.unwrap();
+        assert_eq!(browser.await.unwrap(), reqwest::StatusCode::OK);
+        assert_eq!(token.access_token, "user-access-1");
+        assert!(!token.is_expired(Utc::now()));
+
+        let refreshed = refresh(&client, &config, token.refresh_token.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(refreshed.access_token, "user-access-2");
+
+        // The verifier we sent must match the challenge in the authorize URL.
+        let requests = server.state.lock().unwrap().token_requests.clone();
+        assert!(requests[0].contains(&format!("code_verifier={}", pending.pkce.verifier)));
+    }
+}
@@ -0,0 +1,211 @@
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header with the number of requests remaining in the current window.
+const HEADER_REMAINING: &str = "x-rate-limit-remaining";
+/// Header with the time (UTC epoch seconds) at which the current window resets.
+const HEADER_RESET: &str = "x-rate-limit-reset";
+/// Header with the total number of requests allowed in the window.
+const HEADER_LIMIT: &str = "x-rate-limit-limit";
+
+/// Twitter rate limit windows are 15 minutes long. Used as a fallback when a
+/// 429 response arrives without a usable reset header.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Extra time to wait past the advertised reset, to allow for clock skew
+/// between us and the API.
+const RESET_MARGIN: Duration = Duration::from_secs(1);
+
+/// Rate-limit state for a single API endpoint, as last reported by the
+/// `x-rate-limit-*` response headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitWindow {
+    pub limit: Option<u32>,
+    pub remaining: Option<u32>,
+    /// UTC epoch seconds when the window resets.
+    pub reset_at: Option<u64>,
+}
+
+impl RateLimitWindow {
+    /// Builds a window from the response headers, or `None` if the response
+    /// carried no rate-limit headers at all.
+    pub fn from_headers(headers: &HeaderMap) -> Option<RateLimitWindow> {
+        let parse = |name: &str| -> Option<u64> {
+            headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok()
+        };
+
+        let window = RateLimitWindow {
+            limit: parse(HEADER_LIMIT).map(|v| v as u32),
+            remaining: parse(HEADER_REMAINING).map(|v| v as u32),
+            reset_at: parse(HEADER_RESET),
+        };
+
+        if window == RateLimitWindow::default() {
+            None
+        } else {
+            Some(window)
+        }
+    }
+
+    /// How long to wait until the window resets, relative to `now` (epoch
+    /// seconds). Falls back to a full window if the reset time is unknown.
+    pub fn until_reset(&self, now: u64) -> Duration {
+        match self.reset_at {
+            Some(reset_at) if reset_at > now => Duration::from_secs(reset_at - now) + RESET_MARGIN,
+            Some(_) => RESET_MARGIN,
+            None => DEFAULT_WINDOW,
+        }
+    }
+
+    /// Returns the time to wait before the next request may be sent, or
+    /// `None` if there are requests remaining in the window.
+    pub fn wait_duration(&self, now: u64) -> Option<Duration> {
+        match (self.remaining, self.reset_at) {
+            (Some(0), Some(reset_at)) if reset_at > now => Some(self.until_reset(now)),
+            _ => None,
+        }
+    }
+}
+
+/// Schedules requests to the Twitter API so that we only wait when the rate
+/// limit for an endpoint is actually exhausted, instead of sleeping a fixed
+/// interval between every request. Limits are tracked per endpoint, since the
+/// API applies separate windows to e.g. likes and user lookups.
+#[derive(Debug, Default)]
+pub struct RateLimitScheduler {
+    windows: HashMap<String, RateLimitWindow>,
+}
+
+impl RateLimitScheduler {
+    pub fn new() -> RateLimitScheduler {
+        RateLimitScheduler {
+            ..Default::default()
+        }
+    }
+
+    /// Records the rate-limit headers returned for a request to `endpoint`.
+    pub fn update(&mut self, endpoint: &str, headers: &HeaderMap) {
+        if let Some(window) = RateLimitWindow::from_headers(headers) {
+            self.windows.insert(endpoint.to_string(), window);
+        }
+    }
+
+    /// Returns the last known window for `endpoint`, if any.
+    pub fn window(&self, endpoint: &str) -> Option<&RateLimitWindow> {
+        self.windows.get(endpoint)
+    }
+
+    /// Waits (without blocking the runtime) until a request to `endpoint` is
+    /// allowed. Returns immediately if requests remain in the window.
+    pub async fn wait(&self, endpoint: &str) {
+        let wait = self
+            .window(endpoint)
+            .and_then(|window| window.wait_duration(epoch_seconds()));
+
+        if let Some(duration) = wait {
+            println!(
+                "Rate limit reached for {endpoint}, waiting {}s for the window to reset...",
+                duration.as_secs()
+            );
+            tokio::time::sleep(duration).await;
+        }
+    }
+
+    /// Waits until the window for `endpoint` resets. Used after the API
+    /// responds with HTTP 429 Too Many Requests.
+    pub async fn wait_for_reset(&self, endpoint: &str) {
+        let duration = match self.window(endpoint) {
+            Some(window) => window.until_reset(epoch_seconds()),
+            None => DEFAULT_WINDOW,
+        };
+        println!(
+            "Rate limited (429) on {endpoint}, waiting {}s for the window to reset...",
+            duration.as_secs()
+        );
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// Derives the key used to track rate limits from a request URL: the path,
+/// with numeric id segments (after the API version) replaced by `:id`, so
+/// that e.g. the liked tweets of different users share a single window.
+pub fn endpoint_key(url: &str) -> String {
+    let path = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed.path().to_string(),
+        Err(_) => url.split('?').next().unwrap_or(url).to_string(),
+    };
+
+    path.split('/')
+        .enumerate()
+        .map(|(i, segment)| {
+            // Segment 0 is empty (leading slash) and segment 1 is the version.
+            if i > 1 && !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()) {
+                ":id"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+/// Current time in UTC epoch seconds.
+pub fn epoch_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(remaining: &str, reset: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_LIMIT, HeaderValue::from_static("75"));
+        headers.insert(HEADER_REMAINING, HeaderValue::from_str(remaining).unwrap());
+        headers.insert(HEADER_RESET, HeaderValue::from_str(reset).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let window = RateLimitWindow::from_headers(&headers("3", "1000")).unwrap();
+        assert_eq!(window.limit, Some(75));
+        assert_eq!(window.remaining, Some(3));
+        assert_eq!(window.reset_at, Some(1000));
+        assert_eq!(RateLimitWindow::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_wait_duration() {
+        let window = RateLimitWindow::from_headers(&headers("0", "1060")).unwrap();
+        assert_eq!(window.wait_duration(1000), Some(Duration::from_secs(61)));
+        // The window has already reset.
+        assert_eq!(window.wait_duration(1100), None);
+
+        let window = RateLimitWindow::from_headers(&headers("10", "1060")).unwrap();
+        assert_eq!(window.wait_duration(1000), None);
+    }
+
+    #[test]
+    fn test_until_reset_without_header() {
+        let window = RateLimitWindow::default();
+        assert_eq!(window.until_reset(1000), DEFAULT_WINDOW);
+    }
+
+    #[test]
+    fn test_endpoint_key() {
+        assert_eq!(
+            endpoint_key("https://api.twitter.com/2/users/12345/liked_tweets?max_results=100"),
+            "/2/users/:id/liked_tweets"
+        );
+        assert_eq!(
+            endpoint_key("https://api.twitter.com/2/users/by?usernames=matsuzine"),
+            "/2/users/by"
+        );
+    }
+}
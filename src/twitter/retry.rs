@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+/// Controls how transient failures (network errors and 5xx responses) are
+/// retried; 429 responses wait for the rate limit window to reset instead.
+/// Delays grow exponentially from `base_delay`, capped at `max_delay`, with
+/// "full jitter" so that concurrent exports don't retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt. 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry (before jitter).
+    pub base_delay: Duration,
+    /// Upper bound for any single delay.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay,
+            ..Default::default()
+        }
+    }
+
+    /// Upper bound of the delay before retry number `attempt` (0-based):
+    /// `base_delay * 2^attempt`, capped at `max_delay`.
+    pub fn backoff_ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// Delay before retry number `attempt` (0-based), chosen uniformly at
+    /// random between zero and the backoff ceiling.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.backoff_ceiling(attempt).as_millis() as u64;
+        Duration::from_millis(fastrand::u64(0..=ceiling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_ceiling() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        };
+        assert_eq!(policy.backoff_ceiling(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff_ceiling(3), Duration::from_secs(8));
+        assert_eq!(policy.backoff_ceiling(5), Duration::from_secs(30));
+        assert_eq!(policy.backoff_ceiling(64), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_delay_is_jittered_within_ceiling() {
+        let policy = RetryPolicy::new(3, Duration::from_millis(100));
+        for attempt in 0..5 {
+            assert!(policy.delay(attempt) <= policy.backoff_ceiling(attempt));
+        }
+    }
+}
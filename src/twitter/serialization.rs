@@ -1,28 +1,78 @@
-use std::fs;
+use crate::cache::{compression, write_atomic, write_atomic_private};
+use crate::encryption;
+use crate::cache::migrations::{self, CacheEnvelope, CacheKind, CURRENT_VERSION};
+use serde_json::Value;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fs;
 use std::path::Path;
 
-/// Helper function to serialize serde Serializable types to disk.
+/// Writes `bytes` to `path`, compressed if its extension is `.gz` or `.zst`
+/// and then encrypted if the cache is (see `encryption::seal_cache_file`). The
+/// file is replaced atomically, so it is never left half-written.
+pub fn write_bytes(path: &Path, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let bytes = encryption::seal_cache_file(path, compression::compress(path, bytes)?)?;
+    write_atomic(path, &bytes).map_err(|err| format!("Failed to write file {}: {err}", path.display()))?;
+    Ok(())
+}
+
+/// Reads the file at `path`, decrypting it if it is encrypted and
+/// decompressing it if its extension is `.gz` or `.zst`.
+pub fn read_bytes(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let raw = encryption::open(fs::read(path)?)
+        .map_err(|err| format!("Failed to read file {}: {err}", path.display()))?;
+    Ok(compression::decompress(path, raw)?)
+}
+
+/// Helper function to serialize serde Serializable types to disk (see
+/// `write_bytes`).
 pub fn write<T>(path: &Path, obj: &T) -> Result<(), Box<dyn Error>>
 where
     T: Serialize,
 {
     let json_str = serde_json::to_string_pretty(obj)?;
-    let path_str = match path.to_str() {
-        Some(s) => s,
-        None => "unknown path",
-    };
-    fs::write(path, json_str).expect(&format!("Failed to write file {}", path_str));
+    write_bytes(path, json_str.into_bytes())
+}
+
+/// Like `write`, for files holding secrets: on unix, only their owner can
+/// read them (see `write_atomic_private`). Not compressed.
+pub fn write_private<T>(path: &Path, obj: &T) -> Result<(), Box<dyn Error>>
+where
+    T: Serialize,
+{
+    let bytes = encryption::seal_cache_file(path, serde_json::to_string_pretty(obj)?.into_bytes())?;
+    write_atomic_private(path, &bytes).map_err(|err| format!("Failed to write file {}: {err}", path.display()))?;
     Ok(())
 }
 
-/// Helper function to deserialize serde Deserialize types into memory.
+/// Helper function to deserialize serde Deserialize types into memory (see
+/// `read_bytes`).
 pub fn read<T>(path: &Path) -> Result<T, Box<dyn Error>>
 where
     T: for<'a> Deserialize<'a>,
 {
-    let json_str = fs::read_to_string(path)?;
-    let result = serde_json::from_str::<T>(&json_str)?;
+    let bytes = read_bytes(path)?;
+    let result = serde_json::from_slice::<T>(&bytes)?;
     Ok(result)
-}
\ No newline at end of file
+}
+
+/// Like `write`, but wraps `obj` in a versioned envelope of the given kind.
+pub fn write_versioned<T>(path: &Path, kind: CacheKind, obj: &T) -> Result<(), Box<dyn Error>>
+where
+    T: Serialize,
+{
+    let envelope = CacheEnvelope { version: CURRENT_VERSION, kind, data: obj };
+    write(path, &envelope)
+}
+
+/// Like `read`, but expects a versioned envelope of the given kind, upgrading
+/// files written in an older format.
+pub fn read_versioned<T>(path: &Path, kind: CacheKind) -> Result<T, Box<dyn Error>>
+where
+    T: for<'a> Deserialize<'a>,
+{
+    let value: Value = read(path)?;
+    let upgraded = migrations::upgrade(kind, value)?;
+    let envelope = serde_json::from_value::<CacheEnvelope<T>>(upgraded)?;
+    Ok(envelope.data)
+}
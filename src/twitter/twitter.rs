@@ -1,48 +1,156 @@
-use crate::twitter::json_types::{TwitLikeResponse, TwitUserResponse, UserIdLookup};
-use crate::{args::OutputFormat, cache, dumps};
-use chrono::NaiveDate;
+use crate::twitter::json_types::{
+    ExportCheckpoint, TwitLikeResponse, TwitUserResponse, UserIdLookup,
+};
+use crate::twitter::errors::TwitterApiError;
+use crate::twitter::rate_limit::{endpoint_key, epoch_seconds, RateLimitScheduler};
+use crate::twitter::retry::RetryPolicy;
+use crate::args::{ExportSource, OutputFormat, SortOrder, TableArgs};
+use crate::cache::verify::{self, Issue, RepairReport};
+use crate::media::MediaManifest;
+use crate::serialization::FsLoadable;
+use crate::{cache, dumps, encryption};
+use chrono::{DateTime, NaiveDate, Utc};
 use reqwest::header::{AUTHORIZATION, USER_AGENT};
 use serde::de;
-use std::{error::Error, path::Path};
-
-
-/// Submits an HTTP (GET) request to the Twitter API.
-pub async fn send_request<T>(bearer_token: &str, client: &reqwest::Client, url: &str) -> T
-where
-    T: de::DeserializeOwned + core::fmt::Debug,
-{
-    let resp = client
-        .get(url)
-        .header(AUTHORIZATION, format!("Bearer {bearer_token}"))
-        .header(USER_AGENT, "MatsuzineExportLikes") // TODO: fix
-        .send()
-        .await
-        .unwrap();
+use std::collections::HashSet;
+use std::{error::Error, path::Path, time::Duration};
 
-    // TODO: Error Handling
-    // thread 'main' panicked at 'called `Result::unwrap()` on an `Err` value:
-    // reqwest::Error { kind: Decode, source: Error("missing field `data`",
-    // line: 1, column: 92) }', src/twitter.rs:24:59
 
-    let twit_data: T = match resp.status() {
-        reqwest::StatusCode::OK => resp.json::<T>().await.unwrap(),
-        _ => panic!("Bad response: {:?}", resp.text().await.unwrap()),
-    };
+/// HTTP client for the Twitter API. Wraps a `reqwest::Client` together with
+/// the bearer token, the API URLs, the rate-limit scheduler and the retry
+/// policy, so that every request is paced and retried the same way.
+pub struct TwitClient {
+    client: reqwest::Client,
+    bearer_token: String,
+    pub urls: TwitApiUrl,
+    scheduler: RateLimitScheduler,
+    retry: RetryPolicy,
+}
+
+impl TwitClient {
+    /// Creates a client for the API at `base_url` (see `DEFAULT_API_BASE_URL`).
+    pub fn new(bearer_token: &str, base_url: &str, retry: RetryPolicy) -> TwitClient {
+        TwitClient {
+            client: reqwest::Client::new(),
+            bearer_token: bearer_token.to_string(),
+            urls: TwitApiUrl::new(base_url),
+            scheduler: RateLimitScheduler::new(),
+            retry,
+        }
+    }
+
+    /// Submits an HTTP (GET) request to the Twitter API and decodes the
+    /// response. Before sending, waits on the scheduler if the rate limit for
+    /// this endpoint has been used up. A 429 waits for the window to reset
+    /// and tries again, however often it happens; other transient failures
+    /// (network errors and 5xx responses) are retried according to the retry
+    /// policy, backing off exponentially with jitter.
+    pub async fn send_request<T>(&mut self, url: &str) -> Result<T, TwitterApiError>
+    where
+        T: de::DeserializeOwned + core::fmt::Debug,
+    {
+        let endpoint = endpoint_key(url);
+        let mut attempt: u32 = 0;
+
+        loop {
+            self.scheduler.wait(&endpoint).await;
+
+            let err = match self.try_send_request::<T>(&endpoint, url).await {
+                Ok(data) => return Ok(data),
+                Err(err) => err,
+            };
+
+            // Waiting for the window to reset is not a retry: it doesn't use
+            // up `max_retries`.
+            if let TwitterApiError::RateLimited { .. } = err {
+                self.scheduler.wait_for_reset(&endpoint).await;
+                continue;
+            }
+            if !err.is_transient() || attempt >= self.retry.max_retries {
+                return Err(err);
+            }
+
+            let delay = self.retry.delay(attempt);
+            println!(
+                "Request failed ({err}), retrying in {}ms ({}/{})...",
+                delay.as_millis(),
+                attempt + 1,
+                self.retry.max_retries
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Sends a single request, recording its rate-limit headers.
+    async fn try_send_request<T>(&mut self, endpoint: &str, url: &str) -> Result<T, TwitterApiError>
+    where
+        T: de::DeserializeOwned + core::fmt::Debug,
+    {
+        let resp = self
+            .client
+            .get(url)
+            .header(AUTHORIZATION, format!("Bearer {}", self.bearer_token))
+            .header(USER_AGENT, "MatsuzineExportLikes") // TODO: fix
+            .send()
+            .await?;
+
+        self.scheduler.update(endpoint, resp.headers());
 
-    twit_data
+        let status = resp.status();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = match self.scheduler.window(endpoint) {
+                Some(window) => window.until_reset(epoch_seconds()),
+                None => Duration::ZERO,
+            };
+            return Err(TwitterApiError::RateLimited { retry_after });
+        }
+
+        // Read the raw body first, so that decode failures (e.g. a response
+        // with `errors` but no `data`) can report what the API actually said.
+        let body = resp.text().await?;
+        if status != reqwest::StatusCode::OK {
+            return Err(TwitterApiError::from_status(status, body));
+        }
+
+        serde_json::from_str::<T>(&body).map_err(|err| TwitterApiError::decode(err, body))
+    }
 }
 
+/// Maximum number of users the users lookup endpoints accept per request.
+pub const USERS_LOOKUP_LIMIT: usize = 100;
+
 pub enum TwitUrlFormatErrors {
     ExceedsLimit(String),
     NotAtMinimum(String),
 }
 
-struct TwitApiUrl {}
+/// Base URL of the Twitter API, used unless another is configured (e.g. to
+/// point the exporter at a mock server).
+pub const DEFAULT_API_BASE_URL: &str = "https://api.twitter.com";
+
+/// TwitApiUrl generates the URLs to make requests to the Twitter API v2,
+/// relative to a configurable base URL.
+pub struct TwitApiUrl {
+    base_url: String,
+}
+
+impl Default for TwitApiUrl {
+    fn default() -> TwitApiUrl {
+        TwitApiUrl::new(DEFAULT_API_BASE_URL)
+    }
+}
 
-/// TwitApiUrl has static functions for generating the URLs to make requests to
-/// the Twitter API v2.
 #[allow(rustdoc::bare_urls)]
 impl TwitApiUrl {
+    /// Creates a URL generator for the API at `base_url`, e.g.
+    /// "https://api.twitter.com" or "http://127.0.0.1:8080".
+    pub fn new(base_url: &str) -> TwitApiUrl {
+        TwitApiUrl {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
     /// Generate URL fetching liked tweets for a given user id.
     /// 
     /// # Arguments
@@ -54,7 +162,68 @@ impl TwitApiUrl {
     /// Returns a URL.
     /// <https://developer.twitter.com/en/docs/twitter-api/users/lookup/api-reference>
     /// https://api.twitter.com/2/users/{user_id}/liked_tweets
-    pub fn users_liked_tweets_url(user_id: &str, next_token: Option<&str>) -> String {
+    pub fn users_liked_tweets_url(&self, user_id: &str, next_token: Option<&str>) -> String {
+        self.users_tweets_page_url("liked_tweets", user_id, next_token)
+    }
+
+    /// Generate URL fetching bookmarked tweets for a given user id. Requires a
+    /// user-context (OAuth 2.0) token for that same user.
+    ///
+    /// Returns a URL.
+    /// <https://developer.twitter.com/en/docs/twitter-api/tweets/bookmarks/api-reference>
+    /// https://api.twitter.com/2/users/{user_id}/bookmarks
+    pub fn users_bookmarks_url(&self, user_id: &str, next_token: Option<&str>) -> String {
+        self.users_tweets_page_url("bookmarks", user_id, next_token)
+    }
+
+    /// Generate URL fetching tweets authored by a given user id (their
+    /// timeline, including retweets and replies unless excluded).
+    ///
+    /// # Arguments
+    ///
+    /// - `user_id` id of the user whose tweets to fetch.
+    /// - `next_token` pagination token, if available.
+    /// - `exclude` kinds of tweets to leave out: "replies", "retweets".
+    ///
+    /// Returns a URL.
+    /// <https://developer.twitter.com/en/docs/twitter-api/tweets/timelines/api-reference>
+    /// https://api.twitter.com/2/users/{user_id}/tweets
+    pub fn users_tweets_url(&self, user_id: &str, next_token: Option<&str>, exclude: &[&str]) -> String {
+        let url = self.users_tweets_page_url("tweets", user_id, next_token);
+        if exclude.is_empty() {
+            url
+        } else {
+            format!("{url}&exclude={}", exclude.join(","))
+        }
+    }
+
+    /// Generate URL for a page of tweets from the source in `options` for a
+    /// given user id.
+    pub fn source_url(&self, options: &ExportOptions, user_id: &str, next_token: Option<&str>) -> String {
+        match options.source {
+            ExportSource::Likes => self.users_liked_tweets_url(user_id, next_token),
+            ExportSource::Bookmarks => self.users_bookmarks_url(user_id, next_token),
+            ExportSource::Tweets => {
+                let mut exclude = vec![];
+                if options.exclude_replies {
+                    exclude.push("replies");
+                }
+                if options.exclude_retweets {
+                    exclude.push("retweets");
+                }
+                self.users_tweets_url(user_id, next_token, &exclude)
+            }
+        }
+    }
+
+    /// Generate URL of the OAuth 2.0 token endpoint.
+    /// https://api.twitter.com/2/oauth2/token
+    pub fn oauth2_token_url(&self) -> String {
+        format!("{}/2/oauth2/token", self.base_url)
+    }
+
+    /// Shared by the endpoints returning pages of tweets for a user.
+    fn users_tweets_page_url(&self, endpoint: &str, user_id: &str, next_token: Option<&str>) -> String {
         // See example:
         // https://github.com/twitterdev/Twitter-API-v2-sample-code/blob/main/Likes-Lookup/liked_tweets.py
         // tweet_fields:
@@ -66,13 +235,20 @@ impl TwitApiUrl {
 
         // TODO: Check if user_id is empty.
     
-        let tweet_fields = "tweet.fields=created_at,lang,author_id,attachments,entities";
+        let tweet_fields = "tweet.fields=created_at,lang,author_id,attachments,entities,referenced_tweets,geo";
+        // Referenced objects are returned once per page, in `includes`.
+        let expansions = "expansions=attachments.media_keys,referenced_tweets.id,attachments.poll_ids,author_id,geo.place_id";
+        let media_fields = "media.fields=media_key,type,url,preview_image_url,width,height,alt_text,duration_ms,variants";
+        let poll_fields = "poll.fields=id,options,duration_minutes,end_datetime,voting_status";
+        let place_fields = "place.fields=id,full_name,name,country,country_code,place_type";
+        let user_fields = "user.fields=id,name,username,url,profile_image_url";
         let pagination_token = match next_token {
             Some(next_token) => format!("&pagination_token={next_token}"),
             None => "".to_string(),
         };
         format!(
-            "https://api.twitter.com/2/users/{user_id}/liked_tweets?{tweet_fields}{pagination_token}&max_results=100"
+            "{}/2/users/{user_id}/{endpoint}?{tweet_fields}&{expansions}&{media_fields}&{poll_fields}&{place_fields}&{user_fields}{pagination_token}&max_results=100",
+            self.base_url,
         )
     }
 
@@ -90,7 +266,7 @@ impl TwitApiUrl {
     /// Returns a URL.
     /// <https://developer.twitter.com/en/docs/twitter-api/users/lookup/api-reference>
     /// https://api.twitter.com/2/users/by?usernames={}
-    pub fn users_by_username_url(usernames: &[&str]) -> Result<String, TwitUrlFormatErrors> {
+    pub fn users_by_username_url(&self, usernames: &[&str]) -> Result<String, TwitUrlFormatErrors> {
         // usernames = "usernames=TwitterDev,TwitterAPI"
         // user_fields = "user.fields=description,created_at"
         // # User fields are adjustable, options include:
@@ -110,7 +286,8 @@ impl TwitApiUrl {
     
         let usernames = usernames.join(",");
         Ok(format!(
-            "https://api.twitter.com/2/users/by?usernames={}&user.fields=id,description,name,username,url,profile_image_url",
+            "{}/2/users/by?usernames={}&user.fields=id,description,name,username,url,profile_image_url",
+            self.base_url,
             usernames,
         ))
     }
@@ -130,12 +307,12 @@ impl TwitApiUrl {
 
     /// <https://developer.twitter.com/en/docs/twitter-api/users/lookup/api-reference>
     /// https://api.twitter.com/2/users?ids={user_ids}
-    pub fn users_by_ids_url(user_ids: &[String]) -> Result<String, TwitUrlFormatErrors> {
+    pub fn users_by_ids_url(&self, user_ids: &[String]) -> Result<String, TwitUrlFormatErrors> {
         // created_at, description, entities, id, location, name,
         // pinned_tweet_id, profile_image_url, protected,
         // public_metrics, url, username, verified, withheld
         // curl "https://api.twitter.com/2/users?ids=3107896458,823083&user.fields=id,profile_image_url,url,username"
-        if user_ids.len() > 100 {
+        if user_ids.len() > USERS_LOOKUP_LIMIT {
             return Err(TwitUrlFormatErrors::ExceedsLimit(
                 "Number of user_ids is limited to 100".to_owned(),
             ));
@@ -147,58 +324,371 @@ impl TwitApiUrl {
         let user_ids = user_ids.join(",");
     
         Ok(format!(
-            "https://api.twitter.com/2/users?ids={user_ids}&user.fields=id,profile_image_url,url,username"
+            "{}/2/users?ids={user_ids}&user.fields=id,profile_image_url,url,username",
+            self.base_url,
         ))
     }
     
 }
 
 
-/// Compiles a list of liked tweets and writes them to the specified output
-/// format and (optional) filename
+/// Compiles a list of liked (bookmarked, or authored) tweets and writes them
+/// to the specified output format and (optional) filename. When more than one
+/// source is given, the tweets from all of them are merged into one output.
+/// Media already downloaded to `media_dir` is referenced by its local path.
+/// `table` gives the columns of the tabular formats.
 pub fn compile_twitter_exports_for_username(
     username: &str,
+    sources: &[ExportSource],
     format: &OutputFormat,
     filename: Option<&str>,
+    sort: SortOrder,
+    media_dir: &Path,
+    table: &TableArgs,
 ) -> Result<(), Box<dyn Error>> {
-    let liked_tweets = cache::load_all_liked_tweets_from_cache(username)?;
-    let mut default_filename = format!("liked_tweets-{username}").to_string();
-    let path = match filename {
-        Some(filen) => Path::new(filen),
-        None => match format {
-            OutputFormat::JSON => {
-                default_filename = format!("{default_filename}.json");
-                Path::new(&default_filename)
+    let mut default_filename = match sources {
+        [source] => format!("{}-{username}", source.compiled_name()),
+        _ => format!("merged_tweets-{username}"),
+    };
+    match format {
+        OutputFormat::JSON => default_filename.push_str(".json"),
+        OutputFormat::Jsonl => default_filename.push_str(".jsonl"),
+        OutputFormat::Markdown => default_filename.push_str(".md"),
+        OutputFormat::Csv => default_filename.push_str(".csv"),
+        OutputFormat::Tsv => default_filename.push_str(".tsv"),
+        OutputFormat::Netscape => default_filename.push_str(".bookmarks.html"),
+        OutputFormat::Pinboard => default_filename.push_str(".pinboard.json"),
+        OutputFormat::Raindrop => default_filename.push_str(".raindrop.csv"),
+        // A directory.
+        OutputFormat::Html => {
+            if encryption::encryption_enabled() {
+                return Err("An encrypted site could not be opened in a browser; compile it without --encrypt".into());
             }
-            OutputFormat::Markdown => {
-                default_filename = format!("{default_filename}.md");
-                Path::new(&default_filename)
+        }
+        OutputFormat::Vault => {
+            if encryption::encryption_enabled() {
+                return Err("An encrypted vault could not be opened in a notes app; compile it without --encrypt".into());
             }
-        },
-    };
+            default_filename.push_str("-vault");
+        }
+    }
+    if encryption::encryption_enabled() {
+        default_filename.push_str(encryption::OUTPUT_EXTENSION);
+    }
+    let path = Path::new(filename.unwrap_or(&default_filename));
+    let manifest = MediaManifest::load_or_default(media_dir)?;
+
+    // Streamed straight from the cache, unless the tweets must be sorted.
+    if *format == OutputFormat::Jsonl && sort == SortOrder::Like {
+        let mut writer = dumps::jsonl::JsonlWriter::create(path)?;
+        let mut linked = 0;
+        let (_, duplicates) = cache::for_each_cached_tweet(sources, username, |mut tweet| {
+            linked += manifest.link_tweet(media_dir, &mut tweet);
+            writer.write(&tweet)
+        })?;
+        writer.finish()?;
+        report_compile_counts(duplicates, linked, media_dir);
+        return Ok(());
+    }
+
+    let mut liked_tweets = cache::load_all_liked_tweets_from_cache(sources, username)?;
+    liked_tweets.sort(sort);
+    let linked = manifest.link(media_dir, &mut liked_tweets);
+    report_compile_counts(liked_tweets.duplicates, linked, media_dir);
 
     // TODO: Combine match with above?
     match format {
         OutputFormat::JSON => dumps::to_json(path, &liked_tweets),
+        OutputFormat::Jsonl => dumps::jsonl::to_jsonl(path, &liked_tweets),
         OutputFormat::Markdown => dumps::to_markdown(path, &liked_tweets),
+        OutputFormat::Html => dumps::html::to_html(path, &liked_tweets),
+        OutputFormat::Csv => dumps::table::to_table(path, &liked_tweets, ',', table),
+        OutputFormat::Tsv => dumps::table::to_table(path, &liked_tweets, '\t', table),
+        OutputFormat::Netscape => dumps::bookmarks::to_netscape(path, &liked_tweets),
+        OutputFormat::Pinboard => dumps::bookmarks::to_pinboard(path, &liked_tweets),
+        OutputFormat::Raindrop => dumps::bookmarks::to_raindrop(path, &liked_tweets),
+        OutputFormat::Vault => dumps::vault::to_vault(path, &liked_tweets),
+    }
+}
+
+/// Prints how many duplicates were left out and media files linked. (To
+/// stderr, since outputs can be written to stdout.)
+fn report_compile_counts(duplicates: usize, linked: usize, media_dir: &Path) {
+    if duplicates > 0 {
+        eprintln!(
+            "Collapsed {duplicates} duplicate copies of tweets cached more than once (`cache compact` removes them from the cache)"
+        );
+    }
+    if linked > 0 {
+        eprintln!("Linked {linked} media files in {}", media_dir.display());
     }
 }
 
-/// This function exports the "liked" tweets for a given user in batches, writing
-/// them to a filesystem cache.
+/// Adds the authors of the tweets in `like_response` to `user_id_lkup`. Most
+/// come embedded in the response (`includes.users`); any others are looked up
+/// by id, in batches of at most `USERS_LOOKUP_LIMIT`.
+async fn resolve_authors(
+    client: &mut TwitClient,
+    username: &str,
+    user_id_lkup: &mut UserIdLookup,
+    like_response: &TwitLikeResponse,
+) -> Result<(), Box<dyn Error>> {
+    let mut changed = false;
+    let included_users = like_response
+        .includes
+        .iter()
+        .flat_map(|includes| includes.users.iter().flatten());
+    for user in included_users {
+        if !matches!(user_id_lkup.users_by_id.get(&user.id), Some(Some(_))) {
+            user_id_lkup.insert(user.id.clone(), Some(user.clone()));
+            changed = true;
+        }
+    }
+
+    // Gather the authors we still know nothing about to batch download
+    for tweet in like_response.data.iter().flatten() {
+        if !user_id_lkup.has(&tweet.author_id) {
+            user_id_lkup.insert(tweet.author_id.clone(), None);
+        }
+    }
+    changed |= look_up_missing_users(client, user_id_lkup).await? > 0;
+
+    if changed {
+        cache::write_user_lookup(username, user_id_lkup)?;
+    }
+    Ok(())
+}
+
+/// Looks up the users of `user_id_lkup` which are still missing, in batches
+/// of at most `USERS_LOOKUP_LIMIT`. Returns the number of users looked up.
+async fn look_up_missing_users(
+    client: &mut TwitClient,
+    user_id_lkup: &mut UserIdLookup,
+) -> Result<usize, Box<dyn Error>> {
+    let mut missing_users: Vec<String> = user_id_lkup
+        .users_by_id
+        .keys()
+        .filter(|id| user_id_lkup.is_missing(id))
+        .cloned()
+        .collect();
+    missing_users.sort();
+
+    for batch in missing_users.chunks(USERS_LOOKUP_LIMIT) {
+        let url = match client.urls.users_by_ids_url(batch) {
+            Ok(url) => url,
+            Err(TwitUrlFormatErrors::ExceedsLimit(msg) | TwitUrlFormatErrors::NotAtMinimum(msg)) => {
+                return Err(msg.into())
+            }
+        };
+        let users_response = client.send_request::<TwitUserResponse>(&url).await?;
+        for user in users_response.data {
+            user_id_lkup.insert(user.id.clone(), Some(user));
+        }
+        // Deleted and suspended accounts: remember why, so that compile can
+        // explain the missing author.
+        for error in users_response.errors {
+            if let Some(id) = error.resource_id.clone().or_else(|| error.value.clone()) {
+                if batch.contains(&id) {
+                    println!("Could not resolve author {id}: {error}");
+                    user_id_lkup.insert_error(id, error);
+                }
+            }
+        }
+    }
+    Ok(missing_users.len())
+}
+
+/// Repairs the cache of `username` as far as it can: moves unreadable files
+/// into quarantine, fetches the pages of `source` which are missing again,
+/// and looks up the authors missing from the user lookup. Other sources are
+/// left to their own `repair` run, since they may need another token.
+pub async fn repair_cache_for_username(
+    client: &mut TwitClient,
+    username: &str,
+    source: ExportSource,
+) -> Result<RepairReport, Box<dyn Error>> {
+    let before = verify::verify_account(username)?;
+    let quarantined = verify::quarantine_invalid_files(username, &before)?;
+
+    // Pages which were unreadable only show up as missing in the first report.
+    let after_quarantine = verify::verify_account(username)?;
+    let mut missing_pages: Vec<(u64, String, &Path)> = Vec::new();
+    for issue in before.issues().chain(after_quarantine.issues()) {
+        if let Issue::MissingPage { source: page_source, after, index, next_token } = issue {
+            if *page_source == source && !missing_pages.iter().any(|(_, token, _)| token == next_token) {
+                missing_pages.push((*index, next_token.clone(), after));
+            }
+        }
+    }
+
+    let pages_before = cache::cached_page_paths(source, username)?.len();
+    for (index, next_token, after) in missing_pages {
+        println!("Fetching the missing page {index} of {}...", source.file_prefix());
+        // The page belongs to the export of the page before it, which gives
+        // it its place in like order (and its tweets their `first_seen`).
+        let started_at = TwitLikeResponse::load(after)?.export_started_at;
+        // As in sync mode, stop at the first page which is already cached.
+        let options = ExportOptions {
+            source,
+            next_token: Some(next_token),
+            page_index: index,
+            known_tweet_ids: Some(cache::load_cached_tweet_ids(source, username)?),
+            started_at,
+            ..Default::default()
+        };
+        export_twitter_likes_for_username(client, username, &options).await?;
+    }
+    let refetched_pages = cache::cached_page_paths(source, username)?.len().saturating_sub(pages_before);
+
+    let missing_users: Vec<String> = verify::verify_account(username)?
+        .issues()
+        .filter_map(|issue| match issue {
+            Issue::MissingUser { author_id, .. } => Some(author_id.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut backfilled_users = 0;
+    if !missing_users.is_empty() {
+        let _lock = cache::lock_account(username)?;
+        let mut user_id_lkup = cache::try_load_user_lookup(username);
+        for author_id in missing_users {
+            if !user_id_lkup.has(&author_id) {
+                user_id_lkup.insert(author_id, None);
+            }
+        }
+        backfilled_users = look_up_missing_users(client, &mut user_id_lkup).await?;
+        cache::write_user_lookup(username, &user_id_lkup)?;
+    }
+
+    Ok(RepairReport {
+        quarantined,
+        refetched_pages,
+        backfilled_users,
+        report: verify::verify_account(username)?,
+    })
+}
+
+/// Options controlling what is exported, where an export starts and when it
+/// stops.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// Which tweets to export.
+    pub source: ExportSource,
+    /// Leave replies out of the user's own tweets (`ExportSource::Tweets`).
+    pub exclude_replies: bool,
+    /// Leave retweets out of the user's own tweets (`ExportSource::Tweets`).
+    pub exclude_retweets: bool,
+    /// Optional pagination token to get the next batch of tweets.
+    pub next_token: Option<String>,
+    /// Fetch will stop when a page contains a tweet older than this date.
+    pub not_before_date: NaiveDate,
+    /// Ids of tweets which are already cached. When set (sync mode), only new
+    /// tweets are written, and fetching stops at the first page made up
+    /// entirely of known tweets.
+    pub known_tweet_ids: Option<HashSet<String>>,
+    /// Index of the first page fetched (non-zero when resuming).
+    pub page_index: u64,
+    /// When the export was first started (kept across resumes), which orders
+    /// its pages among those of other exports. Set by the caller when the
+    /// export begins; None only when repairing pages cached before exports
+    /// recorded their start.
+    pub started_at: Option<DateTime<Utc>>,
+    /// Whether to write a checkpoint after each page, so that an interrupted
+    /// export can be continued with `export --resume`.
+    pub checkpoint: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions {
+            source: ExportSource::Likes,
+            exclude_replies: false,
+            exclude_retweets: false,
+            next_token: None,
+            not_before_date: NaiveDate::MIN,
+            known_tweet_ids: None,
+            page_index: 0,
+            started_at: None,
+            checkpoint: false,
+        }
+    }
+}
+
+impl ExportOptions {
+    /// Options to continue an export from where `checkpoint` left off.
+    pub fn from_checkpoint(checkpoint: &ExportCheckpoint) -> ExportOptions {
+        ExportOptions {
+            source: checkpoint.source,
+            exclude_replies: checkpoint.exclude_replies,
+            exclude_retweets: checkpoint.exclude_retweets,
+            next_token: checkpoint.next_token.clone(),
+            not_before_date: checkpoint.not_before_date.unwrap_or(NaiveDate::MIN),
+            page_index: checkpoint.page_index,
+            started_at: Some(checkpoint.started_at),
+            checkpoint: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Continues an interrupted export for `username` from its checkpoint.
+/// Returns an error if there is no checkpoint to resume from.
+pub async fn resume_twitter_likes_export(
+    client: &mut TwitClient,
+    username: &str,
+) -> Result<(), Box<dyn Error>> {
+    let checkpoint = match ExportCheckpoint::load_for_username(username)? {
+        Some(checkpoint) => checkpoint,
+        None => {
+            return Err(format!(
+                "No checkpoint was found for '{username}'. Nothing to resume."
+            )
+            .into())
+        }
+    };
+
+    println!(
+        "Resuming export started at {} from page {}",
+        checkpoint.started_at, checkpoint.page_index
+    );
+    let options = ExportOptions::from_checkpoint(&checkpoint);
+    export_twitter_likes_for_username(client, username, &options).await
+}
+
+/// Fetches only the tweets from `options.source` newer than those already
+/// cached for `username`, stopping as soon as a page contains nothing new. On
+/// a first run (empty cache) this is equivalent to a full export.
+pub async fn sync_twitter_likes_for_username(
+    client: &mut TwitClient,
+    username: &str,
+    options: &ExportOptions,
+) -> Result<(), Box<dyn Error>> {
+    let known_tweet_ids = cache::load_cached_tweet_ids(options.source, username)?;
+    println!(
+        "Found {} cached {} for {username}",
+        known_tweet_ids.len(),
+        options.source.file_prefix()
+    );
+
+    let options = ExportOptions {
+        known_tweet_ids: Some(known_tweet_ids),
+        ..options.clone()
+    };
+    export_twitter_likes_for_username(client, username, &options).await
+}
+
+/// This function exports the "liked" (or bookmarked) tweets for a given user in
+/// batches, writing them to a filesystem cache.
 /// 
 /// # Arguments
 /// 
+/// - `client` Client used to make (authenticated) requests to the Twitter API.
 /// - `username` Twitter username string, like "matsuzine", without @.
-/// - `token` Bearer token to authenticate with the Twitter API.
-/// - `next_token` Optional pagination token to get the next batch of tweets.
-/// - `not_before_date` Fetch will stop when a page contains a tweet older than
-/// this date.
+/// - `options` Where to start fetching, and when to stop.
 pub async fn export_twitter_likes_for_username(
+    client: &mut TwitClient,
     username: &str,
-    token: &str,
-    next_token: Option<&str>,
-    not_before_date: NaiveDate,
+    options: &ExportOptions,
 ) -> Result<(), Box<dyn Error>> {
     // TODO: Probably makes sense to break up this function a little bit.
     // TODO: Currently this function is looping through the batches of tweets
@@ -206,84 +696,86 @@ pub async fn export_twitter_likes_for_username(
     // restart the process and it will pick up where the previous attempt quit.
     // On the other hand, it might be nice to give the caller more control of
     // what happens to the tweets that were loaded.
-    let client = reqwest::Client::new();
+
+    // Held until the export returns, so that another export of the same
+    // account cannot write to the cache at the same time.
+    let _lock = cache::lock_account(username)?;
 
     // Look up the twitter user id by user name / handle
-    let url_users_by = match TwitApiUrl::users_by_username_url(&[username]) {
+    let url_users_by = match client.urls.users_by_username_url(&[username]) {
         Err(TwitUrlFormatErrors::ExceedsLimit(msg)) => panic!("{msg}"),
         Err(TwitUrlFormatErrors::NotAtMinimum(msg)) => panic!("{msg}"),
         Ok(url) => url,
     };
-    let user_response = send_request::<TwitUserResponse>(&token, &client, &url_users_by).await;
+    let user_response = match client.send_request::<TwitUserResponse>(&url_users_by).await {
+        Err(err) if err.is_not_found() => {
+            return Err(Box::new(TwitterApiError::UserNotFound(username.to_string())))
+        }
+        result => result?,
+    };
+
+    let user = match user_response.data.first() {
+        Some(user) => user,
+        None => return Err(Box::new(TwitterApiError::UserNotFound(username.to_string()))),
+    };
+    let mut user_id_lkup = cache::try_load_user_lookup(username);
+    let mut next_token = options.next_token.clone();
+    let not_before_date = options.not_before_date;
+    let mut count: u64 = options.page_index;
+    // A resumed export carries on the same export, so it needs its start.
+    let checkpoint_started_at = match options.started_at {
+        None if options.checkpoint => return Err("An export which can be resumed needs a start time".into()),
+        started_at => started_at.filter(|_| options.checkpoint),
+    };
 
-    let user = &user_response.data[0];
-    let mut user_id_lkup = cache::try_load_user_lookup();
-    let mut next_token = next_token.map(|t| t.to_string());
-    let mut count: u64 = 0;
+    if options.checkpoint && options.page_index == 0 {
+        if let Some(previous) = ExportCheckpoint::load_for_username(username)? {
+            println!(
+                "Starting over, replacing the checkpoint at page {} (use `export --resume` to continue it instead)",
+                previous.page_index
+            );
+        }
+    }
 
     loop {
         println!("Fetching the next batch of tweets...");
         let url_users_liked =
-            TwitApiUrl::users_liked_tweets_url(&user.id, next_token.as_ref().map(|t| &**t));
+            client.urls.source_url(options, &user.id, next_token.as_deref());
         // TODO: Check here if the cache exists, skip loop if so
 
         let mut like_response =
-            send_request::<TwitLikeResponse>(&token, &client, &url_users_liked).await;
+            client.send_request::<TwitLikeResponse>(&url_users_liked).await?;
 
         like_response.user = Some(user.clone());
+        like_response.fetched_at = Some(Utc::now());
+        like_response.export_started_at = options.started_at;
 
+        like_response.index = Some(count);
         if let Some(tkn) = next_token {
             like_response.id = Some(tkn.to_string());
-            like_response.index = Some(count);
         }
 
-        // Handle missing users
-        // TODO: factor this into separate functions
-        if let Some(data) = &like_response.data {
-            // Collect any of the users that we haven't cached previously
-            for data in data.iter() {
-                // Gather all of the user_ids for the liked tweets to batch download
-                if !user_id_lkup.has(&data.author_id) {
-                    user_id_lkup.insert(data.author_id.clone(), None);
-                }
-            }
+        // In sync mode, keep only the tweets we haven't seen before, and stop
+        // once a whole page is already cached.
+        if let Some(known_tweet_ids) = &options.known_tweet_ids {
+            let fetched = like_response.data.as_ref().map_or(0, |data| data.len());
+            let data = like_response.data.take().unwrap_or_default();
+            let new_tweets: Vec<_> = data
+                .into_iter()
+                .filter(|tweet| !known_tweet_ids.contains(&tweet.id))
+                .collect();
 
-            let mut missing_users: Vec<String> = Vec::new();
-            for (key, value) in &user_id_lkup.users_by_id {
-                if let None = value {
-                    missing_users.push(key.clone());
-                }
-            }
-
-            if missing_users.len() > 0 {
-                match TwitApiUrl::users_by_ids_url(&missing_users) {
-                    Err(TwitUrlFormatErrors::ExceedsLimit(msg)) => panic!("{msg}"),
-                    Err(TwitUrlFormatErrors::NotAtMinimum(msg)) => {
-                        println!("No users to look up: {msg}");
-                    }
-                    Ok(url) => {
-                        // println!("{:?}", url);
-                        let users_response =
-                            send_request::<TwitUserResponse>(&token, &client, &url).await;
-
-                        for user in users_response.data {
-                            user_id_lkup.insert(user.id.clone(), Some(user.clone()));
-                        }
-
-                        cache::write_cache(&user_id_lkup, &UserIdLookup::fs_full_path().unwrap())?;
-                    }
-                };
+            if fetched > 0 && new_tweets.is_empty() {
+                println!("Reached tweets which are already cached. Finished.");
+                break;
             }
+            println!("Found {} new likes in a batch of {fetched}", new_tweets.len());
+            like_response.data = Some(new_tweets);
         }
 
-        if let Some(fs_path) = like_response.fs_full_path() {
-            println!("{:?}", fs_path);
-            if fs_path.exists() {
-                println!("Cache exists for this batch of tweets, skipping...");
-            } else {
-                cache::write_cache(&like_response, &fs_path)?;
-            }
-        }
+        resolve_authors(client, username, &mut user_id_lkup, &like_response).await?;
+
+        cache::write_page(options.source, &like_response)?;
 
         if like_response.has_next_token() {
             next_token = like_response.next_token();
@@ -300,11 +792,688 @@ pub async fn export_twitter_likes_for_username(
 
         count += 1;
 
-        // Pretty sure that the rate limit on likes requests is 75 / 15 minutes.
-        println!("Waiting to avoid rate limits...");
-        let twelve_seconds = std::time::Duration::from_secs(12);
-        std::thread::sleep(twelve_seconds);
+        if let Some(started_at) = checkpoint_started_at {
+            let checkpoint = ExportCheckpoint {
+                username: username.to_string(),
+                source: options.source,
+                exclude_replies: options.exclude_replies,
+                exclude_retweets: options.exclude_retweets,
+                next_token: next_token.clone(),
+                page_index: count,
+                started_at,
+                updated_at: Utc::now(),
+                not_before_date: Some(not_before_date).filter(|d| *d != NaiveDate::MIN),
+            };
+            cache::write_cache(&checkpoint, &ExportCheckpoint::fs_full_path(username)?)?;
+        }
+    }
+
+    if options.checkpoint {
+        ExportCheckpoint::clear(username)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::twitter::json_types::{LikedTweets, UNKNOWN_AUTHOR};
+    use crate::twitter::mock_server::{
+        export_started_on, mock_tweet, mock_user, use_scratch_cache_dir, MockTwitter, EXPORT_LOCK,
+    };
+    use hyper::StatusCode;
+    use serde_json::{json, Value};
+
+    fn test_client(base_url: &str) -> TwitClient {
+        TwitClient::new("test-token", base_url, RetryPolicy::new(3, Duration::from_millis(1)))
+    }
+
+    /// `count` likes by a rotating set of three authors, one per day going
+    /// back from 2022-11-30.
+    fn mock_likes(count: u64) -> Vec<Value> {
+        (0..count)
+            .map(|i| {
+                let created_at = NaiveDate::from_ymd_opt(2022, 11, 30).unwrap() - chrono::Days::new(i);
+                mock_tweet(
+                    1_000_000 - i,
+                    &format!("{}", 900 + i % 3),
+                    &format!("{created_at}T12:00:00.000Z"),
+                )
+            })
+            .collect()
+    }
+
+    fn mock_api(user_id: &str, username: &str, likes: u64) -> MockTwitter {
+        MockTwitter::new()
+            .user(user_id, username, "Exporting User")
+            .user("900", "author0", "Author Zero")
+            .user("901", "author1", "Author One")
+            .user("902", "author2", "Author Two")
+            .likes(user_id, mock_likes(likes))
+    }
+
+    fn liked_tweets_requests(requests: &[String]) -> usize {
+        requests.iter().filter(|r| r.contains("/liked_tweets")).count()
+    }
+
+    #[tokio::test]
+    async fn test_export_pages_through_all_likes() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("42", "mock_pages", 250).start().await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_pages", &export_started_on(1))
+            .await
+            .unwrap();
+
+        assert_eq!(liked_tweets_requests(&server.requests()), 3);
+        let liked_tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_pages").unwrap();
+        assert_eq!(liked_tweets.tweets.len(), 250);
+        assert_eq!(liked_tweets.user.unwrap().id, "42");
+        assert!(liked_tweets.tweets.iter().all(|t| t.user.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_export_stops_at_not_before_date() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("43", "mock_not_before", 250).page_size(50).start().await;
+        let mut client = test_client(&server.base_url);
+
+        // The first page covers 2022-11-30 back to 2022-10-12.
+        let options = ExportOptions {
+            not_before_date: NaiveDate::from_ymd_opt(2022, 10, 20).unwrap(),
+            ..export_started_on(1)
+        };
+        export_twitter_likes_for_username(&mut client, "mock_not_before", &options)
+            .await
+            .unwrap();
+
+        assert_eq!(liked_tweets_requests(&server.requests()), 1);
+    }
+
+    #[tokio::test]
+    async fn test_export_retries_transient_failures() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("44", "mock_retry", 10)
+            .respond_with(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable", &[])
+            .respond_with(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"title":"Too Many Requests","detail":"Too Many Requests"}"#,
+                &[("x-rate-limit-remaining", "0"), ("x-rate-limit-reset", "0")],
+            )
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_retry", &export_started_on(1))
+            .await
+            .unwrap();
+
+        // Two failed user lookups before the one which succeeds.
+        let requests = server.requests();
+        assert_eq!(requests.iter().filter(|r| r.starts_with("/2/users/by")).count(), 3);
+        assert_eq!(liked_tweets_requests(&requests), 1);
+        let liked_tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_retry").unwrap();
+        assert_eq!(liked_tweets.tweets.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_do_not_use_up_retries() {
+        let rate_limited = [("x-rate-limit-remaining", "0"), ("x-rate-limit-reset", "0")];
+        let server = mock_api("58", "mock_rate_limited", 0)
+            .respond_with(StatusCode::TOO_MANY_REQUESTS, "", &rate_limited)
+            .respond_with(StatusCode::TOO_MANY_REQUESTS, "", &rate_limited)
+            .start()
+            .await;
+        let mut client = TwitClient::new("test-token", &server.base_url, RetryPolicy::new(0, Duration::from_millis(1)));
+
+        let url = client.urls.users_by_username_url(&["mock_rate_limited"]).ok().unwrap();
+        let users = client.send_request::<TwitUserResponse>(&url).await.unwrap();
+        assert_eq!(users.data[0].id, "58");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_export_gives_up_after_max_retries() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = MockTwitter::new()
+            .respond_with(StatusCode::BAD_GATEWAY, "", &[])
+            .respond_with(StatusCode::BAD_GATEWAY, "", &[])
+            .respond_with(StatusCode::BAD_GATEWAY, "", &[])
+            .respond_with(StatusCode::BAD_GATEWAY, "", &[])
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        let err = export_twitter_likes_for_username(&mut client, "anyone", &export_started_on(1))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<TwitterApiError>(),
+            Some(TwitterApiError::Server { .. })
+        ));
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_export_unknown_user() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = MockTwitter::new().start().await;
+        let mut client = test_client(&server.base_url);
+
+        let err = export_twitter_likes_for_username(&mut client, "nobody", &export_started_on(1))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<TwitterApiError>(),
+            Some(TwitterApiError::UserNotFound(user)) if user == "nobody"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_export_auth_failure_is_not_retried() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = MockTwitter::new()
+            .respond_with(
+                StatusCode::UNAUTHORIZED,
+                r#"{"title":"Unauthorized","type":"about:blank","status":401,"detail":"Unauthorized"}"#,
+                &[],
+            )
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        let err = export_twitter_likes_for_username(&mut client, "anyone", &export_started_on(1))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<TwitterApiError>(),
+            Some(TwitterApiError::Auth { .. })
+        ));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sync_fetches_only_new_likes() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("45", "mock_sync", 250).start().await;
+        let mut client = test_client(&server.base_url);
+
+        // The first sync has nothing cached, so it fetches everything.
+        sync_twitter_likes_for_username(&mut client, "mock_sync", &export_started_on(1)).await.unwrap();
+        assert_eq!(liked_tweets_requests(&server.requests()), 3);
+
+        // Five new likes arrive at the top of the list.
+        {
+            let mut state = server.state.lock().unwrap();
+            let likes = state.likes.get_mut("45").unwrap();
+            for i in 0..5 {
+                likes.insert(0, mock_tweet(2_000_000 + i, "900", "2022-12-01T12:00:00.000Z"));
+            }
+        }
+        sync_twitter_likes_for_username(&mut client, "mock_sync", &export_started_on(2)).await.unwrap();
+
+        // One page with the new likes, and one made up entirely of cached likes.
+        assert_eq!(liked_tweets_requests(&server.requests()), 5);
+        let liked_tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_sync").unwrap();
+        assert_eq!(liked_tweets.tweets.len(), 255);
+
+        // More than a page of new likes: the second page has the token of a
+        // page cached by the first sync, and is kept alongside it.
+        {
+            let mut state = server.state.lock().unwrap();
+            let likes = state.likes.get_mut("45").unwrap();
+            for i in 0..150 {
+                likes.insert(0, mock_tweet(3_000_000 + i, "900", "2022-12-02T12:00:00.000Z"));
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        sync_twitter_likes_for_username(&mut client, "mock_sync", &export_started_on(3)).await.unwrap();
+        assert_eq!(liked_tweets_requests(&server.requests()), 8);
+        let liked_tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_sync").unwrap();
+        assert_eq!(liked_tweets.tweets.len(), 405);
+        assert_eq!(liked_tweets.tweets[0].id, "3000149");
+        assert!(verify::verify_account("mock_sync").unwrap().is_healthy());
+
+        // Nothing was lost, so the next sync stops at its first page.
+        sync_twitter_likes_for_username(&mut client, "mock_sync", &export_started_on(4)).await.unwrap();
+        assert_eq!(liked_tweets_requests(&server.requests()), 9);
+    }
+
+    #[tokio::test]
+    async fn test_resume_continues_from_checkpoint() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("46", "mock_resume", 250)
+            .respond_to("pagination_token=tok100", StatusCode::SERVICE_UNAVAILABLE, "")
+            .start()
+            .await;
+        let retry = RetryPolicy::new(0, Duration::ZERO);
+        let mut client = TwitClient::new("test-token", &server.base_url, retry);
+        let options = ExportOptions {
+            checkpoint: true,
+            ..export_started_on(1)
+        };
+
+        // The second page fails, leaving a checkpoint behind.
+        let err = export_twitter_likes_for_username(&mut client, "mock_resume", &options).await;
+        assert!(err.is_err());
+        let checkpoint = ExportCheckpoint::load_for_username("mock_resume").unwrap().unwrap();
+        assert_eq!(checkpoint.page_index, 1);
+        assert_eq!(checkpoint.next_token.as_deref(), Some("tok100"));
+
+        resume_twitter_likes_export(&mut client, "mock_resume").await.unwrap();
+
+        assert!(ExportCheckpoint::load_for_username("mock_resume").unwrap().is_none());
+        let liked_tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_resume").unwrap();
+        assert_eq!(liked_tweets.tweets.len(), 250);
+        assert!(cache::get_account_file_path("mock_resume", "likes-mock_resume-2-tok200.json").unwrap().exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_without_checkpoint() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = MockTwitter::new().start().await;
+        let mut client = test_client(&server.base_url);
+
+        assert!(resume_twitter_likes_export(&mut client, "mock_no_checkpoint").await.is_err());
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_bookmarks() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("47", "mock_bookmarks", 0)
+            .bookmarks("47", mock_likes(120))
+            .start()
+            .await;
+        let options = ExportOptions {
+            source: ExportSource::Bookmarks,
+            ..export_started_on(1)
+        };
+
+        // App-only tokens are refused by the bookmarks endpoint.
+        let mut client = test_client(&server.base_url);
+        let err = export_twitter_likes_for_username(&mut client, "mock_bookmarks", &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TwitterApiError>(),
+            Some(TwitterApiError::Auth { .. })
+        ));
+
+        let retry = RetryPolicy::new(0, Duration::ZERO);
+        let mut client = TwitClient::new("user-access-1", &server.base_url, retry);
+        export_twitter_likes_for_username(&mut client, "mock_bookmarks", &options)
+            .await
+            .unwrap();
+
+        let bookmarks =
+            cache::load_all_liked_tweets_from_cache(&[ExportSource::Bookmarks], "mock_bookmarks").unwrap();
+        assert_eq!(bookmarks.tweets.len(), 120);
+        assert!(cache::get_account_file_path("mock_bookmarks", "bookmarks-mock_bookmarks-1-tok100.json")
+            .unwrap()
+            .exists());
+        assert!(cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_bookmarks").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_own_tweets_and_compile_merged() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("48", "mock_timeline", 30)
+            .tweets("48", mock_likes(40))
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_timeline", &export_started_on(1))
+            .await
+            .unwrap();
+        let options = ExportOptions {
+            source: ExportSource::Tweets,
+            exclude_replies: true,
+            exclude_retweets: true,
+            ..export_started_on(1)
+        };
+        export_twitter_likes_for_username(&mut client, "mock_timeline", &options)
+            .await
+            .unwrap();
+
+        assert!(server
+            .requests()
+            .iter()
+            .any(|r| r.starts_with("/2/users/48/tweets?") && r.contains("&exclude=replies,retweets")));
+
+        let tweets = cache::load_all_liked_tweets_from_cache(&[ExportSource::Tweets], "mock_timeline").unwrap();
+        assert_eq!(tweets.tweets.len(), 40);
+
+        let merged = cache::load_all_liked_tweets_from_cache(
+            &[ExportSource::Likes, ExportSource::Tweets],
+            "mock_timeline",
+        )
+        .unwrap();
+        assert_eq!(merged.tweets.len(), 70);
+        assert_eq!(
+            merged.tweets.iter().filter(|t| t.source == Some(ExportSource::Likes)).count(),
+            30
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compile_attaches_includes() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let mut likes = mock_likes(3);
+        likes[0]["attachments"] = json!({"media_keys": ["3_1"], "poll_ids": ["77"]});
+        likes[1]["referenced_tweets"] = json!([{"type": "quoted", "id": "555"}]);
+        let server = mock_api("49", "mock_includes", 0)
+            .likes("49", likes)
+            .includes(json!({
+                "media": [
+                    {"media_key": "3_1", "type": "photo", "url": "https://pbs.twimg.com/media/1.jpg", "alt_text": "A cat"},
+                    {"media_key": "3_2", "type": "photo", "url": "https://pbs.twimg.com/media/2.jpg"}
+                ],
+                "polls": [{"id": "77", "options": [{"position": 1, "label": "Yes", "votes": 3}]}],
+                "tweets": [mock_tweet(555, "777", "2022-01-01T00:00:00.000Z")],
+                "users": [mock_user("777", "quoted_author", "Quoted Author")]
+            }))
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_includes", &export_started_on(1))
+            .await
+            .unwrap();
+        assert!(server.requests().iter().any(|r| r.contains("/liked_tweets?")
+            && r.contains("expansions=attachments.media_keys,referenced_tweets.id,attachments.poll_ids,author_id")));
+
+        let compiled = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_includes").unwrap();
+        let by_id = |id: &str| compiled.tweets.iter().find(|t| t.id == id).unwrap();
+
+        let with_media = by_id("1000000").includes.as_ref().unwrap();
+        let media = with_media.media.as_ref().unwrap();
+        assert_eq!(media.len(), 1);
+        assert_eq!(media[0].alt_text.as_deref(), Some("A cat"));
+        assert_eq!(with_media.polls.as_ref().unwrap()[0].options[0].votes, 3);
+        assert_eq!(by_id("1000000").lang.as_deref(), Some("en"));
+
+        let quoting = by_id("999999").includes.as_ref().unwrap();
+        assert_eq!(quoting.tweets.as_ref().unwrap()[0].id, "555");
+        assert_eq!(quoting.users.as_ref().unwrap()[0].username, "quoted_author");
+        assert!(quoting.media.is_none());
+
+        assert!(by_id("999998").includes.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_authors_from_includes_skip_user_lookup() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let likes: Vec<Value> = (0..5)
+            .map(|i| mock_tweet(2_000_000 - i, &format!("{}", 1200 + i), "2022-11-30T12:00:00.000Z"))
+            .collect();
+        let users: Vec<Value> = (0..5)
+            .map(|i| mock_user(&format!("{}", 1200 + i), &format!("embedded{i}"), "Embedded Author"))
+            .collect();
+        let server = mock_api("51", "mock_embedded", 0)
+            .likes("51", likes)
+            .includes(json!({ "users": users }))
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_embedded", &export_started_on(1))
+            .await
+            .unwrap();
+
+        assert!(!server.requests().iter().any(|r| r.starts_with("/2/users?ids=")));
+        let compiled = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_embedded").unwrap();
+        assert_eq!(compiled.tweets[0].user.as_ref().unwrap().username, "embedded0");
+    }
+
+    #[tokio::test]
+    async fn test_user_lookup_batches_of_100() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        // Authors left unresolved by an earlier, interrupted backfill.
+        let mut user_id_lkup = cache::try_load_user_lookup("mock_backfill");
+        let mut mock = mock_api("52", "mock_backfill", 0);
+        for i in 0..250 {
+            let author_id = format!("{}", 5000 + i);
+            mock = mock.user(&author_id, &format!("backfill{i}"), "Backfill Author");
+            user_id_lkup.insert(author_id, None);
+        }
+        cache::write_cache(&user_id_lkup, &UserIdLookup::fs_full_path("mock_backfill").unwrap()).unwrap();
+        let likes = vec![mock_tweet(3_000_000, "5000", "2022-11-30T12:00:00.000Z")];
+        let server = mock.likes("52", likes).start().await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_backfill", &export_started_on(1))
+            .await
+            .unwrap();
+
+        let lookups: Vec<String> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.starts_with("/2/users?ids="))
+            .collect();
+        assert_eq!(lookups.len(), 3);
+        for lookup in &lookups {
+            let ids = lookup.split("ids=").nth(1).unwrap().split('&').next().unwrap();
+            assert!(ids.split(',').count() <= USERS_LOOKUP_LIMIT);
+        }
+        let user_id_lkup = cache::load_user_lookup("mock_backfill").unwrap();
+        assert!((5000..5250).all(|id| matches!(user_id_lkup.users_by_id.get(&id.to_string()), Some(Some(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_compile_with_unknown_authors() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let likes = vec![
+            mock_tweet(4_000_002, "900", "2022-11-30T12:00:00.000Z"),
+            mock_tweet(4_000_001, "6661", "2022-11-29T12:00:00.000Z"),
+            mock_tweet(4_000_000, "6661", "2022-11-28T12:00:00.000Z"),
+        ];
+        let server = mock_api("53", "mock_unknown", 0)
+            .likes("53", likes)
+            .page_size(1)
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+
+        export_twitter_likes_for_username(&mut client, "mock_unknown", &export_started_on(1))
+            .await
+            .unwrap();
+
+        // The missing author is looked up once, not once per page.
+        let lookups = server
+            .requests()
+            .iter()
+            .filter(|r| r.starts_with("/2/users?ids=") && r.contains("6661"))
+            .count();
+        assert_eq!(lookups, 1);
+        let user_id_lkup = cache::load_user_lookup("mock_unknown").unwrap();
+        assert!(user_id_lkup.errors_by_id["6661"].detail.as_deref().unwrap().contains("6661"));
+
+        let compiled = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_unknown").unwrap();
+        assert_eq!(compiled.tweets.len(), 3);
+        let unknown = compiled.tweets.iter().find(|t| t.author_id == "6661").unwrap();
+        assert!(unknown.user.is_none());
+        assert!(unknown.author_label().starts_with(UNKNOWN_AUTHOR));
+
+        let markdown = std::env::temp_dir().join(format!("mock_unknown-{}.md", std::process::id()));
+        compile_twitter_exports_for_username(
+            "mock_unknown",
+            &[ExportSource::Likes],
+            &OutputFormat::Markdown,
+            markdown.to_str(),
+            SortOrder::Like,
+            Path::new("media"),
+            &TableArgs::default(),
+        )
+        .unwrap();
+        let markdown = std::fs::read_to_string(markdown).unwrap();
+        assert!(markdown.contains("Unknown author (Not Found Error: Could not find user with ids: [6661].) id(6661)"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_and_repair_damaged_cache() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("55", "mock_repair", 250).start().await;
+        let mut client = test_client(&server.base_url);
+        export_twitter_likes_for_username(&mut client, "mock_repair", &export_started_on(1))
+            .await
+            .unwrap();
+        assert!(verify::verify_account("mock_repair").unwrap().is_healthy());
+
+        // A page cut short by a crash, and a garbled user lookup.
+        let page = cache::get_account_file_path("mock_repair", "likes-mock_repair-1-tok100.json").unwrap();
+        let content = std::fs::read(&page).unwrap();
+        std::fs::write(&page, &content[..content.len() / 2]).unwrap();
+        std::fs::write(UserIdLookup::fs_full_path("mock_repair").unwrap(), "{").unwrap();
+
+        let report = verify::verify_account("mock_repair").unwrap();
+        assert!(!report.is_healthy());
+        let issues: Vec<_> = report.issues().collect();
+        assert!(issues.iter().any(|i| matches!(i, Issue::InvalidPage { path, .. } if *path == page)));
+        assert!(issues.iter().any(|i| matches!(i, Issue::InvalidUserLookup { .. })));
+        assert!(issues.iter().any(|i| matches!(i, Issue::MissingPage { index: 1, next_token, .. } if next_token == "tok100")));
+        assert!(issues.iter().any(|i| matches!(i, Issue::OrphanPage { index: 2, .. })));
+        assert_eq!(issues.iter().filter(|i| matches!(i, Issue::MissingUser { .. })).count(), 3);
+
+        let repaired = repair_cache_for_username(&mut client, "mock_repair", ExportSource::Likes).await.unwrap();
+        assert_eq!(repaired.quarantined.len(), 2);
+        assert!(repaired.quarantined.iter().all(|path| path.exists()));
+        assert_eq!(repaired.refetched_pages, 1);
+        assert!(repaired.report.is_healthy(), "{:?}", repaired.report.issues);
+        let compiled = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_repair").unwrap();
+        assert_eq!(compiled.tweets.len(), 250);
+        assert!(compiled.tweets.iter().all(|t| t.user.is_some()));
+        // The refetched page keeps its place in like order.
+        let ids: Vec<u64> = compiled.tweets.iter().map(|t| t.id.parse().unwrap()).collect();
+        assert_eq!(ids, (999_751..=1_000_000).rev().collect::<Vec<u64>>());
+        assert!(compiled.tweets.iter().all(|t| t.first_seen == compiled.tweets[0].first_seen));
+    }
+
+    #[tokio::test]
+    async fn test_compile_keeps_like_order() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        // Liked in this order, written in another.
+        let likes = vec![
+            mock_tweet(4_000_001, "902", "2020-06-01T12:00:00.000Z"),
+            mock_tweet(4_000_002, "900", "2022-11-30T18:00:00.000Z"),
+            mock_tweet(4_000_003, "901", "2022-11-30T09:00:00.000Z"),
+        ];
+        let server = mock_api("57", "mock_like_order", 0)
+            .likes("57", likes)
+            .page_size(2)
+            .start()
+            .await;
+        let mut client = test_client(&server.base_url);
+        export_twitter_likes_for_username(&mut client, "mock_like_order", &export_started_on(1))
+            .await
+            .unwrap();
+
+        // An old tweet, liked since.
+        let old_tweet = mock_tweet(4_000_000, "900", "2019-01-01T12:00:00.000Z");
+        server.state.lock().unwrap().likes.get_mut("57").unwrap().insert(0, old_tweet);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        sync_twitter_likes_for_username(&mut client, "mock_like_order", &export_started_on(2))
+            .await
+            .unwrap();
+
+        let mut liked_tweets =
+            cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_like_order").unwrap();
+        let ids = |liked_tweets: &LikedTweets| -> Vec<String> {
+            liked_tweets.tweets.iter().map(|t| t.id.clone()).collect()
+        };
+        assert_eq!(ids(&liked_tweets), ["4000000", "4000001", "4000002", "4000003"]);
+        let positions: Vec<_> = liked_tweets.tweets.iter().map(|t| t.like_position).collect();
+        assert_eq!(positions, [Some(0), Some(1), Some(2), Some(3)]);
+        assert!(liked_tweets.tweets[0].first_seen > liked_tweets.tweets[1].first_seen);
+        assert_eq!(liked_tweets.tweets[1].first_seen, liked_tweets.tweets[3].first_seen);
+
+        liked_tweets.sort(SortOrder::TweetTime);
+        assert_eq!(ids(&liked_tweets), ["4000002", "4000003", "4000001", "4000000"]);
+        liked_tweets.sort(SortOrder::Author);
+        assert_eq!(ids(&liked_tweets), ["4000002", "4000000", "4000003", "4000001"]);
+        liked_tweets.sort(SortOrder::Like);
+        assert_eq!(ids(&liked_tweets), ["4000000", "4000001", "4000002", "4000003"]);
+        // JSON Lines are streamed from the cache in the same order.
+        let jsonl = use_scratch_cache_dir().join("mock_like_order.jsonl");
+        compile_twitter_exports_for_username(
+            "mock_like_order",
+            &[ExportSource::Likes],
+            &OutputFormat::Jsonl,
+            jsonl.to_str(),
+            SortOrder::Like,
+            Path::new("media"),
+            &TableArgs::default(),
+        )
+        .unwrap();
+        let streamed: Vec<serde_json::Value> = std::fs::read_to_string(&jsonl)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let streamed_ids: Vec<_> = streamed.iter().map(|t| t["id"].as_str().unwrap()).collect();
+        assert_eq!(streamed_ids, ["4000000", "4000001", "4000002", "4000003"]);
+        assert_eq!(streamed[3]["like_position"], 3);
+        assert_eq!(streamed[0]["user"]["username"], "author0");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_compile_matches_loaded_with_overlapping_exports() {
+        let _lock = EXPORT_LOCK.lock().await;
+        use_scratch_cache_dir();
+        let server = mock_api("59", "mock_streamed", 5).page_size(2).start().await;
+        let mut client = test_client(&server.base_url);
+        export_twitter_likes_for_username(&mut client, "mock_streamed", &export_started_on(1))
+            .await
+            .unwrap();
+
+        // A full export again, after a new like and an edit.
+        {
+            let mut state = server.state.lock().unwrap();
+            let likes = state.likes.get_mut("59").unwrap();
+            likes[2]["text"] = json!("Edited");
+            likes.insert(0, mock_tweet(5_000_000, "901", "2021-01-01T12:00:00.000Z"));
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        export_twitter_likes_for_username(&mut client, "mock_streamed", &export_started_on(2))
+            .await
+            .unwrap();
+
+        let loaded = cache::load_all_liked_tweets_from_cache(&[ExportSource::Likes], "mock_streamed").unwrap();
+        let mut streamed = Vec::new();
+        let (count, duplicates) = cache::for_each_cached_tweet(&[ExportSource::Likes], "mock_streamed", |tweet| {
+            streamed.push(tweet);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!((count, duplicates), (6, 5));
+        assert_eq!(loaded.duplicates, 5);
+        assert_eq!(serde_json::to_value(&streamed).unwrap(), serde_json::to_value(&loaded.tweets).unwrap());
+        assert_eq!(streamed[0].id, "5000000");
+        assert_eq!(streamed[0].first_seen, export_started_on(2).started_at);
+        assert!(streamed[1..].iter().all(|t| t.first_seen == export_started_on(1).started_at));
+        assert_eq!(streamed[3].text, "Edited");
+    }
+}